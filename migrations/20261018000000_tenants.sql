@@ -0,0 +1,3 @@
+ALTER TABLE "logs" ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+
+CREATE INDEX IF NOT EXISTS logs_tenant_id_timestamp_idx ON "logs" (tenant_id, timestamp);
@@ -0,0 +1,29 @@
+-- Convert "logs" into a table range partitioned on "timestamp". Existing rows are moved into the
+-- default partition; the partition maintenance task moves them into dedicated partitions as they
+-- are created.
+ALTER SEQUENCE logs_id_seq OWNED BY NONE;
+
+ALTER TABLE "logs" RENAME TO "logs_unpartitioned";
+ALTER TABLE "logs_unpartitioned" RENAME CONSTRAINT logs_pkey TO logs_unpartitioned_pkey;
+ALTER INDEX logs_tenant_id_timestamp_idx RENAME TO logs_unpartitioned_tenant_id_timestamp_idx;
+
+CREATE TABLE "logs" (
+    id BIGINT NOT NULL DEFAULT nextval('logs_id_seq'),
+    timestamp TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    message TEXT NOT NULL,
+    level int NOT NULL,
+    context JSONB NOT NULL DEFAULT '{}',
+    tenant_id TEXT NOT NULL DEFAULT 'default',
+    PRIMARY KEY (id, timestamp)
+) PARTITION BY RANGE (timestamp);
+
+ALTER SEQUENCE logs_id_seq OWNED BY "logs".id;
+
+CREATE TABLE IF NOT EXISTS "logs_default" PARTITION OF "logs" DEFAULT;
+
+CREATE INDEX IF NOT EXISTS logs_tenant_id_timestamp_idx ON "logs" (tenant_id, timestamp);
+
+INSERT INTO "logs" (id, timestamp, message, level, context, tenant_id)
+    SELECT id, timestamp, message, level, context, tenant_id FROM "logs_unpartitioned";
+
+DROP TABLE "logs_unpartitioned";
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS "deletion_audits" (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    filters JSONB NOT NULL DEFAULT '{}',
+    rows_deleted BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS deletion_audits_tenant_id_created_at_idx ON "deletion_audits" (tenant_id, created_at);
@@ -0,0 +1,18 @@
+-- Promote the most commonly filtered context keys to columns so they can use btree indexes.
+-- Columns added to the partitioned table, and indexes created on it, cascade to every partition.
+ALTER TABLE "logs" ADD COLUMN IF NOT EXISTS service TEXT;
+ALTER TABLE "logs" ADD COLUMN IF NOT EXISTS host TEXT;
+ALTER TABLE "logs" ADD COLUMN IF NOT EXISTS trace_id TEXT;
+ALTER TABLE "logs" ADD COLUMN IF NOT EXISTS span_id TEXT;
+
+UPDATE "logs" SET
+    service = CASE WHEN jsonb_typeof(context->'service') = 'string' THEN context->>'service' END,
+    host = CASE WHEN jsonb_typeof(context->'host') = 'string' THEN context->>'host' END,
+    trace_id = CASE WHEN jsonb_typeof(context->'trace_id') = 'string' THEN context->>'trace_id' END,
+    span_id = CASE WHEN jsonb_typeof(context->'span_id') = 'string' THEN context->>'span_id' END
+WHERE context ?| ARRAY['service', 'host', 'trace_id', 'span_id'];
+
+CREATE INDEX IF NOT EXISTS logs_tenant_id_service_timestamp_idx ON "logs" (tenant_id, service, timestamp);
+CREATE INDEX IF NOT EXISTS logs_tenant_id_host_timestamp_idx ON "logs" (tenant_id, host, timestamp);
+CREATE INDEX IF NOT EXISTS logs_tenant_id_trace_id_idx ON "logs" (tenant_id, trace_id);
+CREATE INDEX IF NOT EXISTS logs_tenant_id_span_id_idx ON "logs" (tenant_id, span_id);
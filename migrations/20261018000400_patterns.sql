@@ -0,0 +1,13 @@
+-- Message templates mined from logs; each log references the template its message matched.
+CREATE TABLE IF NOT EXISTS "log_patterns" (
+    tenant_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    template TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    PRIMARY KEY (tenant_id, id)
+);
+
+ALTER TABLE "logs" ADD COLUMN IF NOT EXISTS pattern_id TEXT;
+
+CREATE INDEX IF NOT EXISTS logs_tenant_id_pattern_id_timestamp_idx ON "logs" (tenant_id, pattern_id, timestamp);
@@ -0,0 +1,12 @@
+-- Named GET /logs queries shared within a tenant; parameters hold the raw query parameters.
+CREATE TABLE IF NOT EXISTS "saved_searches" (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    parameters JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS saved_searches_tenant_id_name_idx ON "saved_searches" (tenant_id, name);
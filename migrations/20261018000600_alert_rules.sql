@@ -0,0 +1,24 @@
+-- Rules alerting on the number of logs matching a query over a trailing window. state is the
+-- outcome of the last evaluation, either ok or firing.
+CREATE TABLE IF NOT EXISTS "alert_rules" (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    saved_search_id BIGINT REFERENCES "saved_searches" (id),
+    parameters JSONB NOT NULL DEFAULT '{}',
+    condition TEXT NOT NULL,
+    threshold BIGINT NOT NULL DEFAULT 1,
+    window_seconds BIGINT NOT NULL,
+    webhooks JSONB NOT NULL DEFAULT '[]',
+    state TEXT NOT NULL DEFAULT 'ok',
+    last_count BIGINT,
+    last_evaluated_at TIMESTAMP WITH TIME ZONE,
+    fired_at TIMESTAMP WITH TIME ZONE,
+    resolved_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS alert_rules_tenant_id_name_idx ON "alert_rules" (tenant_id, name);
+CREATE INDEX IF NOT EXISTS alert_rules_saved_search_id_idx ON "alert_rules" (saved_search_id);
@@ -0,0 +1,15 @@
+-- Notifications of alert rules waiting to be delivered, one row per webhook. A row is removed
+-- once delivered or given up on; next_attempt_at is when its delivery is next due.
+CREATE TABLE IF NOT EXISTS "alert_notifications" (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id TEXT NOT NULL,
+    rule_id BIGINT NOT NULL,
+    webhook TEXT NOT NULL,
+    dedupe_key TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS alert_notifications_rule_id_webhook_idx ON "alert_notifications" (rule_id, webhook, id);
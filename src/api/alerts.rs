@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{
+        Path,
+        State,
+    },
+    http::StatusCode,
+    Json,
+};
+use sea_orm::{
+    ActiveModelTrait,
+    ActiveValue,
+    ColumnTrait,
+    DbErr,
+    EntityTrait,
+    QueryFilter,
+    QueryOrder,
+};
+use serde::Deserialize;
+
+use crate::{
+    database,
+    error::{
+        HttpError,
+        Loggable,
+    },
+    models::{
+        AlertCondition,
+        AlertRule,
+        AlertRuleActiveModel,
+        AlertRuleColumn,
+        AlertRuleModel,
+        SavedSearch,
+        SavedSearchColumn,
+        STATE_OK,
+    },
+    parameters::QueryParameters,
+    tasks::alerts,
+    time_expression,
+};
+
+use super::{
+    Actor,
+    AppState,
+    Tenant,
+};
+
+
+const MAX_NAME_LENGTH: usize = 256;
+const MAX_WEBHOOKS: usize = 10;
+
+
+#[derive(Debug, Deserialize)]
+pub struct AlertRuleBody {
+    name: String,
+    /// Saved search whose query the rule counts, with `parameters` applied over it
+    saved_search_id: Option<i64>,
+    #[serde(default)]
+    parameters: HashMap<String, String>,
+    condition: AlertCondition,
+    threshold: Option<i64>,
+    /// Trailing window counted, e.g. `5m`
+    window: String,
+    webhooks: Vec<String>,
+}
+
+/// A validated [`AlertRuleBody`]
+struct AlertRuleDefinition {
+    name: String,
+    saved_search_id: Option<i64>,
+    parameters: serde_json::Value,
+    condition: AlertCondition,
+    threshold: i64,
+    window_seconds: i64,
+    webhooks: serde_json::Value,
+}
+
+impl AlertRuleBody {
+    async fn validate(self, state: &AppState, tenant: &Tenant) -> Result<AlertRuleDefinition, HttpError> {
+        let name = self.name.trim();
+
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            return Err(HttpError::bad_request(Some(format!("name must be between 1 and {} characters", MAX_NAME_LENGTH))));
+        }
+
+        let threshold = match (self.condition, self.threshold) {
+            (AlertCondition::Any, _) => 1,
+            (AlertCondition::Threshold, Some(threshold)) if threshold >= 1 => threshold,
+            (AlertCondition::Threshold, _) => return Err(HttpError::bad_request(Some("threshold must be at least 1".to_string()))),
+        };
+        let window_seconds = time_expression::interval_seconds(&self.window)
+            .ok_or_else(|| HttpError::bad_request(Some(format!("Invalid window: {}", self.window))))?;
+
+        if self.webhooks.is_empty() || self.webhooks.len() > MAX_WEBHOOKS {
+            return Err(HttpError::bad_request(Some(format!("Between 1 and {} webhooks are required", MAX_WEBHOOKS))));
+        }
+
+        for webhook in &self.webhooks {
+            let valid = reqwest::Url::parse(webhook)
+                .map(|url| alerts::is_allowed_webhook(&url, state.config.alert_webhook_allow_private))
+                .unwrap_or(false);
+
+            if !valid {
+                return Err(HttpError::bad_request(Some(format!("Invalid webhook: {}", webhook))));
+            }
+        }
+
+        let parameters = match self.saved_search_id {
+            Some(id) => SavedSearch::find_by_id(id)
+                .filter(SavedSearchColumn::TenantId.eq(tenant.as_str()))
+                .one(&*state.db)
+                .await
+                .log_error("An exception occurred while fetching a saved search")
+                .map_err(|_| HttpError::internal_server_error(None))?
+                .ok_or_else(|| HttpError::bad_request(Some(format!("Saved search {} not found", id))))?
+                .query_parameters(self.parameters.clone()),
+            None => self.parameters.clone(),
+        };
+
+        QueryParameters::from_hashmap(parameters)
+            .map_err(|op| HttpError::bad_request(Some(op.to_string())))?;
+
+        Ok(AlertRuleDefinition {
+            name: name.to_string(),
+            saved_search_id: self.saved_search_id,
+            parameters: serde_json::json!(self.parameters),
+            condition: self.condition,
+            threshold,
+            window_seconds,
+            webhooks: serde_json::json!(self.webhooks),
+        })
+    }
+}
+
+
+/// Alert rules of a tenant. Anyone in the tenant can read a rule; only its owner can change
+/// or delete it. Rules are evaluated by the alerts task.
+pub struct Alerts;
+
+impl Alerts {
+    pub async fn list(
+        state: State<AppState>,
+        tenant: Tenant,
+    ) -> Result<Json<Vec<AlertRuleModel>>, HttpError> {
+        let rules = AlertRule::find()
+            .filter(AlertRuleColumn::TenantId.eq(tenant.as_str()))
+            .order_by_asc(AlertRuleColumn::Name)
+            .all(&*state.db)
+            .await
+            .log_error("An exception occurred while listing alert rules")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+
+        Ok(Json(rules))
+    }
+
+    pub async fn create(
+        state: State<AppState>,
+        tenant: Tenant,
+        actor: Actor,
+        Json(body): Json<AlertRuleBody>,
+    ) -> Result<(StatusCode, Json<AlertRuleModel>), HttpError> {
+        let rule = body.validate(&state, &tenant).await?;
+        let name = rule.name.clone();
+        let now = chrono::Utc::now();
+        let rule = AlertRuleActiveModel {
+            tenant_id: ActiveValue::Set(tenant.as_str().to_string()),
+            name: ActiveValue::Set(rule.name),
+            owner: ActiveValue::Set(actor.as_str().to_string()),
+            saved_search_id: ActiveValue::Set(rule.saved_search_id),
+            parameters: ActiveValue::Set(rule.parameters),
+            condition: ActiveValue::Set(rule.condition.as_str().to_string()),
+            threshold: ActiveValue::Set(rule.threshold),
+            window_seconds: ActiveValue::Set(rule.window_seconds),
+            webhooks: ActiveValue::Set(rule.webhooks),
+            state: ActiveValue::Set(STATE_OK.to_string()),
+            created_at: ActiveValue::Set(now.into()),
+            updated_at: ActiveValue::Set(now.into()),
+            ..Default::default()
+        }
+        .insert(&*state.db)
+        .await
+        .map_err(|e| Self::save_error(e, &name, "An exception occurred while creating an alert rule"))?;
+
+        Ok((StatusCode::CREATED, Json(rule)))
+    }
+
+    pub async fn get(
+        state: State<AppState>,
+        tenant: Tenant,
+        Path(id): Path<i64>,
+    ) -> Result<Json<AlertRuleModel>, HttpError> {
+        let rule = Self::find(&state, &tenant, id)
+            .await?;
+
+        Ok(Json(rule))
+    }
+
+    /// Replaces the definition of a rule; its state is kept, and catches up at the next
+    /// evaluation
+    pub async fn update(
+        state: State<AppState>,
+        tenant: Tenant,
+        actor: Actor,
+        Path(id): Path<i64>,
+        Json(body): Json<AlertRuleBody>,
+    ) -> Result<Json<AlertRuleModel>, HttpError> {
+        let rule = Self::find_owned(&state, &tenant, &actor, id)
+            .await?;
+        let definition = body.validate(&state, &tenant).await?;
+        let name = definition.name.clone();
+
+        let mut rule: AlertRuleActiveModel = rule.into();
+
+        rule.name = ActiveValue::Set(definition.name);
+        rule.saved_search_id = ActiveValue::Set(definition.saved_search_id);
+        rule.parameters = ActiveValue::Set(definition.parameters);
+        rule.condition = ActiveValue::Set(definition.condition.as_str().to_string());
+        rule.threshold = ActiveValue::Set(definition.threshold);
+        rule.window_seconds = ActiveValue::Set(definition.window_seconds);
+        rule.webhooks = ActiveValue::Set(definition.webhooks);
+        rule.updated_at = ActiveValue::Set(chrono::Utc::now().into());
+
+        let rule = rule
+            .update(&*state.db)
+            .await
+            .map_err(|e| Self::save_error(e, &name, "An exception occurred while updating an alert rule"))?;
+
+        Ok(Json(rule))
+    }
+
+    pub async fn delete(
+        state: State<AppState>,
+        tenant: Tenant,
+        actor: Actor,
+        Path(id): Path<i64>,
+    ) -> Result<StatusCode, HttpError> {
+        Self::find_owned(&state, &tenant, &actor, id)
+            .await?;
+
+        AlertRule::delete_by_id(id)
+            .exec(&*state.db)
+            .await
+            .log_error("An exception occurred while deleting an alert rule")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    async fn find(state: &AppState, tenant: &Tenant, id: i64) -> Result<AlertRuleModel, HttpError> {
+        AlertRule::find_by_id(id)
+            .filter(AlertRuleColumn::TenantId.eq(tenant.as_str()))
+            .one(&*state.db)
+            .await
+            .log_error("An exception occurred while fetching an alert rule")
+            .map_err(|_| HttpError::internal_server_error(None))?
+            .ok_or_else(|| HttpError::not_found(Some(format!("Alert rule {} not found", id))))
+    }
+
+    async fn find_owned(state: &AppState, tenant: &Tenant, actor: &Actor, id: i64) -> Result<AlertRuleModel, HttpError> {
+        let rule = Self::find(state, tenant, id)
+            .await?;
+
+        if rule.owner != actor.as_str() {
+            return Err(HttpError::forbidden(Some(format!("Alert rule {} belongs to {}", id, rule.owner))));
+        }
+
+        Ok(rule)
+    }
+
+    /// Names are unique within a tenant, as enforced by the index on `(tenant_id, name)`
+    fn save_error(err: DbErr, name: &str, context: &str) -> HttpError {
+        if database::is_error_code(&err, database::UNIQUE_VIOLATION) {
+            return HttpError::conflict(Some(format!("An alert rule named {} already exists", name)));
+        }
+
+        tracing::error!("{} {:#?}", context, err);
+
+        HttpError::internal_server_error(None)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{
+        self,
+        StatusCode,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::Api,
+        testing::{
+            self,
+            call,
+        },
+    };
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_alerts_database() {
+        let config = testing::config(&[]);
+        let db = testing::database(&config)
+            .await;
+        let tenant = testing::tenant("alerts");
+        let alice = [("X-Tenant", tenant.as_str()), ("X-Actor", "alice")];
+        let bob = [("X-Tenant", tenant.as_str()), ("X-Actor", "bob")];
+        let router: axum::Router = Api::new(db, config).into();
+        let (_, search) = call(&router, http::Method::POST, "/saved-searches", &alice, Some(json!({
+            "name": "billing",
+            "parameters": {"filter[service][eq]": "billing"},
+        }))).await;
+        let search_id = search["id"].as_i64().unwrap();
+        let rule = json!({
+            "name": "billing errors",
+            "saved_search_id": search_id,
+            "parameters": {"filter[level][gte]": "4"},
+            "condition": "threshold",
+            "threshold": 10,
+            "window": "5m",
+            "webhooks": ["https://hooks.example.com/alerts"],
+        });
+
+        for (field, value) in [
+            ("threshold", json!(0)),
+            ("window", json!("5 minutes")),
+            ("webhooks", json!(["ftp://hooks.example.com/alerts"])),
+            ("webhooks", json!(["http://localhost:9000/hook"])),
+            ("webhooks", json!(["http://169.254.169.254/latest/meta-data"])),
+            ("webhooks", json!([])),
+            ("saved_search_id", json!(search_id + 1)),
+            ("parameters", json!({"filter[level][nope]": "4"})),
+        ] {
+            let mut invalid = rule.clone();
+
+            invalid[field] = value;
+
+            let (status, _) = call(&router, http::Method::POST, "/alerts", &alice, Some(invalid)).await;
+
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{} should be rejected", field);
+        }
+
+        let (status, created) = call(&router, http::Method::POST, "/alerts", &alice, Some(rule.clone())).await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created["owner"], "alice");
+        assert_eq!(created["window_seconds"], 300);
+        assert_eq!(created["state"], "ok");
+
+        let id = created["id"].as_i64().unwrap();
+
+        let (status, _) = call(&router, http::Method::POST, "/alerts", &alice, Some(rule)).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // a saved search can't be deleted while a rule uses it
+        let (status, _) = call(&router, http::Method::DELETE, &format!("/saved-searches/{}", search_id), &alice, None).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let any = json!({"name": "any error", "parameters": {"filter[level][gte]": "4"}, "condition": "any", "window": "1m", "webhooks": ["https://example.com/hook"]});
+        let (status, _) = call(&router, http::Method::PUT, &format!("/alerts/{}", id), &bob, Some(any.clone())).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, updated) = call(&router, http::Method::PUT, &format!("/alerts/{}", id), &alice, Some(any)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(updated["condition"], "any");
+        assert_eq!(updated["threshold"], 1);
+        assert_eq!(updated["saved_search_id"], serde_json::Value::Null);
+
+        let (_, rules) = call(&router, http::Method::GET, "/alerts", &bob, None).await;
+
+        assert_eq!(rules.as_array().unwrap().len(), 1);
+
+        let (status, _) = call(&router, http::Method::DELETE, &format!("/alerts/{}", id), &alice, None).await;
+
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = call(&router, http::Method::DELETE, &format!("/saved-searches/{}", search_id), &alice, None).await;
+
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+}
@@ -0,0 +1,231 @@
+use axum::{
+    extract::{
+        Path,
+        State,
+    },
+    Json,
+};
+use chrono::{
+    NaiveDate,
+    TimeZone,
+    Utc,
+};
+use sea_orm::{
+    EntityTrait,
+    IntoActiveModel,
+    sea_query::OnConflict,
+};
+
+use crate::{
+    archive::Archive,
+    error::{
+        HttpError,
+        Loggable,
+    },
+    models::{
+        Log,
+        LogActiveModel,
+        LogColumn,
+    },
+    tasks::retention::RetentionPolicy,
+};
+
+use super::{
+    AppState,
+    Tenant,
+};
+
+
+// keeps each INSERT well under Postgres' bind parameter limit
+const RESTORE_CHUNK_SIZE: usize = 1000;
+
+
+pub struct Archives;
+
+impl Archives {
+    /// Re-inserts the tenant's logs archived for `day`; rows which still exist are skipped.
+    /// Days the retention policy would purge again are refused.
+    pub async fn restore(
+        state: State<AppState>,
+        tenant: Tenant,
+        Path(day): Path<String>,
+    ) -> Result<Json<serde_json::Value>, HttpError> {
+        let archive = state.config.archive_directory
+            .as_ref()
+            .map(Archive::new)
+            .ok_or_else(|| HttpError::bad_request(Some("Archiving is not configured".to_string())))?;
+        let day = NaiveDate::parse_from_str(&day, "%Y-%m-%d")
+            .map_err(|_| HttpError::bad_request(Some(format!("Invalid day: {}", day))))?;
+        let start = Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).unwrap());
+
+        if let Some(cutoff) = RetentionPolicy::from_config(&state.config).cutoff_for_tenant(tenant.as_str(), Utc::now()) {
+            if start < cutoff {
+                return Err(HttpError::conflict(Some(format!(
+                    "Logs for {} are past retention and would be purged again; extend retention before restoring",
+                    day,
+                ))));
+            }
+        }
+
+        let tenant_id = tenant.as_str().to_string();
+        let models = tokio::task::spawn_blocking(move || archive.read(day, &tenant_id))
+            .await
+            .log_error("An exception occurred while reading the archive")
+            .map_err(|_| HttpError::internal_server_error(None))?
+            .log_error("An exception occurred while reading the archive")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+
+        let active_logs = models
+            .into_iter()
+            .map(|model| model.into_active_model())
+            .collect::<Vec<LogActiveModel>>();
+        let mut restored = 0;
+
+        for chunk in active_logs.chunks(RESTORE_CHUNK_SIZE) {
+            restored += Log::insert_many(chunk.to_vec())
+                .on_conflict(
+                    OnConflict::columns([LogColumn::Id, LogColumn::Timestamp])
+                        .do_nothing()
+                        .to_owned()
+                )
+                .exec_without_returning(&*state.db)
+                .await
+                .log_error("An exception occurred while restoring archived logs")
+                .map_err(|_| HttpError::internal_server_error(None))?;
+        }
+
+        Ok(Json(serde_json::json!({"restored": restored})))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{
+        self,
+        StatusCode,
+    };
+    use chrono::{
+        NaiveDate,
+        TimeZone,
+        Utc,
+    };
+    use sea_orm::{
+        ConnectionTrait,
+        DatabaseBackend,
+        MockDatabase,
+        Statement,
+    };
+
+    use crate::{
+        api::Api,
+        archive::Archive,
+        config::Config,
+        models::LogModel,
+        testing::{
+            self,
+            call,
+        },
+    };
+
+    fn config(archive_directory: Option<&str>) -> Config {
+        match archive_directory {
+            Some(archive_directory) => testing::config(&[("ARCHIVE_DIRECTORY", archive_directory)]),
+            None => testing::config(&[]),
+        }
+    }
+
+    async fn restore(router: axum::Router, tenant: &str, day: &str) -> (StatusCode, serde_json::Value) {
+        call(&router, http::Method::POST, &format!("/archives/{}/restore", day), &[("X-Tenant", tenant)], None).await
+    }
+
+    #[tokio::test]
+    async fn test_restore_invalid() {
+        let router: axum::Router = Api::new(
+            MockDatabase::new(DatabaseBackend::Postgres).into_connection(),
+            config(None),
+        ).into();
+
+        assert_eq!(restore(router, "default", "2022-12-25").await.0, StatusCode::BAD_REQUEST);
+
+        let router: axum::Router = Api::new(
+            MockDatabase::new(DatabaseBackend::Postgres).into_connection(),
+            config(Some("/tmp")),
+        ).into();
+
+        assert_eq!(restore(router, "default", "yesterday").await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_restore_past_retention() {
+        let mut config = config(Some("/tmp"));
+
+        config.retention_days = Some(30);
+
+        let router: axum::Router = Api::new(
+            MockDatabase::new(DatabaseBackend::Postgres).into_connection(),
+            config,
+        ).into();
+
+        assert_eq!(restore(router, "default", "2022-12-25").await.0, StatusCode::CONFLICT);
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_restore_database() {
+        let directory = std::env::temp_dir().join(format!("log-ingest-restore-{}", std::process::id()));
+        let config = config(directory.to_str());
+        let db = testing::database(&config)
+            .await;
+        let tenant = testing::tenant("archives");
+        let other = testing::tenant("archives-other");
+        let mut models = vec![];
+
+        // ids from the sequence, so they can't clash with another test's rows
+        for tenant in [&tenant, &tenant, &other] {
+            let id = db.query_one(
+                Statement::from_string(
+                    DatabaseBackend::Postgres,
+                    "SELECT nextval(pg_get_serial_sequence('logs', 'id')) AS id".to_owned(),
+                )
+            ).await
+            .unwrap()
+            .unwrap()
+            .try_get::<i64>("", "id")
+            .unwrap();
+
+            models.push(LogModel {
+                id,
+                timestamp: Some(Utc.with_ymd_and_hms(2022, 12, 25, 13, 45, 0).unwrap().into()),
+                message: "archived".to_string(),
+                level: 3,
+                context: Some(serde_json::json!({"host": "a"})),
+                tenant_id: tenant.to_string(),
+                service: None,
+                host: None,
+                trace_id: None,
+                span_id: None,
+                pattern_id: None,
+            });
+        }
+
+        Archive::new(&directory)
+            .write(NaiveDate::from_ymd_opt(2022, 12, 25).unwrap(), "logs_p20221225", &models)
+            .unwrap();
+
+        let router: axum::Router = Api::new(db, config).into();
+
+        let (status, body) = restore(router.clone(), &tenant, "2022-12-25").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["restored"], 2);
+
+        // restoring twice does not duplicate rows
+        let (_, body) = restore(router, &tenant, "2022-12-25").await;
+
+        assert_eq!(body["restored"], 0);
+
+        std::fs::remove_dir_all(directory)
+            .unwrap();
+    }
+}
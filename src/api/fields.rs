@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{
+        Path,
+        Query,
+        State,
+    },
+    Json,
+};
+use sea_orm::{
+    ConnectionTrait,
+    FromQueryResult,
+};
+
+use crate::{
+    error::{
+        HttpError,
+        Loggable,
+    },
+    models::QueryBuilder,
+    parameters::{
+        FieldSelector,
+        QueryParameters,
+    },
+};
+
+use super::{
+    AppState,
+    Tenant,
+};
+
+
+const TOP_PARAMETER: &str = "top";
+const DEFAULT_TOP: u64 = 20;
+const MAX_TOP: u64 = 1000;
+
+
+pub struct Fields;
+
+impl Fields {
+    /// Lists the top-level context keys of matching logs, with how often each JSON type
+    /// appears under the key
+    pub async fn list(
+        state: State<AppState>,
+        tenant: Tenant,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Result<Json<serde_json::Value>, HttpError> {
+        let parameters = QueryParameters::from_hashmap(params)
+            .map_err(|op| HttpError::bad_request(Some(op.to_string())))?;
+        let db_connection = state.db.clone();
+        let statement = QueryBuilder::new(tenant)
+            .conditions(&parameters)
+            .into_fields_statement(db_connection.get_database_backend());
+
+        let rows = serde_json::Value::find_by_statement(statement)
+            .all(&*db_connection)
+            .await
+            .log_error("An exception occurred while listing fields")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+
+        // rows are ordered by name, so each key's types are adjacent
+        let mut fields: Vec<serde_json::Value> = vec![];
+
+        for row in rows {
+            let name = &row["name"];
+            let count = row["count"].as_i64().unwrap_or_default();
+            let type_name = row["type"].as_str().unwrap_or("null").to_string();
+
+            match fields.last_mut().filter(|field| field["name"] == *name) {
+                Some(field) => {
+                    field["count"] = (field["count"].as_i64().unwrap_or_default() + count).into();
+                    field["types"][type_name] = count.into();
+                },
+                None => fields.push(serde_json::json!({
+                    "name": name,
+                    "count": count,
+                    "types": {type_name: count},
+                })),
+            }
+        }
+
+        Ok(Json(serde_json::Value::Array(fields)))
+    }
+
+    /// The most frequent values of a column or context path among matching logs
+    pub async fn values(
+        state: State<AppState>,
+        tenant: Tenant,
+        Path(name): Path<String>,
+        Query(mut params): Query<HashMap<String, String>>,
+    ) -> Result<Json<serde_json::Value>, HttpError> {
+        let field = FieldSelector::resolve(&name)
+            .ok_or_else(|| HttpError::bad_request(Some(format!("Invalid field: {}", name))))?;
+        let top = match params.remove(TOP_PARAMETER) {
+            Some(top) => top
+                .parse::<u64>()
+                .ok()
+                .filter(|top| (1..=MAX_TOP).contains(top))
+                .ok_or_else(|| HttpError::bad_request(Some(format!("{} must be between 1 and {}", TOP_PARAMETER, MAX_TOP))))?,
+            None => DEFAULT_TOP,
+        };
+        let parameters = QueryParameters::from_hashmap(params)
+            .map_err(|op| HttpError::bad_request(Some(op.to_string())))?;
+        let db_connection = state.db.clone();
+        let statement = QueryBuilder::new(tenant)
+            .conditions(&parameters)
+            .into_values_statement(db_connection.get_database_backend(), &field, top);
+
+        let values = serde_json::Value::find_by_statement(statement)
+            .all(&*db_connection)
+            .await
+            .log_error("An exception occurred while counting field values")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+
+        Ok(Json(serde_json::json!({
+            "field": field.name(),
+            "values": values,
+        })))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{
+        self,
+        StatusCode,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::Api,
+        testing::{
+            self,
+            call,
+        },
+    };
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_fields_database() {
+        let config = testing::config(&[]);
+        let db = testing::database(&config)
+            .await;
+        let tenant = testing::tenant("fields");
+        let headers = [("X-Tenant", tenant.as_str())];
+        let router: axum::Router = Api::new(db, config).into();
+        let body = json!([
+            {"timestamp": "2022-12-25T13:45:00Z", "level": 3, "message": "a", "context": {"http": {"status": 500}, "user": "alice"}},
+            {"timestamp": "2022-12-25T13:46:00Z", "level": 3, "message": "b", "context": {"http": {"status": 500}, "user": 7}},
+            {"timestamp": "2022-12-25T13:47:00Z", "level": 1, "message": "c", "context": {"http": {"status": 200}}},
+            {"timestamp": "2022-12-20T13:45:00Z", "level": 3, "message": "old", "context": {"stale": true}}
+        ]);
+        let (status, _) = call(&router, http::Method::POST, "/logs", &headers, Some(body)).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (status, fields) = call(&router, http::Method::GET, "/fields?from=2022-12-25&to=2022-12-26", &headers, None).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(fields, json!([
+            {"name": "http", "count": 3, "types": {"object": 3}},
+            {"name": "user", "count": 2, "types": {"number": 1, "string": 1}},
+        ]));
+
+        let (status, values) = call(&router, http::Method::GET, "/fields/http.status/values?from=2022-12-25&top=1", &headers, None).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(values, json!({"field": "context.http.status", "values": [{"value": 500, "count": 2}]}));
+
+        let (_, values) = call(&router, http::Method::GET, "/fields/level/values?filter[message][eq]=c", &headers, None).await;
+
+        assert_eq!(values, json!({"field": "level", "values": [{"value": 1, "count": 1}]}));
+
+        let (status, _) = call(&router, http::Method::GET, "/fields/level/values?top=0", &headers, None).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
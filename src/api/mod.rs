@@ -1,15 +1,65 @@
 use axum::{
-    routing::get,
+    body::Body,
+    extract::State,
+    http::Request,
+    middleware::{
+        self,
+        Next,
+    },
+    response::Response,
+    routing::{
+        get,
+        post,
+    },
     Router,
 };
 use sea_orm::DatabaseConnection;
 
 use crate::{
-    api::logs::Logs,
+    api::{
+        alerts::Alerts,
+        archives::Archives,
+        fields::Fields,
+        logs::Logs,
+        metrics::Metrics,
+        patterns::Patterns,
+        retention::Retention,
+        saved_searches::SavedSearches,
+        stats::Stats,
+        traces::Traces,
+    },
     config::Config,
+    error::ErrorKind,
+    ingest::{
+        metrics::LogMetrics,
+        patterns::PatternMiner,
+        pipeline::Pipeline,
+        redaction::Redactor,
+    },
+    metrics::ServiceMetrics,
+    tasks::retention::RetentionStats,
 };
 
+mod actor;
+mod admin;
+mod alerts;
+mod archives;
+mod fields;
 mod logs;
+mod metrics;
+mod patterns;
+mod retention;
+mod saved_searches;
+mod stats;
+mod stream;
+mod tenant;
+mod traces;
+
+pub use self::{
+    actor::Actor,
+    admin::Admin,
+    tenant::Tenant,
+};
 
 
 #[derive(Clone)]
@@ -19,47 +69,188 @@ pub struct AppState {
     // to satisfy Mock
     pub db: std::sync::Arc<DatabaseConnection>,
     pub config: Config,
+    pub retention: std::sync::Arc<RetentionStats>,
+    pub redactor: std::sync::Arc<Redactor>,
+    pub pipeline: std::sync::Arc<Pipeline>,
+    pub patterns: std::sync::Arc<PatternMiner>,
+    pub log_metrics: std::sync::Arc<LogMetrics>,
+    pub metrics: std::sync::Arc<ServiceMetrics>,
+    /// Key for the deletion confirmation tokens; random per process unless
+    /// `CONFIRMATION_SECRET` is set
+    pub confirmation_secret: std::sync::Arc<Vec<u8>>,
 }
 
 impl AppState {
     pub fn new(db: DatabaseConnection, config: Config) -> Self {
+        let confirmation_secret = match &config.confirmation_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut secret = vec![0; 32];
+
+                getrandom::getrandom(&mut secret)
+                    .expect("Could not generate a confirmation secret");
+
+                secret
+            },
+        };
+
+        let patterns = PatternMiner::new(config.pattern_max_per_tenant);
+
         Self {
             db: std::sync::Arc::new(db),
             config,
+            retention: std::sync::Arc::new(RetentionStats::default()),
+            redactor: std::sync::Arc::new(Redactor::default()),
+            pipeline: std::sync::Arc::new(Pipeline::default()),
+            patterns: std::sync::Arc::new(patterns),
+            log_metrics: std::sync::Arc::new(LogMetrics::default()),
+            metrics: std::sync::Arc::new(ServiceMetrics::default()),
+            confirmation_secret: std::sync::Arc::new(confirmation_secret),
         }
     }
 }
 
 pub struct Api {
-    db: DatabaseConnection,
-    config: Config,
+    state: AppState,
 }
 
 
 impl Api {
     pub fn new(db: DatabaseConnection, config: Config) -> Self {
         Self {
-            db,
-            config
+            state: AppState::new(db, config),
         }
     }
 
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.state.redactor = std::sync::Arc::new(redactor);
+        self
+    }
+
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.state.pipeline = std::sync::Arc::new(pipeline);
+        self
+    }
+
+    pub fn with_log_metrics(mut self, log_metrics: LogMetrics) -> Self {
+        self.state.log_metrics = std::sync::Arc::new(log_metrics);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: ServiceMetrics) -> Self {
+        self.state.metrics = std::sync::Arc::new(metrics);
+        self
+    }
+
+    /// Shared state, for background tasks which run alongside the router
+    pub fn state(&self) -> AppState {
+        self.state.clone()
+    }
+
     pub fn into_router(self) -> Router {
-        let state = AppState::new(self.db, self.config);
+        let state = self.state;
 
         Router::new()
             .route(
                 "/logs", 
                 get(Logs::query_logs)
                     .post(Logs::ingest_logs)
+                    .delete(Logs::delete_logs)
+            )
+            .route(
+                "/logs/:id",
+                get(Logs::get_log)
+            )
+            .route(
+                "/logs/:id/context",
+                get(Logs::log_context)
+            )
+            .route(
+                "/fields",
+                get(Fields::list)
+            )
+            .route(
+                "/fields/:name/values",
+                get(Fields::values)
+            )
+            .route(
+                "/metrics",
+                get(Metrics::render)
+            )
+            .route(
+                "/patterns",
+                get(Patterns::list)
             )
+            .route(
+                "/patterns/mine",
+                post(Patterns::mine)
+            )
+            .route(
+                "/redactions",
+                get(Logs::redactions)
+            )
+            .route(
+                "/retention",
+                get(Retention::stats)
+            )
+            .route(
+                "/saved-searches",
+                get(SavedSearches::list)
+                    .post(SavedSearches::create)
+            )
+            .route(
+                "/saved-searches/:id",
+                get(SavedSearches::get)
+                    .put(SavedSearches::update)
+                    .delete(SavedSearches::delete)
+            )
+            .route(
+                "/saved-searches/:id/run",
+                get(SavedSearches::run)
+            )
+            .route(
+                "/stats",
+                get(Stats::summarise)
+            )
+            .route(
+                "/traces/:trace_id/logs",
+                get(Traces::logs)
+            )
+            .route(
+                "/alerts",
+                get(Alerts::list)
+                    .post(Alerts::create)
+            )
+            .route(
+                "/alerts/:id",
+                get(Alerts::get)
+                    .put(Alerts::update)
+                    .delete(Alerts::delete)
+            )
+            .route(
+                "/archives/:day/restore",
+                post(Archives::restore)
+            )
+            .layer(middleware::from_fn_with_state(state.clone(), record_errors))
             .with_state(state)
     }
 }
 
 
-impl Into<Router> for Api {
-    fn into(self) -> Router {
-        self.into_router()
+/// Counts responses made from an `HttpError`, by variant
+async fn record_errors(state: State<AppState>, request: Request<Body>, next: Next<Body>) -> Response {
+    let response = next.run(request).await;
+
+    if let Some(ErrorKind(kind)) = response.extensions().get::<ErrorKind>() {
+        state.metrics.record_error(kind);
+    }
+
+    response
+}
+
+
+impl From<Api> for Router {
+    fn from(api: Api) -> Self {
+        api.into_router()
     }
 }
@@ -0,0 +1,234 @@
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+
+use axum::{
+    extract::{
+        Query,
+        State,
+    },
+    Json,
+};
+use sea_orm::{
+    ConnectionTrait,
+    FromQueryResult,
+    Statement,
+};
+
+use crate::{
+    error::{
+        HttpError,
+        Loggable,
+    },
+    models::{
+        LogModel,
+        QueryBuilder,
+    },
+    parameters::{
+        FieldSelector,
+        QueryParameters,
+        SortField,
+    },
+};
+
+use super::{
+    AppState,
+    Tenant,
+};
+
+
+const LIMIT_PARAMETER: &str = "limit";
+const DEFAULT_PATTERNS: u64 = 100;
+const MAX_PATTERNS: u64 = 1000;
+const DEFAULT_MINE_ROWS: u64 = 10_000;
+const MAX_MINE_ROWS: u64 = 100_000;
+
+
+pub struct Patterns;
+
+impl Patterns {
+    /// Lists the patterns of matching logs, most frequent first
+    pub async fn list(
+        state: State<AppState>,
+        tenant: Tenant,
+        Query(mut params): Query<HashMap<String, String>>,
+    ) -> Result<Json<serde_json::Value>, HttpError> {
+        let limit = Self::limit(params.remove(LIMIT_PARAMETER), DEFAULT_PATTERNS, MAX_PATTERNS)?;
+        let parameters = QueryParameters::from_hashmap(params)
+            .map_err(|op| HttpError::bad_request(Some(op.to_string())))?;
+        let db_connection = state.db.clone();
+        let statement = QueryBuilder::new(tenant)
+            .conditions(&parameters)
+            .into_patterns_statement(db_connection.get_database_backend(), limit);
+
+        let patterns = serde_json::Value::find_by_statement(statement)
+            .all(&*db_connection)
+            .await
+            .log_error("An exception occurred while listing patterns")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+
+        Ok(Json(serde_json::Value::Array(patterns)))
+    }
+
+    /// Mines patterns for matching logs which don't have one yet, such as logs ingested while
+    /// mining was disabled, oldest first
+    pub async fn mine(
+        state: State<AppState>,
+        tenant: Tenant,
+        Query(mut params): Query<HashMap<String, String>>,
+    ) -> Result<Json<serde_json::Value>, HttpError> {
+        let limit = Self::limit(params.remove(LIMIT_PARAMETER), DEFAULT_MINE_ROWS, MAX_MINE_ROWS)?;
+        let parameters = QueryParameters::from_hashmap(params)
+            .map_err(|op| HttpError::bad_request(Some(op.to_string())))?;
+        let db_connection = state.db.clone();
+        let backend = db_connection.get_database_backend();
+        let fields = [FieldSelector::Column("id".to_string()), FieldSelector::Column("message".to_string())];
+        let sort = [SortField::asc(FieldSelector::Column("timestamp".to_string()))];
+
+        let logs = QueryBuilder::new(tenant.as_str())
+            .select(&fields)
+            .conditions(&parameters)
+            .null(FieldSelector::Column("pattern_id".to_string()))
+            .sort(&sort)
+            .limit(limit)
+            .build(&db_connection)
+            .into_json()
+            .all(&*db_connection)
+            .await
+            .log_error("An exception occurred while querying logs to mine")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+        let messages: Vec<&str> = logs
+            .iter()
+            .map(|log| log["message"].as_str().unwrap_or_default())
+            .collect();
+
+        let pattern_ids = state.patterns.mine(&db_connection, tenant.as_str(), &messages)
+            .await
+            .log_error("An exception occurred while mining log patterns")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+
+        let mut ids_by_pattern: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        // logs left without a pattern, once the tenant has its maximum, stay unmined
+        for (log, pattern_id) in logs.iter().zip(pattern_ids) {
+            if let (Some(id), Some(pattern_id)) = (log["id"].as_i64(), pattern_id) {
+                ids_by_pattern.entry(pattern_id).or_default().push(id.to_string());
+            }
+        }
+
+        for (pattern_id, ids) in &ids_by_pattern {
+            // ids are integers read back from the database, so are safe to inline
+            let statement = Statement::from_sql_and_values(
+                backend,
+                &format!(
+                    "UPDATE {} SET \"pattern_id\" = $1 WHERE \"tenant_id\" = $2 AND \"id\" IN ({})",
+                    LogModel::table_name(),
+                    ids.join(", "),
+                ),
+                [pattern_id.clone().into(), tenant.as_str().into()],
+            );
+
+            db_connection.execute(statement)
+                .await
+                .log_error("An exception occurred while storing log patterns")
+                .map_err(|_| HttpError::internal_server_error(None))?;
+        }
+
+        Ok(Json(serde_json::json!({
+            "mined": logs.len(),
+            "patterns": ids_by_pattern.len(),
+        })))
+    }
+
+    fn limit(limit: Option<String>, default: u64, max: u64) -> Result<u64, HttpError> {
+        match limit {
+            Some(limit) => limit
+                .parse::<u64>()
+                .ok()
+                .filter(|limit| (1..=max).contains(limit))
+                .ok_or_else(|| HttpError::bad_request(Some(format!("{} must be between 1 and {}", LIMIT_PARAMETER, max)))),
+            None => Ok(default),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{
+        self,
+        StatusCode,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::Api,
+        testing::{
+            self,
+            call,
+        },
+    };
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_patterns_database() {
+        let config = testing::config(&[]);
+        let tenant = testing::tenant("patterns");
+        let headers = [("X-Tenant", tenant.as_str())];
+        let logs = |messages: &[&str]| -> serde_json::Value {
+            messages
+                .iter()
+                .enumerate()
+                .map(|(i, message)| json!({
+                    "timestamp": format!("2022-12-25T13:45:0{}Z", i),
+                    "level": 3,
+                    "message": message,
+                }))
+                .collect()
+        };
+
+        // ingested before mining is enabled
+        let router: axum::Router = Api::new(testing::database(&config).await, config.clone()).into();
+        let (status, _) = call(&router, http::Method::POST, "/logs", &headers, Some(logs(&["user 7 logged in", "disk full on /dev/sda1"]))).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (_, patterns) = call(&router, http::Method::GET, "/patterns", &headers, None).await;
+
+        assert_eq!(patterns, json!([]));
+
+        let mut config = config;
+
+        config.pattern_mining = true;
+
+        let router: axum::Router = Api::new(testing::database(&config).await, config).into();
+        let (status, _) = call(&router, http::Method::POST, "/logs", &headers, Some(logs(&["user 12 logged in", "user 30 logged in"]))).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (status, mined) = call(&router, http::Method::POST, "/patterns/mine?filter[level][eq]=3", &headers, None).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(mined, json!({"mined": 2, "patterns": 2}));
+
+        let (status, patterns) = call(&router, http::Method::GET, "/patterns?from=2022-12-25&limit=10", &headers, None).await;
+
+        assert_eq!(status, StatusCode::OK);
+
+        let patterns = patterns.as_array().unwrap();
+
+        assert_eq!(patterns.len(), 2);
+        assert_eq!(patterns[0]["template"], "user <*> logged in");
+        assert_eq!(patterns[0]["count"], 3);
+        assert_eq!(patterns[0]["sample"], "user 12 logged in");
+        assert!(patterns[0]["first_seen"].as_str().unwrap().starts_with("2022-12-25T13:45:00"));
+        assert!(patterns[0]["last_seen"].as_str().unwrap().starts_with("2022-12-25T13:45:01"));
+        assert_eq!(patterns[1]["template"], "disk full on <*>");
+        assert_eq!(patterns[1]["count"], 1);
+
+        let (_, logs) = call(&router, http::Method::GET, &format!("/logs?filter[pattern_id][eq]={}", patterns[1]["id"].as_str().unwrap()), &headers, None).await;
+
+        assert_eq!(logs[0]["message"], "disk full on /dev/sda1");
+    }
+}
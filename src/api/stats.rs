@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{
+        Query,
+        State,
+    },
+    Json,
+};
+use sea_orm::{
+    ConnectionTrait,
+    FromQueryResult,
+};
+
+use crate::{
+    error::{
+        HttpError,
+        Loggable,
+    },
+    models::QueryBuilder,
+    parameters::StatsParameters,
+};
+
+use super::{
+    AppState,
+    Tenant,
+};
+
+
+/// Most rows (buckets and groups) returned by a single request
+const MAX_ROWS: u64 = 10_000;
+
+
+pub struct Stats;
+
+impl Stats {
+    /// Summarises a numeric column or context path over matching logs, optionally per time
+    /// bucket and per group
+    pub async fn summarise(
+        state: State<AppState>,
+        tenant: Tenant,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Result<Json<serde_json::Value>, HttpError> {
+        let parameters = StatsParameters::from_hashmap(params)
+            .map_err(|op| HttpError::bad_request(Some(op.to_string())))?;
+        let db_connection = state.db.clone();
+        let statement = QueryBuilder::new(tenant)
+            .conditions(&parameters.query)
+            .into_stats_statement(db_connection.get_database_backend(), &parameters, MAX_ROWS);
+
+        let rows = serde_json::Value::find_by_statement(statement)
+            .all(&*db_connection)
+            .await
+            .log_error("An exception occurred while summarising logs")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+
+        Ok(Json(serde_json::json!({
+            "field": parameters.field.name(),
+            "interval": parameters.interval,
+            "rows": rows,
+        })))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{
+        self,
+        StatusCode,
+    };
+    use sea_orm::{
+        DatabaseBackend,
+        MockDatabase,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::Api,
+        testing::{
+            self,
+            call,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_stats_non_numeric_column() {
+        let router: axum::Router = Api::new(
+            MockDatabase::new(DatabaseBackend::Postgres).into_connection(),
+            testing::config(&[]),
+        ).into();
+
+        for field in ["message", "timestamp"] {
+            let (status, _) = call(&router, http::Method::GET, &format!("/stats?field={}", field), &[], None).await;
+
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_stats_database() {
+        let config = testing::config(&[]);
+        let db = testing::database(&config)
+            .await;
+        let tenant = testing::tenant("stats");
+        let headers = [("X-Tenant", tenant.as_str())];
+        let router: axum::Router = Api::new(db, config).into();
+        let body = json!([
+            {"timestamp": "2022-12-25T13:45:00Z", "level": 3, "message": "a", "context": {"service": "api", "latency_ms": 10}},
+            {"timestamp": "2022-12-25T13:46:00Z", "level": 3, "message": "b", "context": {"service": "api", "latency_ms": 30}},
+            {"timestamp": "2022-12-25T13:52:00Z", "level": 3, "message": "c", "context": {"service": "api", "latency_ms": 50}},
+            {"timestamp": "2022-12-25T13:46:00Z", "level": 3, "message": "d", "context": {"service": "worker", "latency_ms": 5.5}},
+            {"timestamp": "2022-12-25T13:46:00Z", "level": 3, "message": "e", "context": {"service": "worker", "latency_ms": "slow"}}
+        ]);
+        let (status, _) = call(&router, http::Method::POST, "/logs", &headers, Some(body)).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let uri = "/stats?field=latency_ms&stats=count,avg,sum,min,max,p50&group_by=service&interval=10m&from=2022-12-25";
+        let (status, body) = call(&router, http::Method::GET, uri, &headers, None).await;
+
+        assert_eq!(status, StatusCode::OK);
+
+        let rows = body["rows"].as_array().unwrap();
+
+        assert_eq!(body["field"], "context.latency_ms");
+        assert_eq!(body["interval"], 600);
+        assert_eq!(rows.len(), 3);
+
+        // 13:40 - 13:50 for the api, ignoring the worker's non-numeric latency
+        assert_eq!(rows[0]["service"], "api");
+        assert_eq!(rows[0]["count"], 2);
+        assert_eq!(rows[0]["avg"], 20.0);
+        assert_eq!(rows[0]["sum"], 40.0);
+        assert_eq!(rows[0]["min"], 10.0);
+        assert_eq!(rows[0]["max"], 30.0);
+        assert_eq!(rows[0]["p50"], 20.0);
+        assert_eq!(rows[1]["service"], "worker");
+        assert_eq!(rows[1]["count"], 1);
+        assert_eq!(rows[1]["p50"], 5.5);
+        assert_eq!(rows[2]["service"], "api");
+        assert_eq!(rows[2]["count"], 1);
+        assert!(rows[0]["bucket"].as_str().unwrap().starts_with("2022-12-25T13:40:00"));
+        assert!(rows[2]["bucket"].as_str().unwrap().starts_with("2022-12-25T13:50:00"));
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    error::Error,
+    fmt::{
+        Display,
+        Formatter,
+    },
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use axum::{
+    body::{
+        boxed,
+        Body,
+        Bytes,
+    },
+    http::{
+        header::{
+            ACCEPT,
+            CONTENT_DISPOSITION,
+            CONTENT_TYPE,
+        },
+        HeaderMap,
+        HeaderValue,
+    },
+    response::Response,
+};
+use hyper::body::Sender;
+use sea_orm::{
+    ConnectionTrait,
+    DatabaseBackend,
+    DatabaseConnection,
+    DatabaseTransaction,
+    DbErr,
+    EntityTrait,
+    Statement,
+    TransactionTrait,
+};
+
+use crate::{
+    export::{
+        Encoder,
+        ExportError,
+    },
+    metrics::ServiceMetrics,
+    models::{
+        Log,
+        QueryBuilder,
+    },
+};
+
+use super::logs::flatten_context;
+
+
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+/// Header carrying the row cap applied to a streamed response
+const ROW_LIMIT_HEADER: &str = "X-Row-Limit";
+const CURSOR_NAME: &str = "logs_stream";
+/// Rows fetched from the cursor per round trip
+const FETCH_SIZE: u64 = 1000;
+/// Longest a single fetch from the cursor may run
+const STATEMENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest a client may take to accept a chunk of the body before the stream is aborted
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest the cursor's transaction may sit idle, e.g. if the task holding it stalls; longer
+/// than `SEND_TIMEOUT` so waiting on a slow client doesn't trip it
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+
+/// Whether the client asked for newline delimited JSON
+pub fn accepts_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| media_type.split(';').next().unwrap_or_default().trim() == NDJSON_CONTENT_TYPE)
+}
+
+
+/// A server-side cursor over the rows of a query, read in batches
+pub struct Cursor {
+    transaction: DatabaseTransaction,
+    backend: DatabaseBackend,
+    exhausted: bool,
+}
+
+impl Cursor {
+    pub async fn open(db: &DatabaseConnection, query: QueryBuilder) -> Result<Self, DbErr> {
+        let backend = db.get_database_backend();
+        let statement = query.into_sql_statement(backend);
+        // cursors only live as long as the transaction which declares them
+        let transaction = db.begin().await?;
+
+        // the transaction holds a connection for as long as the stream runs, so neither a
+        // slow query nor an abandoned transaction can hold it indefinitely
+        for (setting, timeout) in [("statement_timeout", STATEMENT_TIMEOUT), ("idle_in_transaction_session_timeout", IDLE_TIMEOUT)] {
+            transaction
+                .execute(Statement::from_string(
+                    backend,
+                    format!("SET LOCAL {} = {}", setting, timeout.as_millis()),
+                ))
+                .await?;
+        }
+
+        transaction
+            .execute(Statement::from_sql_and_values(
+                backend,
+                &format!("DECLARE {} NO SCROLL CURSOR FOR {}", CURSOR_NAME, statement.sql),
+                statement.values.map(|values| values.0).unwrap_or_default(),
+            ))
+            .await?;
+
+        Ok(Self {
+            transaction,
+            backend,
+            exhausted: false,
+        })
+    }
+
+    /// The next batch of rows, or None once every row has been read
+    pub async fn next(&mut self) -> Result<Option<Vec<serde_json::Value>>, DbErr> {
+        if self.exhausted {
+            return Ok(None);
+        }
+
+        let rows = Log::find()
+            .from_raw_sql(Statement::from_string(self.backend, format!("FETCH {} FROM {}", FETCH_SIZE, CURSOR_NAME)))
+            .into_json()
+            .all(&self.transaction)
+            .await?;
+
+        self.exhausted = (rows.len() as u64) < FETCH_SIZE;
+
+        if rows.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(rows))
+        }
+    }
+
+    /// Closes the cursor; nothing was written, so the transaction is rolled back
+    pub async fn close(self) -> Result<(), DbErr> {
+        self.transaction.rollback().await
+    }
+}
+
+
+#[derive(Debug)]
+enum StreamError {
+    Database(DbErr),
+    Export(ExportError),
+}
+
+impl Error for StreamError {}
+
+impl Display for StreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamError::Database(e) => write!(f, "Database error: {}", e),
+            StreamError::Export(e) => write!(f, "Export error: {}", e),
+        }
+    }
+}
+
+impl From<DbErr> for StreamError {
+    fn from(e: DbErr) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl From<ExportError> for StreamError {
+    fn from(e: ExportError) -> Self {
+        Self::Export(e)
+    }
+}
+
+
+/// Newline delimited JSON, one row per line
+pub struct Ndjson {
+    flatten: bool,
+}
+
+impl Ndjson {
+    pub fn new(flatten: bool) -> Self {
+        Self {
+            flatten,
+        }
+    }
+}
+
+impl Encoder for Ndjson {
+    fn content_type(&self) -> &'static str {
+        NDJSON_CONTENT_TYPE
+    }
+
+    fn encode(&mut self, rows: Vec<serde_json::Value>) -> Result<Vec<u8>, ExportError> {
+        let mut buffer = Vec::new();
+
+        for row in rows {
+            let row = if self.flatten { flatten_context(row) } else { row };
+
+            serde_json::to_writer(&mut buffer, &row)
+                .expect("Serialising a JSON value cannot fail");
+            buffer.push(b'\n');
+        }
+
+        Ok(buffer)
+    }
+
+    fn truncated(&mut self, max_rows: u64) -> Option<Vec<u8>> {
+        let mut buffer = serde_json::to_vec(&serde_json::json!({"truncated": true, "row_limit": max_rows}))
+            .expect("Serialising a JSON value cannot fail");
+
+        buffer.push(b'\n');
+
+        Some(buffer)
+    }
+}
+
+
+/// How a stream ended, short of an error
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Complete,
+    /// More than `max_rows` rows matched
+    Truncated,
+    /// The client went away
+    Disconnected,
+    /// The client didn't accept a chunk within `SEND_TIMEOUT`
+    TimedOut,
+}
+
+
+/// Sends `bytes` to the client, giving up after `SEND_TIMEOUT`
+async fn send(sender: &mut Sender, bytes: Vec<u8>) -> Outcome {
+    if bytes.is_empty() {
+        return Outcome::Complete;
+    }
+
+    match tokio::time::timeout(SEND_TIMEOUT, sender.send_data(Bytes::from(bytes))).await {
+        Ok(Ok(())) => Outcome::Complete,
+        Ok(Err(_)) => Outcome::Disconnected,
+        Err(_) => Outcome::TimedOut,
+    }
+}
+
+
+/// Streams the rows of `query` through `encoder`. Rows are read through a [`Cursor`] and
+/// written to the body as they're fetched, so neither side holds the full result. At most
+/// `max_rows` rows are returned; when more match, the encoder's truncation marker ends the
+/// body, or the body is aborted for formats without one.
+pub fn response(
+    db: Arc<DatabaseConnection>,
+    metrics: Arc<ServiceMetrics>,
+    query: QueryBuilder,
+    max_rows: u64,
+    mut encoder: Box<dyn Encoder>,
+) -> Response {
+    let (mut sender, body) = Body::channel();
+    let content_type = encoder.content_type();
+    let filename = encoder.filename();
+
+    tokio::spawn(async move {
+        let started = Instant::now();
+        let mut count = 0;
+        let mut marked = false;
+        let result: Result<Outcome, StreamError> = async {
+            // one more row than the cap, to tell whether the result was cut short
+            let mut cursor = Cursor::open(&db, query.limit(max_rows + 1)).await?;
+            let mut outcome = Outcome::Complete;
+
+            while let Some(mut rows) = cursor.next().await? {
+                if count + rows.len() as u64 > max_rows {
+                    rows.truncate((max_rows - count) as usize);
+                    outcome = Outcome::Truncated;
+                }
+
+                count += rows.len() as u64;
+
+                let bytes = encoder.encode(rows)?;
+
+                match send(&mut sender, bytes).await {
+                    Outcome::Complete => {},
+                    failed => {
+                        outcome = failed;
+                        break;
+                    },
+                }
+
+                if outcome == Outcome::Truncated {
+                    break;
+                }
+            }
+
+            cursor.close().await?;
+
+            let trailer = match outcome {
+                Outcome::Complete => Some(vec![]),
+                Outcome::Truncated => {
+                    let marker = encoder.truncated(max_rows);
+
+                    marked = marker.is_some();
+                    marker
+                },
+                Outcome::Disconnected | Outcome::TimedOut => None,
+            };
+
+            if let Some(trailer) = trailer {
+                let mut bytes = trailer;
+
+                bytes.extend(encoder.finish()?);
+
+                // nothing more to be done if the client goes away now
+                if send(&mut sender, bytes).await == Outcome::TimedOut {
+                    return Ok(Outcome::TimedOut);
+                }
+            }
+
+            Ok(outcome)
+        }.await;
+
+        // the client can only tell the stream is incomplete if the body is aborted
+        match result {
+            Ok(Outcome::Complete | Outcome::Disconnected) => metrics.record_query("stream", started.elapsed(), count),
+            Ok(Outcome::Truncated) => {
+                metrics.record_query("stream", started.elapsed(), count);
+
+                if !marked {
+                    sender.abort();
+                }
+            },
+            Ok(Outcome::TimedOut) => {
+                tracing::warn!("Aborted a stream of logs after the client stopped reading");
+                sender.abort();
+            },
+            Err(e) => {
+                tracing::error!("An exception occurred while streaming logs: {}", e);
+                sender.abort();
+            },
+        }
+    });
+
+    let mut response = Response::new(boxed(body));
+    let headers = response.headers_mut();
+
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(ROW_LIMIT_HEADER, HeaderValue::from(max_rows));
+
+    if let Some(filename) = filename {
+        let disposition = format!("attachment; filename=\"{}\"", filename);
+
+        headers.insert(CONTENT_DISPOSITION, HeaderValue::from_str(&disposition).expect("Filenames are valid header values"));
+    }
+
+    response
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{
+        header::ACCEPT,
+        HeaderMap,
+        HeaderValue,
+    };
+
+    use super::accepts_ndjson;
+
+    #[test]
+    fn test_accepts_ndjson() {
+        let headers = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+
+            headers.insert(ACCEPT, HeaderValue::from_static(accept));
+            headers
+        };
+
+        assert!(accepts_ndjson(&headers("application/x-ndjson")));
+        assert!(accepts_ndjson(&headers("application/json;q=0.5, application/x-ndjson;q=1")));
+        assert!(!accepts_ndjson(&headers("application/json")));
+        assert!(!accepts_ndjson(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque,
+};
+
+use axum::{
+    extract::{
+        Path,
+        State,
+    },
+    Json,
+};
+
+use crate::{
+    error::{
+        HttpError,
+        Loggable,
+    },
+    models::QueryBuilder,
+    parameters::FieldSelector,
+};
+
+use super::{
+    AppState,
+    Tenant,
+};
+
+
+/// Context key naming the span a span was started from
+const PARENT_SPAN_KEY: &str = "parent_span_id";
+/// Most levels of spans nested in the response; deeper spans are listed at the top level
+const MAX_SPAN_DEPTH: usize = 64;
+/// Most logs of a trace returned
+const MAX_TRACE_ROWS: u64 = 10_000;
+
+
+struct Span {
+    parent_span_id: Option<String>,
+    logs: Vec<serde_json::Value>,
+    children: Vec<String>,
+}
+
+
+/// Groups the logs of a trace by span, nesting each span under its parent. Spans whose
+/// parent has no logs in the trace become roots; logs without a span id are returned apart.
+#[derive(Default)]
+struct SpanTree {
+    order: Vec<String>,
+    spans: HashMap<String, Span>,
+    unassigned: Vec<serde_json::Value>,
+}
+
+impl SpanTree {
+    /// Builds the tree from logs in the order they should be listed
+    fn build(logs: Vec<serde_json::Value>) -> Self {
+        let mut tree = Self::default();
+
+        for log in logs {
+            let span_id = match log.get("span_id").and_then(|span_id| span_id.as_str()) {
+                Some(span_id) => span_id.to_string(),
+                None => {
+                    tree.unassigned.push(log);
+                    continue;
+                },
+            };
+            let parent_span_id = log
+                .get("context")
+                .and_then(|context| context.get(PARENT_SPAN_KEY))
+                .and_then(|parent| parent.as_str())
+                .filter(|parent| *parent != span_id)
+                .map(|parent| parent.to_string());
+
+            let span = tree.spans
+                .entry(span_id.clone())
+                .or_insert_with(|| {
+                    tree.order.push(span_id.clone());
+
+                    Span {
+                        parent_span_id: None,
+                        logs: vec![],
+                        children: vec![],
+                    }
+                });
+
+            if span.parent_span_id.is_none() {
+                span.parent_span_id = parent_span_id;
+            }
+
+            span.logs.push(log);
+        }
+
+        for span_id in &tree.order {
+            let parent = tree.spans[span_id].parent_span_id.clone();
+
+            if let Some(parent) = parent.filter(|parent| tree.spans.contains_key(parent)) {
+                tree.spans
+                    .get_mut(&parent)
+                    .unwrap()
+                    .children
+                    .push(span_id.clone());
+            }
+        }
+
+        tree
+    }
+
+    fn into_json(mut self, trace_id: &str, truncated: bool) -> serde_json::Value {
+        let count = self.unassigned.len() + self.spans.values().map(|span| span.logs.len()).sum::<usize>();
+        let mut visited = HashSet::new();
+        let mut roots = vec![];
+
+        let mut queue: VecDeque<String> = self.order
+            .iter()
+            .filter(|span_id| {
+                self.spans[*span_id].parent_span_id
+                    .as_ref()
+                    .map(|parent| !self.spans.contains_key(parent))
+                    .unwrap_or(true)
+            })
+            .cloned()
+            .collect();
+        let mut order = self.order.clone().into_iter();
+
+        loop {
+            let span_id = match queue.pop_front() {
+                Some(span_id) => span_id,
+                // spans whose parents form a cycle are never reached from a root
+                None => match order.find(|span_id| !visited.contains(span_id)) {
+                    Some(span_id) => span_id,
+                    None => break,
+                },
+            };
+
+            visited.insert(span_id.clone());
+
+            let (span, deep) = self.render(&span_id, &mut visited);
+
+            roots.push(span);
+            queue.extend(deep);
+        }
+
+        serde_json::json!({
+            "trace_id": trace_id,
+            "count": count,
+            "truncated": truncated,
+            "spans": roots,
+            "unassigned": self.unassigned,
+        })
+    }
+
+    /// Renders the spans under `root` without recursing, as the depth of a trace is up to its
+    /// sender. Spans nested more than `MAX_SPAN_DEPTH` deep are returned to be listed at the
+    /// top level instead.
+    fn render(&mut self, root: &str, visited: &mut HashSet<String>) -> (serde_json::Value, Vec<String>) {
+        let mut preorder = vec![];
+        let mut deep = vec![];
+        let mut stack = vec![(root.to_string(), 1)];
+
+        while let Some((span_id, depth)) = stack.pop() {
+            let mut children = vec![];
+
+            for child in &self.spans[&span_id].children {
+                if !visited.insert(child.clone()) {
+                    continue;
+                }
+
+                if depth >= MAX_SPAN_DEPTH {
+                    deep.push(child.clone());
+                } else {
+                    children.push(child.clone());
+                    stack.push((child.clone(), depth + 1));
+                }
+            }
+
+            preorder.push((span_id, children));
+        }
+
+        // children come after their parent in preorder, so are rendered first in reverse
+        let mut rendered: HashMap<String, serde_json::Value> = HashMap::new();
+
+        for (span_id, children) in preorder.into_iter().rev() {
+            let span = self.spans.get_mut(&span_id).unwrap();
+            let children: Vec<serde_json::Value> = children
+                .iter()
+                .filter_map(|child| rendered.remove(child))
+                .collect();
+            let json = serde_json::json!({
+                "span_id": span_id,
+                "parent_span_id": span.parent_span_id,
+                "logs": std::mem::take(&mut span.logs),
+                "children": children,
+            });
+
+            rendered.insert(span_id, json);
+        }
+
+        (rendered.remove(root).unwrap_or_default(), deep)
+    }
+}
+
+
+pub struct Traces;
+
+impl Traces {
+    /// The logs of a trace, ordered by time and grouped into a tree of spans; at most
+    /// `MAX_TRACE_ROWS`, with `truncated` set when there were more
+    pub async fn logs(
+        state: State<AppState>,
+        tenant: Tenant,
+        Path(trace_id): Path<String>,
+    ) -> Result<Json<serde_json::Value>, HttpError> {
+        let db_connection = state.db.clone();
+        let logs = QueryBuilder::new(tenant)
+            .eq(FieldSelector::Column("trace_id".to_string()), trace_id.clone())
+            .order_by_asc(FieldSelector::Column("timestamp".to_string()))
+            .order_by_asc(FieldSelector::Column("id".to_string()))
+            .limit(MAX_TRACE_ROWS + 1)
+            .build(&db_connection)
+            .into_json()
+            .all(&*db_connection)
+            .await
+            .log_error("An exception occurred while querying trace logs")
+            .map_err(|_| HttpError::internal_server_error(None))?;
+
+        let truncated = logs.len() as u64 > MAX_TRACE_ROWS;
+        let mut logs = logs;
+
+        logs.truncate(MAX_TRACE_ROWS as usize);
+
+        Ok(Json(SpanTree::build(logs).into_json(&trace_id, truncated)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{
+        self,
+        StatusCode,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::Api,
+        testing::{
+            self,
+            call,
+        },
+    };
+    use super::SpanTree;
+
+    fn log(id: i64, span_id: Option<&str>, parent_span_id: Option<&str>) -> serde_json::Value {
+        let context = match parent_span_id {
+            Some(parent) => json!({"parent_span_id": parent}),
+            None => json!({}),
+        };
+
+        json!({"id": id, "span_id": span_id, "context": context})
+    }
+
+    fn ids(logs: &serde_json::Value) -> Vec<i64> {
+        logs.as_array()
+            .unwrap()
+            .iter()
+            .map(|log| log["id"].as_i64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_span_tree() {
+        let tree = SpanTree::build(vec![
+            log(1, Some("root"), None),
+            log(2, Some("child"), Some("root")),
+            log(3, None, None),
+            log(4, Some("grandchild"), Some("child")),
+            log(5, Some("root"), None),
+            log(6, Some("orphan"), Some("missing")),
+        ]).into_json("trace", false);
+
+        assert_eq!(tree["count"], 6);
+        assert_eq!(ids(&tree["unassigned"]), vec![3]);
+
+        let spans = tree["spans"].as_array().unwrap();
+
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["span_id"], "root");
+        assert_eq!(ids(&spans[0]["logs"]), vec![1, 5]);
+        assert_eq!(spans[0]["children"][0]["span_id"], "child");
+        assert_eq!(spans[0]["children"][0]["children"][0]["span_id"], "grandchild");
+        assert_eq!(spans[1]["span_id"], "orphan");
+        assert_eq!(spans[1]["parent_span_id"], "missing");
+    }
+
+    #[test]
+    fn test_span_tree_cycle() {
+        let tree = SpanTree::build(vec![
+            log(1, Some("a"), Some("b")),
+            log(2, Some("b"), Some("a")),
+        ]).into_json("trace", false);
+
+        let spans = tree["spans"].as_array().unwrap();
+
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0]["children"][0]["span_id"], "b");
+    }
+
+    #[test]
+    fn test_span_tree_deep() {
+        let logs: Vec<serde_json::Value> = (0..5000)
+            .map(|id| {
+                let span_id = format!("span-{}", id);
+                let parent_span_id = format!("span-{}", id - 1);
+
+                log(id, Some(&span_id), (id > 0).then_some(parent_span_id.as_str()))
+            })
+            .collect();
+        let tree = SpanTree::build(logs).into_json("trace", true);
+
+        // a chain this long is split into top-level spans at most 64 deep
+        let spans = tree["spans"].as_array().unwrap();
+
+        assert_eq!(tree["truncated"], true);
+        assert_eq!(spans.len(), 79);
+        assert_eq!(spans[1]["span_id"], "span-64");
+        assert_eq!(spans[1]["parent_span_id"], "span-63");
+
+        let mut depth = 0;
+        let mut span = &spans[0];
+
+        while let Some(child) = span["children"].get(0) {
+            span = child;
+            depth += 1;
+        }
+
+        assert_eq!(depth, 63);
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_trace_logs_database() {
+        let config = testing::config(&[]);
+        let db = testing::database(&config)
+            .await;
+        let tenant = testing::tenant("traces");
+        let headers = [("X-Tenant", tenant.as_str())];
+        let router: axum::Router = Api::new(db, config).into();
+        let body = json!([
+            {
+                "timestamp": "2022-12-25T13:45:00Z",
+                "level": 3,
+                "message": "request received",
+                "context": {"traceparent": "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"}
+            },
+            {
+                "timestamp": "2022-12-25T13:45:01Z",
+                "level": 3,
+                "message": "query ran",
+                "trace_id": "4bf92f3577b34da6a3ce929d0e0e4736",
+                "span_id": "b7ad6b7169203331",
+                "context": {"parent_span_id": "00f067aa0ba902b7"}
+            },
+            {
+                "level": 3,
+                "message": "other trace",
+                "context": {"trace_id": "0af7651916cd43dd8448eb211c80319c"}
+            }
+        ]);
+        let (status, _) = call(&router, http::Method::POST, "/logs", &headers, Some(body)).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (status, body) = call(&router, http::Method::GET, "/traces/4bf92f3577b34da6a3ce929d0e0e4736/logs", &headers, None).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["count"], 2);
+        assert_eq!(body["spans"][0]["span_id"], "00f067aa0ba902b7");
+        assert_eq!(body["spans"][0]["logs"][0]["message"], "request received");
+        assert_eq!(body["spans"][0]["children"][0]["span_id"], "b7ad6b7169203331");
+        assert_eq!(body["spans"][0]["children"][0]["logs"][0]["message"], "query ran");
+    }
+}
@@ -0,0 +1,498 @@
+use std::{
+    collections::{
+        BTreeSet,
+        HashSet,
+    },
+    error::Error,
+    fmt::{
+        Display,
+        Formatter,
+    },
+    fs::{
+        self,
+        File,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+};
+
+use arrow_array::{
+    Array,
+    ArrayRef,
+    BooleanArray,
+    Int32Array,
+    Int64Array,
+    RecordBatch,
+    StringArray,
+    TimestampMicrosecondArray,
+};
+use arrow_schema::{
+    ArrowError,
+    DataType,
+    Field,
+    Schema,
+    SchemaRef,
+    TimeUnit,
+};
+use chrono::{
+    NaiveDate,
+    TimeZone,
+    Utc,
+};
+use parquet::{
+    arrow::{
+        arrow_reader::{
+            ArrowPredicateFn,
+            ParquetRecordBatchReaderBuilder,
+            RowFilter,
+        },
+        ArrowWriter,
+        ProjectionMask,
+    },
+    basic::Compression,
+    errors::ParquetError,
+    file::properties::WriterProperties,
+};
+
+use crate::models::LogModel;
+
+
+const CONTEXT_PREFIX: &str = "context.";
+
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Arrow(ArrowError),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Parquet(ParquetError),
+    Schema(String),
+}
+
+impl Error for ArchiveError {}
+
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveError::Arrow(e) => write!(f, "Arrow error: {}", e),
+            ArchiveError::Io(e) => write!(f, "IO error: {}", e),
+            ArchiveError::Json(e) => write!(f, "JSON error: {}", e),
+            ArchiveError::Parquet(e) => write!(f, "Parquet error: {}", e),
+            ArchiveError::Schema(e) => write!(f, "Invalid archive schema: {}", e),
+        }
+    }
+}
+
+impl From<ArrowError> for ArchiveError {
+    fn from(e: ArrowError) -> Self {
+        Self::Arrow(e)
+    }
+}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ArchiveError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl From<ParquetError> for ArchiveError {
+    fn from(e: ParquetError) -> Self {
+        Self::Parquet(e)
+    }
+}
+
+
+/// Parquet archive of logs, laid out as `<directory>/<YYYY-MM-DD>/<name>.parquet`.
+/// Files hold every `LogModel` column, the raw `context` as JSON and one
+/// `context.<key>` column per top-level context key.
+#[derive(Clone, Debug)]
+pub struct Archive {
+    directory: PathBuf,
+}
+
+impl Archive {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    /// Top-level context keys of `models`, sorted
+    #[cfg(test)]
+    pub fn context_keys(models: &[LogModel]) -> BTreeSet<String> {
+        models
+            .iter()
+            .filter_map(|model| model.context.as_ref())
+            .filter_map(|context| context.as_object())
+            .flat_map(|context| context.keys().cloned())
+            .collect()
+    }
+
+    pub fn schema(context_keys: &BTreeSet<String>) -> SchemaRef {
+        let mut fields = vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("timestamp", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), true),
+            Field::new("message", DataType::Utf8, false),
+            Field::new("level", DataType::Int32, false),
+            Field::new("tenant_id", DataType::Utf8, false),
+            Field::new("service", DataType::Utf8, true),
+            Field::new("host", DataType::Utf8, true),
+            Field::new("trace_id", DataType::Utf8, true),
+            Field::new("span_id", DataType::Utf8, true),
+            Field::new("pattern_id", DataType::Utf8, true),
+            Field::new("context", DataType::Utf8, true),
+        ];
+
+        for key in context_keys {
+            fields.push(Field::new(format!("{}{}", CONTEXT_PREFIX, key), DataType::Utf8, true));
+        }
+
+        Arc::new(Schema::new(fields))
+    }
+
+    pub fn record_batch(schema: SchemaRef, context_keys: &BTreeSet<String>, models: &[LogModel]) -> Result<RecordBatch, ArchiveError> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(Int64Array::from_iter_values(models.iter().map(|model| model.id))),
+            Arc::new(
+                TimestampMicrosecondArray::from_iter(
+                    models.iter().map(|model| model.timestamp.map(|timestamp| timestamp.timestamp_micros()))
+                ).with_timezone("UTC")
+            ),
+            Arc::new(StringArray::from_iter_values(models.iter().map(|model| model.message.as_str()))),
+            Arc::new(Int32Array::from_iter_values(models.iter().map(|model| model.level))),
+            Arc::new(StringArray::from_iter_values(models.iter().map(|model| model.tenant_id.as_str()))),
+            Arc::new(StringArray::from_iter(models.iter().map(|model| model.service.as_deref()))),
+            Arc::new(StringArray::from_iter(models.iter().map(|model| model.host.as_deref()))),
+            Arc::new(StringArray::from_iter(models.iter().map(|model| model.trace_id.as_deref()))),
+            Arc::new(StringArray::from_iter(models.iter().map(|model| model.span_id.as_deref()))),
+            Arc::new(StringArray::from_iter(models.iter().map(|model| model.pattern_id.as_deref()))),
+            Arc::new(
+                StringArray::from_iter(
+                    models.iter().map(|model| model.context.as_ref().map(|context| context.to_string()))
+                )
+            ),
+        ];
+
+        for key in context_keys {
+            let values = models
+                .iter()
+                .map(|model| {
+                    model.context
+                        .as_ref()
+                        .and_then(|context| context.get(key))
+                        .map(|value| match value {
+                            serde_json::Value::String(s) => s.clone(),
+                            value => value.to_string(),
+                        })
+                });
+
+            columns.push(Arc::new(StringArray::from_iter(values)));
+        }
+
+        Ok(RecordBatch::try_new(schema, columns)?)
+    }
+
+    /// Opens a writer for `<day>/<name>.parquet`; the file only appears under its final
+    /// name once the writer is finished
+    pub fn writer(&self, day: NaiveDate, name: &str, context_keys: &BTreeSet<String>) -> Result<ArchiveWriter, ArchiveError> {
+        let directory = self.day_directory(day);
+
+        fs::create_dir_all(&directory)?;
+
+        let path = directory.join(format!("{}.parquet", name));
+        let partial_path = directory.join(format!("{}.parquet.partial", name));
+        let schema = Self::schema(context_keys);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = ArrowWriter::try_new(
+            File::create(&partial_path)?,
+            schema.clone(),
+            Some(properties),
+        )?;
+
+        Ok(ArchiveWriter {
+            writer,
+            schema,
+            context_keys: context_keys.clone(),
+            path,
+            partial_path,
+        })
+    }
+
+    /// Writes `models` to `<day>/<name>.parquet`
+    #[cfg(test)]
+    pub fn write(&self, day: NaiveDate, name: &str, models: &[LogModel]) -> Result<PathBuf, ArchiveError> {
+        let mut writer = self.writer(day, name, &Self::context_keys(models))?;
+
+        writer.write(models)?;
+        writer.finish()
+    }
+
+    /// Reads back `tenant_id`'s logs archived for `day`. A row archived more than once, e.g. by
+    /// an earlier purge interrupted before it deleted, is only returned once.
+    pub fn read(&self, day: NaiveDate, tenant_id: &str) -> Result<Vec<LogModel>, ArchiveError> {
+        let directory = self.day_directory(day);
+        let mut models = vec![];
+
+        if !directory.is_dir() {
+            return Ok(models);
+        }
+
+        let mut paths = fs::read_dir(&directory)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, std::io::Error>>()?;
+
+        paths.sort();
+
+        for path in paths.iter().filter(|path| path.extension().map(|ext| ext == "parquet").unwrap_or(false)) {
+            models.extend(Self::read_file(path, tenant_id)?);
+        }
+
+        let mut seen = HashSet::new();
+
+        models.retain(|model| seen.insert((model.id, model.timestamp)));
+
+        Ok(models)
+    }
+
+    /// Reads the rows of `tenant_id` from one file. Rows are filtered on `tenant_id` as each row
+    /// group is decoded, so other tenants' rows are never read in full.
+    fn read_file(path: &Path, tenant_id: &str) -> Result<Vec<LogModel>, ArchiveError> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+        let tenant_column = builder.parquet_schema()
+            .columns()
+            .iter()
+            .position(|column| column.name() == "tenant_id")
+            .ok_or_else(|| ArchiveError::Schema("missing or mistyped column tenant_id".to_string()))?;
+        let projection = ProjectionMask::leaves(builder.parquet_schema(), [tenant_column]);
+        let tenant_id = tenant_id.to_string();
+        let predicate = ArrowPredicateFn::new(projection, move |batch| {
+            let tenants = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .ok_or_else(|| ArrowError::SchemaError("mistyped column tenant_id".to_string()))?;
+
+            Ok(tenants
+                .iter()
+                .map(|tenant| Some(tenant == Some(tenant_id.as_str())))
+                .collect::<BooleanArray>())
+        });
+        let reader = builder
+            .with_row_filter(RowFilter::new(vec![Box::new(predicate)]))
+            .build()?;
+        let mut models = vec![];
+
+        for batch in reader {
+            let batch = batch?;
+            let ids = Self::column::<Int64Array>(&batch, "id")?;
+            let timestamps = Self::column::<TimestampMicrosecondArray>(&batch, "timestamp")?;
+            let messages = Self::column::<StringArray>(&batch, "message")?;
+            let levels = Self::column::<Int32Array>(&batch, "level")?;
+            let tenants = Self::column::<StringArray>(&batch, "tenant_id")?;
+            let contexts = Self::column::<StringArray>(&batch, "context")?;
+            // absent from archives written before these columns existed
+            let services = Self::optional_column::<StringArray>(&batch, "service")?;
+            let hosts = Self::optional_column::<StringArray>(&batch, "host")?;
+            let trace_ids = Self::optional_column::<StringArray>(&batch, "trace_id")?;
+            let span_ids = Self::optional_column::<StringArray>(&batch, "span_id")?;
+            let pattern_ids = Self::optional_column::<StringArray>(&batch, "pattern_id")?;
+
+            for row in 0..batch.num_rows() {
+                let timestamp = if timestamps.is_null(row) {
+                    None
+                } else {
+                    Utc.timestamp_micros(timestamps.value(row))
+                        .single()
+                        .map(|timestamp| timestamp.into())
+                };
+                let context = if contexts.is_null(row) {
+                    None
+                } else {
+                    Some(serde_json::from_str(contexts.value(row))?)
+                };
+
+                models.push(LogModel {
+                    id: ids.value(row),
+                    timestamp,
+                    message: messages.value(row).to_string(),
+                    level: levels.value(row),
+                    context,
+                    tenant_id: tenants.value(row).to_string(),
+                    service: Self::optional_value(services, row),
+                    host: Self::optional_value(hosts, row),
+                    trace_id: Self::optional_value(trace_ids, row),
+                    span_id: Self::optional_value(span_ids, row),
+                    pattern_id: Self::optional_value(pattern_ids, row),
+                });
+            }
+        }
+
+        Ok(models)
+    }
+
+    fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T, ArchiveError> {
+        batch
+            .column_by_name(name)
+            .and_then(|column| column.as_any().downcast_ref::<T>())
+            .ok_or_else(|| ArchiveError::Schema(format!("missing or mistyped column {}", name)))
+    }
+
+    fn optional_column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> Result<Option<&'a T>, ArchiveError> {
+        match batch.column_by_name(name) {
+            Some(_) => Self::column(batch, name).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn optional_value(column: Option<&StringArray>, row: usize) -> Option<String> {
+        column
+            .filter(|column| !column.is_null(row))
+            .map(|column| column.value(row).to_string())
+    }
+
+    fn day_directory(&self, day: NaiveDate) -> PathBuf {
+        self.directory.join(day.format("%Y-%m-%d").to_string())
+    }
+}
+
+
+pub struct ArchiveWriter {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    context_keys: BTreeSet<String>,
+    path: PathBuf,
+    partial_path: PathBuf,
+}
+
+impl ArchiveWriter {
+    /// Appends `models` as a row group; context keys outside the writer's schema
+    /// are only kept in the raw `context` column
+    pub fn write(&mut self, models: &[LogModel]) -> Result<(), ArchiveError> {
+        if models.is_empty() {
+            return Ok(());
+        }
+
+        let batch = Archive::record_batch(self.schema.clone(), &self.context_keys, models)?;
+
+        self.writer.write(&batch)?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<PathBuf, ArchiveError> {
+        self.writer.close()?;
+        fs::rename(&self.partial_path, &self.path)?;
+
+        Ok(self.path)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::{
+        NaiveDate,
+        TimeZone,
+        Utc,
+    };
+    use serde_json::json;
+
+    use crate::models::LogModel;
+    use super::Archive;
+
+    fn model(id: i64, context: serde_json::Value) -> LogModel {
+        LogModel {
+            id,
+            timestamp: Some(Utc.with_ymd_and_hms(2022, 12, 25, 13, 45, 0).unwrap().into()),
+            message: format!("message {}", id),
+            level: 3,
+            context: Some(context),
+            tenant_id: "default".to_string(),
+            service: Some("api".to_string()),
+            host: None,
+            trace_id: None,
+            span_id: None,
+            pattern_id: None,
+        }
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let directory = std::env::temp_dir().join(format!("log-ingest-archive-{}", std::process::id()));
+        let archive = Archive::new(&directory);
+        let day = NaiveDate::from_ymd_opt(2022, 12, 25).unwrap();
+        let models = vec![
+            model(1, json!({"host": "a", "latency_ms": 5})),
+            model(2, json!({"host": "b", "nested": {"foo": "bar"}})),
+        ];
+
+        let path = archive.write(day, "logs_p20221225", &models)
+            .unwrap();
+
+        assert!(path.ends_with("2022-12-25/logs_p20221225.parquet"));
+        assert_eq!(archive.read(day, "default").unwrap(), models);
+        assert!(archive.read(day.succ_opt().unwrap(), "default").unwrap().is_empty());
+
+        // rows archived again by a later run are read back once
+        archive.write(day, "expired-1", &models[1..])
+            .unwrap();
+
+        let mut read = archive.read(day, "default")
+            .unwrap();
+
+        read.sort_by_key(|model| model.id);
+
+        assert_eq!(read, models);
+
+        // only the requested tenant's rows are read
+        let mut other = model(3, json!({}));
+
+        other.tenant_id = "other".to_string();
+        archive.write(day, "expired-2", &[models[0].clone(), other.clone()])
+            .unwrap();
+
+        assert_eq!(archive.read(day, "other").unwrap(), vec![other]);
+        assert_eq!(archive.read(day, "default").unwrap().len(), 2);
+
+        std::fs::remove_dir_all(directory)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_flattened_context_columns() {
+        let models = vec![
+            model(1, json!({"host": "a", "latency_ms": 5})),
+            model(2, json!({"host": "b"})),
+        ];
+        let keys = Archive::context_keys(&models);
+        let batch = Archive::record_batch(Archive::schema(&keys), &keys, &models)
+            .unwrap();
+
+        let names: Vec<String> = batch.schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().clone())
+            .collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "id", "timestamp", "message", "level", "tenant_id", "service", "host", "trace_id", "span_id", "pattern_id",
+                "context", "context.host", "context.latency_ms",
+            ],
+        );
+        assert_eq!(batch.column_by_name("context.latency_ms").unwrap().null_count(), 1);
+    }
+}
@@ -1,13 +1,19 @@
 use sea_orm::{
-    ConnectOptions,
-    Database,
+    ConnectionTrait,
     DatabaseConnection,
     DbErr,
+    RuntimeErr,
+    SqlxPostgresConnector,
 };
 use sqlx::{
+    ConnectOptions,
     Error,
     migrate::Migrator,
-    postgres::PgPoolOptions,
+    postgres::{
+        PgConnectOptions,
+        PgPool,
+        PgPoolOptions,
+    },
 };
 
 use crate::config::Config;
@@ -15,16 +21,51 @@ use crate::config::Config;
 
 pub static MIGRATE: Migrator = sqlx::migrate!();
 
+pub const MAX_CONNECTIONS: u32 = 100;
+const MIN_CONNECTIONS: u32 = 5;
 
-pub async fn get_db_connection(config: &Config) -> Result<DatabaseConnection, DbErr> {
-    let mut opt = ConnectOptions::new(config.database_url.to_owned());
+/// SQLSTATE of a transaction which couldn't be serialized with a concurrent one
+pub const SERIALIZATION_FAILURE: &str = "40001";
 
-    opt.max_connections(100)
-        .min_connections(5)
-        .sqlx_logging(true)
-        .sqlx_logging_level(log::LevelFilter::Info);
-    
-    Database::connect(opt).await
+/// SQLSTATE of a write rejected by a unique index
+pub const UNIQUE_VIOLATION: &str = "23505";
+
+
+/// The pool behind the application's [`DatabaseConnection`]. sea-orm doesn't expose the pool
+/// it creates, so it's created here and kept for pool metrics.
+pub async fn get_db_pool(config: &Config) -> Result<PgPool, Error> {
+    let mut options: PgConnectOptions = config.database_url.parse()?;
+
+    options.log_statements(log::LevelFilter::Info);
+
+    PgPoolOptions::new()
+        .max_connections(MAX_CONNECTIONS)
+        .min_connections(MIN_CONNECTIONS)
+        .connect_with(options)
+        .await
+}
+
+pub fn into_db_connection(pool: PgPool) -> DatabaseConnection {
+    SqlxPostgresConnector::from_sqlx_postgres_pool(pool)
+}
+
+/// Connects without keeping hold of the pool
+#[cfg(test)]
+pub async fn get_db_connection(config: &Config) -> Result<DatabaseConnection, sea_orm::DbErr> {
+    get_db_pool(config)
+        .await
+        .map(into_db_connection)
+        .map_err(|e| sea_orm::DbErr::Conn(sea_orm::RuntimeErr::SqlxError(e)))
+}
+
+/// Whether `err` was raised by the database with the SQLSTATE `code`
+pub fn is_error_code(err: &DbErr, code: &str) -> bool {
+    match err {
+        DbErr::Conn(RuntimeErr::SqlxError(Error::Database(e)))
+        | DbErr::Exec(RuntimeErr::SqlxError(Error::Database(e)))
+        | DbErr::Query(RuntimeErr::SqlxError(Error::Database(e))) => e.code().as_deref() == Some(code),
+        _ => false,
+    }
 }
 
 pub async fn migrate(config: &Config) -> Result<(), Error> {
@@ -36,3 +77,20 @@ pub async fn migrate(config: &Config) -> Result<(), Error> {
         .await
         .map_err(|e| Error::Migrate(Box::new(e)))
 }
+
+/// Version of the latest migration applied to the database, as recorded by sqlx
+pub async fn migration_version(db: &DatabaseConnection) -> Result<i64, DbErr> {
+    let statement = sea_orm::Statement::from_string(
+        db.get_database_backend(),
+        "SELECT max(version) AS version FROM _sqlx_migrations WHERE success".to_owned(),
+    );
+
+    Ok(
+        db.query_one(statement)
+            .await?
+            .map(|row| row.try_get::<Option<i64>>("", "version"))
+            .transpose()?
+            .flatten()
+            .unwrap_or_default()
+    )
+}
@@ -14,10 +14,19 @@ use serde_json::json;
 #[derive(Debug, Clone, Serialize)]
 pub enum HttpError {
     BadRequest(String),
+    Conflict(String),
+    Forbidden(String),
     InternalServerError(String),
+    NotFound(String),
+    Unauthorized(String),
 }
 
 
+/// Response extension naming the [`HttpError`] variant a response was made from
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorKind(pub &'static str);
+
+
 pub trait Loggable<T, E> {
     fn log_error<C>(self, context: C) -> Result<T, E>
     where
@@ -47,6 +56,18 @@ where
 
 
 impl HttpError {
+    /// Name of the variant, as recorded in metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HttpError::BadRequest(_) => "BadRequest",
+            HttpError::Conflict(_) => "Conflict",
+            HttpError::Forbidden(_) => "Forbidden",
+            HttpError::InternalServerError(_) => "InternalServerError",
+            HttpError::NotFound(_) => "NotFound",
+            HttpError::Unauthorized(_) => "Unauthorized",
+        }
+    }
+
     pub fn internal_server_error(message: Option<String>) -> Self {
         let message: String = message
             .unwrap_or("Internal Server Error".to_string());
@@ -60,17 +81,54 @@ impl HttpError {
 
         Self::BadRequest(message)
     }
+
+    pub fn conflict(message: Option<String>) -> Self {
+        let message: String = message
+            .unwrap_or("Conflict".to_string());
+
+        Self::Conflict(message)
+    }
+
+    pub fn forbidden(message: Option<String>) -> Self {
+        let message: String = message
+            .unwrap_or("Forbidden".to_string());
+
+        Self::Forbidden(message)
+    }
+
+    pub fn not_found(message: Option<String>) -> Self {
+        let message: String = message
+            .unwrap_or("Not Found".to_string());
+
+        Self::NotFound(message)
+    }
+
+    pub fn unauthorized(message: Option<String>) -> Self {
+        let message: String = message
+            .unwrap_or("Unauthorized".to_string());
+
+        Self::Unauthorized(message)
+    }
 }
 
 
 impl IntoResponse for HttpError {
     fn into_response(self) -> Response {
+        // lets middleware tell which error a response came from
+        let kind = ErrorKind(self.kind());
         let (status_code, message) = match self {
             HttpError::InternalServerError(s) => (StatusCode::INTERNAL_SERVER_ERROR, s),
             HttpError::BadRequest(s) => (StatusCode::BAD_REQUEST, s),
+            HttpError::Conflict(s) => (StatusCode::CONFLICT, s),
+            HttpError::Forbidden(s) => (StatusCode::FORBIDDEN, s),
+            HttpError::NotFound(s) => (StatusCode::NOT_FOUND, s),
+            HttpError::Unauthorized(s) => (StatusCode::UNAUTHORIZED, s),
         };
 
-        (status_code, Json(json!({"message": message}))).into_response()
+        let mut response = (status_code, Json(json!({"message": message}))).into_response();
+
+        response.extensions_mut().insert(kind);
+        response
     }
 }
 
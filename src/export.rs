@@ -0,0 +1,416 @@
+use std::{
+    error::Error,
+    fmt::{
+        Display,
+        Formatter,
+    },
+    str::FromStr,
+    sync::Arc,
+};
+
+use arrow_array::{
+    ArrayRef,
+    Int32Array,
+    Int64Array,
+    RecordBatch,
+    StringArray,
+    TimestampMicrosecondArray,
+};
+use arrow_schema::{
+    ArrowError,
+    DataType,
+    Field,
+    Schema,
+    SchemaRef,
+    TimeUnit,
+};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::Compression,
+    errors::ParquetError,
+    file::properties::WriterProperties,
+};
+use serde_json::Value;
+
+use crate::{
+    models::LogModel,
+    parameters::FieldSelector,
+};
+
+
+/// Rows buffered into each Parquet row group before it's written out
+const ROW_GROUP_SIZE: usize = 10_000;
+
+
+#[derive(Debug)]
+pub enum ExportError {
+    Arrow(ArrowError),
+    Parquet(ParquetError),
+}
+
+impl Error for ExportError {}
+
+impl Display for ExportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportError::Arrow(e) => write!(f, "Arrow error: {}", e),
+            ExportError::Parquet(e) => write!(f, "Parquet error: {}", e),
+        }
+    }
+}
+
+impl From<ArrowError> for ExportError {
+    fn from(e: ArrowError) -> Self {
+        Self::Arrow(e)
+    }
+}
+
+impl From<ParquetError> for ExportError {
+    fn from(e: ParquetError) -> Self {
+        Self::Parquet(e)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+
+/// Turns batches of query rows into bytes of a streamed response body
+pub trait Encoder: Send + 'static {
+    fn content_type(&self) -> &'static str;
+
+    /// Name offered to clients saving the response, if it's a file download
+    fn filename(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn encode(&mut self, rows: Vec<Value>) -> Result<Vec<u8>, ExportError>;
+
+    /// Anything still buffered once every row has been encoded
+    fn finish(&mut self) -> Result<Vec<u8>, ExportError> {
+        Ok(vec![])
+    }
+
+    /// A marker ending the body when rows were left out at the row limit, written before
+    /// `finish`. Formats without one leave the body to be aborted, so a cut short file can't
+    /// be mistaken for a complete one.
+    fn truncated(&mut self, _max_rows: u64) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+
+/// Creates the encoder for `format`, writing one column per field. Without fields every
+/// column is exported, with `context` as JSON.
+pub fn encoder(format: ExportFormat, fields: &[FieldSelector]) -> Result<Box<dyn Encoder>, ExportError> {
+    let columns = if fields.is_empty() {
+        LogModel::columns()
+            .into_iter()
+            .map(|column| FieldSelector::Column(column.to_string()))
+            .collect()
+    } else {
+        fields.to_vec()
+    };
+
+    Ok(match format {
+        ExportFormat::Csv => Box::new(Csv::new(columns)),
+        ExportFormat::Parquet => Box::new(Parquet::new(columns)?),
+    })
+}
+
+
+/// Text of a value for formats without JSON types: strings unquoted, null empty and anything
+/// else as JSON
+fn text(value: Option<&Value>) -> Option<String> {
+    match value {
+        None | Some(Value::Null) => None,
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(value) => Some(value.to_string()),
+    }
+}
+
+
+/// RFC 4180 CSV with a header row of field names
+pub struct Csv {
+    columns: Vec<FieldSelector>,
+    header: bool,
+}
+
+impl Csv {
+    pub fn new(columns: Vec<FieldSelector>) -> Self {
+        Self {
+            columns,
+            header: false,
+        }
+    }
+
+    /// Writes a row of `fields`. A field which a spreadsheet would read as a formula is
+    /// prefixed with `'`, so opening an export can't run one.
+    fn write_record<I: Iterator<Item = String>>(buffer: &mut String, fields: I) {
+        for (index, mut field) in fields.enumerate() {
+            if index > 0 {
+                buffer.push(',');
+            }
+
+            if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+                field.insert(0, '\'');
+            }
+
+            if field.contains([',', '"', '\n', '\r']) {
+                buffer.push('"');
+                buffer.push_str(&field.replace('"', "\"\""));
+                buffer.push('"');
+            } else {
+                buffer.push_str(&field);
+            }
+        }
+
+        buffer.push_str("\r\n");
+    }
+}
+
+impl Encoder for Csv {
+    fn content_type(&self) -> &'static str {
+        "text/csv"
+    }
+
+    fn filename(&self) -> Option<&'static str> {
+        Some("logs.csv")
+    }
+
+    fn encode(&mut self, rows: Vec<Value>) -> Result<Vec<u8>, ExportError> {
+        let mut buffer = String::new();
+
+        if !self.header {
+            Self::write_record(&mut buffer, self.columns.iter().map(|column| column.name()));
+            self.header = true;
+        }
+
+        for row in rows {
+            let fields = self.columns
+                .iter()
+                .map(|column| text(row.get(column.name())).unwrap_or_default());
+
+            Self::write_record(&mut buffer, fields);
+        }
+
+        Ok(buffer.into_bytes())
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, ExportError> {
+        // an empty result still has a header
+        if self.header {
+            Ok(vec![])
+        } else {
+            self.encode(vec![])
+        }
+    }
+}
+
+
+/// Parquet, written out a row group at a time. `id`, `level` and `timestamp` keep their
+/// types; every other column, and every context path, is text.
+pub struct Parquet {
+    columns: Vec<FieldSelector>,
+    schema: SchemaRef,
+    writer: ArrowWriter<Vec<u8>>,
+}
+
+impl Parquet {
+    pub fn new(columns: Vec<FieldSelector>) -> Result<Self, ExportError> {
+        let fields: Vec<Field> = columns
+            .iter()
+            .map(|column| {
+                let data_type = match column {
+                    FieldSelector::Column(name) if name == "id" => DataType::Int64,
+                    FieldSelector::Column(name) if name == "level" => DataType::Int32,
+                    FieldSelector::Column(name) if name == "timestamp" => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+                    _ => DataType::Utf8,
+                };
+
+                Field::new(column.name(), data_type, true)
+            })
+            .collect();
+        let schema = Arc::new(Schema::new(fields));
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(ROW_GROUP_SIZE)
+            .build();
+        let writer = ArrowWriter::try_new(Vec::new(), schema.clone(), Some(properties))?;
+
+        Ok(Self {
+            columns,
+            schema,
+            writer,
+        })
+    }
+
+    fn record_batch(&self, rows: &[Value]) -> Result<RecordBatch, ExportError> {
+        let columns: Vec<ArrayRef> = self.columns
+            .iter()
+            .zip(self.schema.fields())
+            .map(|(column, field)| {
+                let name = column.name();
+                let values = rows.iter().map(move |row| row.get(&name));
+
+                let array: ArrayRef = match field.data_type() {
+                    DataType::Int64 => Arc::new(Int64Array::from_iter(values.map(|value| value.and_then(Value::as_i64)))),
+                    DataType::Int32 => Arc::new(Int32Array::from_iter(
+                        values.map(|value| value.and_then(Value::as_i64).and_then(|value| i32::try_from(value).ok()))
+                    )),
+                    DataType::Timestamp(_, _) => Arc::new(
+                        TimestampMicrosecondArray::from_iter(
+                            values.map(|value| {
+                                value
+                                    .and_then(Value::as_str)
+                                    .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
+                                    .map(|timestamp| timestamp.timestamp_micros())
+                            })
+                        ).with_timezone("UTC")
+                    ),
+                    _ => Arc::new(StringArray::from_iter(values.map(text))),
+                };
+
+                array
+            })
+            .collect();
+
+        Ok(RecordBatch::try_new(self.schema.clone(), columns)?)
+    }
+
+    /// Takes whatever the writer has produced so far; the writer only appends, so the
+    /// remainder of the file follows on
+    fn take_written(&mut self) -> Vec<u8> {
+        std::mem::take(self.writer.inner_mut())
+    }
+}
+
+impl Encoder for Parquet {
+    fn content_type(&self) -> &'static str {
+        "application/vnd.apache.parquet"
+    }
+
+    fn filename(&self) -> Option<&'static str> {
+        Some("logs.parquet")
+    }
+
+    fn encode(&mut self, rows: Vec<Value>) -> Result<Vec<u8>, ExportError> {
+        let batch = self.record_batch(&rows)?;
+
+        self.writer.write(&batch)?;
+
+        Ok(self.take_written())
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, ExportError> {
+        self.writer.finish()?;
+
+        Ok(self.take_written())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{
+        Array,
+        Int32Array,
+        StringArray,
+        TimestampMicrosecondArray,
+    };
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use serde_json::json;
+
+    use crate::parameters::FieldSelector;
+    use super::{
+        encoder,
+        ExportFormat,
+    };
+
+    fn fields(fields: &[&str]) -> Vec<FieldSelector> {
+        fields
+            .iter()
+            .map(|field| field.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_csv_export() {
+        let mut csv = encoder(ExportFormat::Csv, &fields(&["level", "message", "context.http.status"]))
+            .unwrap();
+        let mut output = csv.encode(vec![
+            json!({"level": 1, "message": "plain", "context.http.status": 200}),
+            json!({"level": 2, "message": "has \"quotes\", commas\nand lines", "context.http.status": null}),
+            json!({"level": 3, "message": "=HYPERLINK(\"http://example.com\")", "context.http.status": "@SUM(A1)"}),
+            json!({"level": 4, "message": "+1", "context.http.status": "-1"}),
+            json!({"level": 5, "message": "\tcell", "context.http.status": "\rcell"}),
+        ]).unwrap();
+
+        output.extend(csv.finish().unwrap());
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "level,message,context.http.status\r\n1,plain,200\r\n2,\"has \"\"quotes\"\", commas\nand lines\",\r\n\
+                3,\"'=HYPERLINK(\"\"http://example.com\"\")\",'@SUM(A1)\r\n4,'+1,'-1\r\n5,'\tcell,\"'\rcell\"\r\n",
+        );
+
+        let mut empty = encoder(ExportFormat::Csv, &[]).unwrap();
+
+        assert_eq!(
+            String::from_utf8(empty.finish().unwrap()).unwrap(),
+            "id,timestamp,message,level,context,tenant_id,service,host,trace_id,span_id,pattern_id\r\n",
+        );
+    }
+
+    #[test]
+    fn test_parquet_export() {
+        let mut parquet = encoder(ExportFormat::Parquet, &fields(&["timestamp", "level", "context.http"]))
+            .unwrap();
+        let mut output = vec![];
+
+        for batch in [
+            vec![json!({"timestamp": "2022-12-25T13:45:00+00:00", "level": 1, "context.http": {"status": 200}})],
+            vec![json!({"timestamp": null, "level": 2, "context.http": "GET"})],
+        ] {
+            output.extend(parquet.encode(batch).unwrap());
+        }
+
+        output.extend(parquet.finish().unwrap());
+
+        let batches: Vec<_> = ParquetRecordBatchReaderBuilder::try_new(axum::body::Bytes::from(output))
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let batch = &batches[0];
+
+        assert_eq!(batch.num_rows(), 2);
+
+        let timestamps = batch.column(0).as_any().downcast_ref::<TimestampMicrosecondArray>().unwrap();
+        let levels = batch.column(1).as_any().downcast_ref::<Int32Array>().unwrap();
+        let http = batch.column(2).as_any().downcast_ref::<StringArray>().unwrap();
+
+        assert_eq!(timestamps.value(0), 1671975900000000);
+        assert!(timestamps.is_null(1));
+        assert_eq!(levels.values().to_vec(), vec![1, 2]);
+        assert_eq!(http.value(0), "{\"status\":200}");
+        assert_eq!(http.value(1), "GET");
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+use sea_orm::Value;
+
+use crate::{
+    models::IngestLog,
+    parameters::{
+        FieldSelector,
+        FilterParameter,
+        Operator,
+    },
+    time_expression,
+};
+
+use super::IngestError;
+
+
+/// A value a filter compares against, mirroring the `jsonb_typeof` checks `QueryBuilder`
+/// makes: a filter only matches a field holding the same kind of value.
+#[derive(Debug, PartialEq, PartialOrd)]
+enum Comparable {
+    Bool(bool),
+    Number(f64),
+    Text(String),
+    Time(DateTime<Utc>),
+}
+
+impl Comparable {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(Some(b)) => Some(Self::Bool(*b)),
+            Value::TinyInt(Some(n)) => Some(Self::Number(*n as f64)),
+            Value::SmallInt(Some(n)) => Some(Self::Number(*n as f64)),
+            Value::Int(Some(n)) => Some(Self::Number(*n as f64)),
+            Value::BigInt(Some(n)) => Some(Self::Number(*n as f64)),
+            Value::TinyUnsigned(Some(n)) => Some(Self::Number(*n as f64)),
+            Value::SmallUnsigned(Some(n)) => Some(Self::Number(*n as f64)),
+            Value::Unsigned(Some(n)) => Some(Self::Number(*n as f64)),
+            Value::BigUnsigned(Some(n)) => Some(Self::Number(*n as f64)),
+            Value::Float(Some(n)) => Some(Self::Number(*n as f64)),
+            Value::Double(Some(n)) => Some(Self::Number(*n)),
+            Value::String(Some(s)) => Some(Self::Text(s.to_string())),
+            Value::ChronoDateTimeUtc(Some(t)) => Some(Self::Time(**t)),
+            _ => None,
+        }
+    }
+
+    /// Reads `field` from `log` as the same kind of value as `like`
+    fn from_log(log: &IngestLog, field: &FieldSelector, like: &Comparable) -> Option<Self> {
+        let value = match field {
+            FieldSelector::Column(column) if column == "timestamp" => {
+                return log.timestamp
+                    .map(|timestamp| Self::Time(timestamp.with_timezone(&Utc)));
+            },
+            field => selector_value(log, field)?,
+        };
+
+        match (like, value) {
+            (Self::Bool(_), serde_json::Value::Bool(b)) => Some(Self::Bool(b)),
+            (Self::Number(_), serde_json::Value::Number(n)) => n.as_f64().map(Self::Number),
+            (Self::Text(_), serde_json::Value::String(s)) => Some(Self::Text(s)),
+            (Self::Time(_), serde_json::Value::String(s)) => DateTime::parse_from_rfc3339(&s)
+                .ok()
+                .map(|timestamp| Self::Time(timestamp.with_timezone(&Utc))),
+            _ => None,
+        }
+    }
+}
+
+
+/// The value of a column or top-level context key of `log`, as JSON
+pub fn field_value(log: &IngestLog, field: &str) -> Option<serde_json::Value> {
+    let value = match field {
+        "message" => serde_json::Value::String(log.message.clone()),
+        "level" => serde_json::Value::from(log.level),
+        "timestamp" => serde_json::to_value(log.timestamp?).ok()?,
+        "service" if log.service.is_some() => serde_json::Value::from(log.service.clone()),
+        "host" if log.host.is_some() => serde_json::Value::from(log.host.clone()),
+        "trace_id" if log.trace_id.is_some() => serde_json::Value::from(log.trace_id.clone()),
+        "span_id" if log.span_id.is_some() => serde_json::Value::from(log.span_id.clone()),
+        // promoted fields fall back to the context, as they do at ingest
+        field => log.context
+            .as_ref()
+            .and_then(|context| context.get(field))
+            .cloned()?,
+    };
+
+    Some(value)
+}
+
+
+/// The value `field` selects from `log`, following nested context keys as `QueryBuilder` does
+pub fn selector_value(log: &IngestLog, field: &FieldSelector) -> Option<serde_json::Value> {
+    match field {
+        FieldSelector::Column(column) => field_value(log, column),
+        FieldSelector::Context(path) => path
+            .iter()
+            .try_fold(log.context.as_ref()?, |value, segment| value.get(segment))
+            .cloned(),
+    }
+}
+
+
+/// A filter of a [`Condition`], with the expression of a relative `timestamp` filter
+#[derive(Clone, Debug)]
+struct ConditionFilter {
+    filter: FilterParameter,
+    /// Resolved again every time logs are matched, so `now-5m` follows the current time
+    relative: Option<String>,
+}
+
+
+/// A set of filters, all of which must match, evaluated against logs in memory
+#[derive(Clone, Debug, Default)]
+pub struct Condition {
+    filters: Vec<ConditionFilter>,
+}
+
+impl Condition {
+    pub fn parse(filters: &HashMap<String, String>) -> Result<Self, IngestError> {
+        let mut parsed = vec![];
+
+        for (key, value) in filters {
+            let filter = FilterParameter::from_hashmap(HashMap::from([(key.clone(), value.clone())]))
+                .map_err(|e| IngestError::Config(e.to_string()))?
+                .pop()
+                .ok_or_else(|| IngestError::Config(format!("Invalid filter: {}", key)))?;
+            let relative = matches!(filter.value, Value::ChronoDateTimeUtc(_)) && value.trim().starts_with("now");
+
+            parsed.push(ConditionFilter {
+                filter,
+                relative: relative.then(|| value.clone()),
+            });
+        }
+
+        // deterministic evaluation order
+        parsed.sort_by_key(|filter| filter.filter.field.name());
+
+        Ok(Self {
+            filters: parsed,
+        })
+    }
+
+    pub fn matches(&self, log: &IngestLog) -> bool {
+        let now = Utc::now();
+
+        self.filters
+            .iter()
+            .all(|filter| Self::filter_matches(filter, log, now))
+    }
+
+    fn filter_matches(filter: &ConditionFilter, log: &IngestLog, now: DateTime<Utc>) -> bool {
+        let expected = match &filter.relative {
+            Some(expression) => time_expression::resolve(expression, now).map(Comparable::Time),
+            None => Comparable::from_value(&filter.filter.value),
+        };
+        let expected = match expected {
+            Some(expected) => expected,
+            None => return false,
+        };
+        let actual = match Comparable::from_log(log, &filter.filter.field, &expected) {
+            Some(actual) => actual,
+            None => return false,
+        };
+
+        match filter.filter.op {
+            Operator::Eq => actual == expected,
+            Operator::Gt => actual > expected,
+            Operator::Gte => actual >= expected,
+            Operator::Lt => actual < expected,
+            Operator::Lte => actual <= expected,
+            Operator::Contains => match (actual, expected) {
+                (Comparable::Text(actual), Comparable::Text(expected)) => actual.contains(&expected),
+                _ => false,
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::models::IngestLog;
+    use super::Condition;
+
+    fn condition(filters: &[(&str, &str)]) -> Condition {
+        let filters: HashMap<String, String> = filters
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        Condition::parse(&filters)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_condition_matches() {
+        let log = IngestLog {
+            timestamp: Some(chrono::DateTime::parse_from_rfc3339("2022-12-25T13:45:00Z").unwrap()),
+            message: "GET /health 200".to_string(),
+            level: 2,
+            context: Some(json!({"service": "api", "latency_ms": 12.5, "cached": true})),
+            ..Default::default()
+        };
+
+        assert!(condition(&[("filter[level][lt]", "3"), ("filter[service][eq]", "api")]).matches(&log));
+        assert!(condition(&[("filter[message][contains]", "/health")]).matches(&log));
+        assert!(condition(&[("filter[latency_ms][gte]", "12")]).matches(&log));
+        assert!(condition(&[("filter[cached][eq]", "true")]).matches(&log));
+        assert!(condition(&[("filter[timestamp][gt]", "2022-01-01T00:00:00Z")]).matches(&log));
+        assert!(!condition(&[("filter[level][gte]", "3")]).matches(&log));
+        assert!(!condition(&[("filter[missing][eq]", "api")]).matches(&log));
+        assert!(!condition(&[("filter[service.name][eq]", "api")]).matches(&log));
+        // a number filter doesn't match a boolean field, as in the database
+        assert!(!condition(&[("filter[cached][eq]", "1")]).matches(&log));
+    }
+
+    #[test]
+    fn test_condition_relative_time() {
+        let before_now = condition(&[("filter[timestamp][lt]", "now")]);
+        let recent = condition(&[("filter[timestamp][gte]", "now-5m")]);
+        let log = |age: chrono::Duration| IngestLog {
+            timestamp: Some((chrono::Utc::now() - age).into()),
+            message: "tick".to_string(),
+            ..Default::default()
+        };
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+
+        // logged after the condition was parsed, so only matches if `now` is resolved when matching
+        assert!(before_now.matches(&log(chrono::Duration::milliseconds(5))));
+        assert!(recent.matches(&log(chrono::Duration::zero())));
+        assert!(!recent.matches(&log(chrono::Duration::minutes(10))));
+    }
+
+    #[test]
+    fn test_condition_parse_fail() {
+        let filters = HashMap::from([("filter[level][between]".to_string(), "3".to_string())]);
+
+        assert!(Condition::parse(&filters).is_err());
+    }
+}
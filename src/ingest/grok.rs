@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::models::LogModel;
+
+use super::IngestError;
+
+
+/// Nesting limit when expanding patterns, guarding against definitions which refer to themselves
+const MAX_DEPTH: usize = 16;
+
+/// Built-in pattern library; later entries may refer to earlier ones
+const PATTERNS: &[(&str, &str)] = &[
+    ("INT", r"[+-]?[0-9]+"),
+    ("NUMBER", r"[+-]?(?:[0-9]+(?:\.[0-9]*)?|\.[0-9]+)"),
+    ("WORD", r"\b\w+\b"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("LINE", r"[^\r\n]*"),
+    ("QS", r#""(?:[^"\\]|\\.)*""#),
+    ("USER", r"[a-zA-Z0-9._@-]+"),
+    ("IPV4", r"(?:[0-9]{1,3}\.){3}[0-9]{1,3}"),
+    ("IPV6", r"[0-9A-Fa-f:]*:[0-9A-Fa-f:.]+"),
+    ("IP", r"(?:%{IPV6}|%{IPV4})"),
+    ("HOSTNAME", r"\b[0-9A-Za-z][0-9A-Za-z-]{0,62}(?:\.[0-9A-Za-z][0-9A-Za-z-]{0,62})*\.?\b"),
+    ("IPORHOST", r"(?:%{IP}|%{HOSTNAME})"),
+    ("MONTH", r"\b(?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec)\b"),
+    ("HTTPDATE", r"[0-9]{2}/%{MONTH}/[0-9]{4}:[0-9]{2}:[0-9]{2}:[0-9]{2} [+-][0-9]{4}"),
+    ("TIMESTAMP_ISO8601", r"[0-9]{4}-[0-9]{2}-[0-9]{2}[T ][0-9]{2}:[0-9]{2}(?::[0-9]{2}(?:\.[0-9]+)?)?(?:Z|[+-][0-9]{2}:?[0-9]{2})?"),
+    ("LOGLEVEL", r"(?i:trace|debug|info|notice|warn(?:ing)?|error|err|crit(?:ical)?|fatal|severe|emerg(?:ency)?|alert)"),
+    ("JAVACLASS", r"(?:[a-zA-Z_$][a-zA-Z0-9_$]*\.)*[a-zA-Z_$][a-zA-Z0-9_$]*"),
+    ("JAVAMETHOD", r"(?:<init>|<clinit>|[a-zA-Z_$][a-zA-Z0-9_$]*)"),
+    ("JAVAFILE", r"(?:[a-zA-Z0-9_$]+\.java|Native Method|Unknown Source)"),
+    (
+        "NGINX_ACCESS",
+        r#"%{IPORHOST:remote_addr} - %{USER:remote_user} \[%{HTTPDATE:time_local}\] "%{WORD:method} %{NOTSPACE:path} HTTP/%{NUMBER:http_version}" %{INT:status:int} %{INT:body_bytes_sent:int} "%{DATA:referrer}" "%{DATA:user_agent}""#,
+    ),
+    (
+        "APACHE_COMMON",
+        r#"%{IPORHOST:client_ip} %{USER:ident} %{USER:auth} \[%{HTTPDATE:time_local}\] "%{WORD:method} %{NOTSPACE:path}(?: HTTP/%{NUMBER:http_version})?" %{INT:status:int} (?:%{INT:bytes:int}|-)"#,
+    ),
+    ("APACHE_COMBINED", r#"%{APACHE_COMMON} "%{DATA:referrer}" "%{DATA:user_agent}""#),
+    ("POSTGRES_LEVEL", r"(?:DEBUG[1-5]|INFO|NOTICE|WARNING|ERROR|LOG|FATAL|PANIC|STATEMENT|DETAIL|HINT|CONTEXT)"),
+    (
+        "POSTGRES",
+        r"%{TIMESTAMP_ISO8601:log_time}(?: %{WORD:timezone})? \[%{INT:pid:int}\](?: %{USER:user}@%{NOTSPACE:database})? %{POSTGRES_LEVEL:severity}:\s+%{LINE:detail}",
+    ),
+    (
+        "JAVA_STACKTRACE",
+        r"%{JAVACLASS:exception_class}(?:: %{LINE:exception_message})?\r?\n\s*at %{JAVACLASS:class}\.%{JAVAMETHOD:method}\(%{JAVAFILE:file}(?::%{INT:line:int})?\)",
+    ),
+];
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CaptureType {
+    String,
+    Int,
+    Float,
+}
+
+
+#[derive(Clone, Debug)]
+struct Capture {
+    group: String,
+    /// Context path the capture is stored under, nested on `.`
+    path: Vec<String>,
+    kind: CaptureType,
+}
+
+
+/// A compiled grok expression: a regex in which `%{NAME}` refers to a library pattern and
+/// `%{NAME:field}` or `%{NAME:field:int|float}` captures the match as a context field. Field
+/// names are context paths as in queries, so `http.status` is stored nested under `http`.
+#[derive(Clone, Debug)]
+pub struct Grok {
+    regex: Regex,
+    captures: Vec<Capture>,
+}
+
+impl Grok {
+    /// Compiles `pattern`, resolving references against `definitions` before the built-in library
+    pub fn compile(pattern: &str, definitions: &HashMap<String, String>) -> Result<Self, IngestError> {
+        let mut captures = vec![];
+        let expression = expand(pattern, definitions, 0, &mut captures)?;
+        let regex = Regex::new(&expression)
+            .map_err(|e| IngestError::Config(format!("invalid grok pattern {}: {}", pattern, e)))?;
+
+        Ok(Self {
+            regex,
+            captures,
+        })
+    }
+
+    /// Named captures of the first match in `text`, converted to their declared types.
+    /// Optional groups which did not participate in the match are omitted.
+    pub fn parse(&self, text: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+        let matched = self.regex.captures(text)?;
+        let mut fields = serde_json::Map::new();
+
+        for capture in &self.captures {
+            let raw = match matched.name(&capture.group) {
+                Some(raw) => raw.as_str(),
+                None => continue,
+            };
+            // values which don't convert are kept as strings
+            let value = match capture.kind {
+                CaptureType::String => None,
+                CaptureType::Int => raw.parse::<i64>().ok().map(serde_json::Value::from),
+                CaptureType::Float => raw.parse::<f64>()
+                    .ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(serde_json::Value::Number),
+            };
+
+            insert(&mut fields, &capture.path, value.unwrap_or_else(|| serde_json::Value::from(raw)));
+        }
+
+        Some(fields)
+    }
+}
+
+
+/// Inserts `value` at `path`, creating objects along the way and replacing anything else
+fn insert(fields: &mut serde_json::Map<String, serde_json::Value>, path: &[String], value: serde_json::Value) {
+    let (last, parents) = match path.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut fields = fields;
+
+    for segment in parents {
+        let entry = fields
+            .entry(segment.clone())
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+
+        if !entry.is_object() {
+            *entry = serde_json::Value::Object(serde_json::Map::new());
+        }
+
+        fields = entry.as_object_mut().unwrap();
+    }
+
+    fields.insert(last.clone(), value);
+}
+
+
+/// Merges `fields` into `context`, combining objects present in both
+pub fn merge(context: &mut serde_json::Map<String, serde_json::Value>, fields: serde_json::Map<String, serde_json::Value>) {
+    for (key, value) in fields {
+        match (context.get_mut(&key), value) {
+            (Some(serde_json::Value::Object(existing)), serde_json::Value::Object(value)) => merge(existing, value),
+            (_, value) => {
+                context.insert(key, value);
+            },
+        }
+    }
+}
+
+
+/// The context path a capture named `field` is stored under. Segments are limited to the
+/// characters queries accept, and captures into columns which aren't filled from the context
+/// are refused, as queries would read the column rather than the capture.
+fn capture_path(field: &str) -> Result<Vec<String>, IngestError> {
+    let path: Vec<String> = field
+        .split('.')
+        .map(|segment| segment.to_string())
+        .collect();
+    let valid = path
+        .iter()
+        .all(|segment| !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'));
+
+    if !valid {
+        return Err(IngestError::Config(format!("invalid grok capture name {}", field)));
+    }
+
+    if LogModel::columns().contains(&path[0].as_str()) && !LogModel::promoted_columns().contains(&path[0].as_str()) {
+        return Err(IngestError::Config(format!("grok capture {} collides with the {} column", field, path[0])));
+    }
+
+    Ok(path)
+}
+
+
+fn expand(pattern: &str, definitions: &HashMap<String, String>, depth: usize, captures: &mut Vec<Capture>) -> Result<String, IngestError> {
+    if depth > MAX_DEPTH {
+        return Err(IngestError::Config(format!("grok pattern nested too deeply: {}", pattern)));
+    }
+
+    let reference = Regex::new(r"%\{(?P<name>[A-Za-z0-9_]+)(?::(?P<field>[^:}]+))?(?::(?P<kind>[a-z]+))?\}")
+        .unwrap();
+    let mut expression = String::new();
+    let mut last = 0;
+
+    for matched in reference.captures_iter(pattern) {
+        let whole = matched.get(0).unwrap();
+        let name = &matched["name"];
+        let definition = definitions
+            .get(name)
+            .map(|definition| definition.as_str())
+            .or_else(|| PATTERNS.iter().find(|(pattern, _)| *pattern == name).map(|(_, definition)| *definition))
+            .ok_or_else(|| IngestError::Config(format!("unknown grok pattern {}", name)))?;
+        let inner = expand(definition, definitions, depth + 1, captures)?;
+
+        expression.push_str(&pattern[last..whole.start()]);
+
+        match matched.name("field") {
+            Some(field) => {
+                let kind = match matched.name("kind").map(|kind| kind.as_str()) {
+                    None | Some("string") => CaptureType::String,
+                    Some("int") => CaptureType::Int,
+                    Some("float") => CaptureType::Float,
+                    Some(kind) => return Err(IngestError::Config(format!("unknown grok capture type {}", kind))),
+                };
+                let group = format!("grok{}", captures.len());
+
+                expression.push_str(&format!("(?P<{}>{})", group, inner));
+                captures.push(Capture {
+                    group,
+                    path: capture_path(field.as_str())?,
+                    kind,
+                });
+            },
+            None => expression.push_str(&format!("(?:{})", inner)),
+        }
+
+        last = whole.end();
+    }
+
+    expression.push_str(&pattern[last..]);
+
+    Ok(expression)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::Grok;
+
+    fn parse(pattern: &str, text: &str) -> Option<serde_json::Value> {
+        Grok::compile(pattern, &HashMap::new())
+            .unwrap()
+            .parse(text)
+            .map(serde_json::Value::Object)
+    }
+
+    #[test]
+    fn test_builtin_patterns() {
+        let nginx = parse(
+            "%{NGINX_ACCESS}",
+            r#"10.0.0.1 - - [25/Dec/2022:13:45:00 +0000] "GET /health?verbose=1 HTTP/1.1" 200 612 "-" "curl/7.64.1""#,
+        ).unwrap();
+
+        assert_eq!(nginx["remote_addr"], "10.0.0.1");
+        assert_eq!(nginx["path"], "/health?verbose=1");
+        assert_eq!(nginx["status"], 200);
+        assert_eq!(nginx["user_agent"], "curl/7.64.1");
+
+        let apache = parse(
+            "%{APACHE_COMBINED}",
+            r#"example.com - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 404 - "http://example.com/" "Mozilla/4.08""#,
+        ).unwrap();
+
+        assert_eq!(apache["auth"], "frank");
+        assert_eq!(apache["time_local"], "10/Oct/2000:13:55:36 -0700");
+        assert_eq!(apache["status"], 404);
+        assert!(apache.get("bytes").is_none());
+
+        let postgres = parse(
+            "%{POSTGRES}",
+            "2022-12-25 13:45:00.123 UTC [4242] app@logs ERROR:  relation \"foo\" does not exist",
+        ).unwrap();
+
+        assert_eq!(postgres["log_time"], "2022-12-25 13:45:00.123");
+        assert_eq!(postgres["pid"], 4242);
+        assert_eq!(postgres["database"], "logs");
+        assert_eq!(postgres["severity"], "ERROR");
+        assert_eq!(postgres["detail"], "relation \"foo\" does not exist");
+
+        let java = parse(
+            "%{JAVA_STACKTRACE}",
+            "java.lang.IllegalStateException: boom\n\tat com.example.Service.run(Service.java:42)\n\tat java.lang.Thread.run(Thread.java:750)",
+        ).unwrap();
+
+        assert_eq!(
+            java,
+            json!({
+                "exception_class": "java.lang.IllegalStateException",
+                "exception_message": "boom",
+                "class": "com.example.Service",
+                "method": "run",
+                "file": "Service.java",
+                "line": 42,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_custom_definitions() {
+        let definitions = HashMap::from([("REQUEST_ID".to_string(), "req-[0-9a-f]+".to_string())]);
+        let grok = Grok::compile(r"%{REQUEST_ID:request_id} took %{NUMBER:duration:float}ms", &definitions)
+            .unwrap();
+
+        assert_eq!(
+            grok.parse("req-abc123 took 12.5ms").map(serde_json::Value::Object),
+            Some(json!({"request_id": "req-abc123", "duration": 12.5})),
+        );
+        assert!(grok.parse("no request here").is_none());
+    }
+
+    #[test]
+    fn test_capture_names() {
+        let grok = Grok::compile(r"%{WORD:http.method} %{INT:http.status:int} %{WORD:service}", &HashMap::new())
+            .unwrap();
+
+        assert_eq!(
+            grok.parse("GET 200 api").map(serde_json::Value::Object),
+            Some(json!({"http": {"method": "GET", "status": 200}, "service": "api"})),
+        );
+
+        for pattern in ["%{INT:timestamp}", "%{INT:level.value}", "%{INT:a..b}", "%{INT:user@host}", "%{INT:.status}"] {
+            assert!(Grok::compile(pattern, &HashMap::new()).is_err(), "{} should be rejected", pattern);
+        }
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        let recursive = HashMap::from([("LOOP".to_string(), "%{LOOP}".to_string())]);
+
+        assert!(Grok::compile("%{NOPE}", &HashMap::new()).is_err());
+        assert!(Grok::compile("%{INT:n:bool}", &HashMap::new()).is_err());
+        assert!(Grok::compile("%{LOOP}", &recursive).is_err());
+    }
+}
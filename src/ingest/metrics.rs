@@ -0,0 +1,338 @@
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    sync::Mutex,
+};
+
+use prometheus::{
+    HistogramOpts,
+    HistogramVec,
+    IntCounterVec,
+    Opts,
+    proto::MetricFamily,
+    Registry,
+};
+use serde::Deserialize;
+
+use crate::{
+    models::IngestLog,
+    parameters::FieldSelector,
+};
+
+use super::{
+    condition::{
+        Condition,
+        selector_value,
+    },
+    IngestError,
+};
+
+
+const TENANT_LABEL: &str = "tenant";
+/// Label value of every label of the series observations past a metric's series limit go to
+const OVERFLOW_LABEL_VALUE: &str = "__overflow__";
+const DEFAULT_MAX_SERIES: usize = 1000;
+
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType {
+    /// Counts matching logs
+    Counter,
+    /// Observes a numeric field of matching logs
+    Histogram,
+}
+
+
+/// A single metric as loaded from `METRICS_CONFIG`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub metric_type: MetricType,
+    pub help: Option<String>,
+    /// Logs are only counted or observed if they match every filter
+    #[serde(default)]
+    pub filter: HashMap<String, String>,
+    /// Columns or context paths (nested keys separated by dots) whose values label each series
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Labels each series with the tenant first. Off by default, as it would list every
+    /// tenant to anyone scraping `/metrics`.
+    #[serde(default)]
+    pub tenant_label: bool,
+    /// Column or context path observed by a histogram
+    pub field: Option<String>,
+    pub buckets: Option<Vec<f64>>,
+    /// Label combinations kept before further observations are recorded as overflow
+    pub max_series: Option<usize>,
+}
+
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub metrics: Vec<MetricConfig>,
+}
+
+
+enum Collector {
+    Counter(IntCounterVec),
+    Histogram(HistogramVec, FieldSelector),
+}
+
+
+struct LogMetric {
+    condition: Condition,
+    tenant_label: bool,
+    labels: Vec<FieldSelector>,
+    collector: Collector,
+    max_series: usize,
+    series: Mutex<HashSet<Vec<String>>>,
+}
+
+impl LogMetric {
+    fn from_config(config: MetricConfig, registry: &Registry) -> Result<Self, IngestError> {
+        let help = config.help
+            .unwrap_or_else(|| format!("Logs matching the {} filter", config.name));
+        let label_names: Vec<String> = config.tenant_label
+            .then(|| TENANT_LABEL.to_string())
+            .into_iter()
+            .chain(config.labels.iter().map(|label| label_name(label)))
+            .collect();
+        let label_names: Vec<&str> = label_names
+            .iter()
+            .map(|name| name.as_str())
+            .collect();
+        let error = |e: prometheus::Error| IngestError::Config(format!("{}: {}", config.name, e));
+        let resolve = |field: &str| FieldSelector::resolve(field)
+            .ok_or_else(|| IngestError::Config(format!("{}: invalid field {}", config.name, field)));
+        let labels = config.labels
+            .iter()
+            .map(|label| resolve(label))
+            .collect::<Result<Vec<FieldSelector>, IngestError>>()?;
+
+        if config.labels.iter().any(|label| label_name(label) == TENANT_LABEL) {
+            return Err(IngestError::Config(format!("{}: the {} label is reserved", config.name, TENANT_LABEL)));
+        }
+
+        let collector = match config.metric_type {
+            MetricType::Counter => {
+                let counter = IntCounterVec::new(Opts::new(&config.name, help), &label_names)
+                    .map_err(error)?;
+
+                registry.register(Box::new(counter.clone()))
+                    .map_err(error)?;
+
+                Collector::Counter(counter)
+            },
+            MetricType::Histogram => {
+                let field = config.field
+                    .as_deref()
+                    .ok_or_else(|| IngestError::Config(format!("{}: histograms require a field", config.name)))
+                    .and_then(resolve)?;
+                let mut opts = HistogramOpts::new(&config.name, help);
+
+                if let Some(buckets) = config.buckets.clone() {
+                    opts = opts.buckets(buckets);
+                }
+
+                let histogram = HistogramVec::new(opts, &label_names)
+                    .map_err(error)?;
+
+                registry.register(Box::new(histogram.clone()))
+                    .map_err(error)?;
+
+                Collector::Histogram(histogram, field)
+            },
+        };
+
+        Ok(Self {
+            condition: Condition::parse(&config.filter)?,
+            tenant_label: config.tenant_label,
+            labels,
+            collector,
+            max_series: config.max_series.unwrap_or(DEFAULT_MAX_SERIES),
+            series: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn observe(&self, tenant_id: &str, log: &IngestLog) {
+        if !self.condition.matches(log) {
+            return;
+        }
+
+        let value = match &self.collector {
+            Collector::Counter(_) => None,
+            Collector::Histogram(_, field) => match selector_value(log, field).and_then(|value| value.as_f64()) {
+                Some(value) => Some(value),
+                // only numbers can be observed
+                None => return,
+            },
+        };
+        let values = self.label_values(tenant_id, log);
+        let values: Vec<&str> = values
+            .iter()
+            .map(|value| value.as_str())
+            .collect();
+
+        match &self.collector {
+            Collector::Counter(counter) => counter.with_label_values(&values).inc(),
+            Collector::Histogram(histogram, _) => histogram.with_label_values(&values).observe(value.unwrap_or_default()),
+        }
+    }
+
+    /// Label values of `log`, or the overflow series once `max_series` other combinations
+    /// have been seen
+    fn label_values(&self, tenant_id: &str, log: &IngestLog) -> Vec<String> {
+        let values: Vec<String> = self.tenant_label
+            .then(|| tenant_id.to_string())
+            .into_iter()
+            .chain(self.labels.iter().map(|label| match selector_value(log, label) {
+                None | Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(s)) => s,
+                Some(value) => value.to_string(),
+            }))
+            .collect();
+        let mut series = self.series.lock().unwrap();
+
+        if series.contains(&values) {
+            return values;
+        }
+
+        if series.len() >= self.max_series {
+            return vec![OVERFLOW_LABEL_VALUE.to_string(); values.len()];
+        }
+
+        series.insert(values.clone());
+
+        values
+    }
+}
+
+
+/// Label names may only hold letters, digits and underscores
+fn label_name(field: &str) -> String {
+    field
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+
+/// Prometheus metrics derived from logs as they're ingested: counters of the logs matching a
+/// filter and histograms of a numeric field, labelled by any configured fields and, if enabled,
+/// the tenant.
+/// Each metric keeps at most `max_series` label combinations; logs with any other combination
+/// are recorded under a single series with every label set to `__overflow__`.
+#[derive(Default)]
+pub struct LogMetrics {
+    registry: Registry,
+    metrics: Vec<LogMetric>,
+}
+
+impl LogMetrics {
+    pub fn from_config(config: MetricsConfig) -> Result<Self, IngestError> {
+        let registry = Registry::new();
+        let metrics = config.metrics
+            .into_iter()
+            .map(|metric| LogMetric::from_config(metric, &registry))
+            .collect::<Result<Vec<LogMetric>, IngestError>>()?;
+
+        Ok(Self {
+            registry,
+            metrics,
+        })
+    }
+
+    pub fn observe(&self, tenant_id: &str, log: &IngestLog) {
+        for metric in &self.metrics {
+            metric.observe(tenant_id, log);
+        }
+    }
+
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        self.registry.gather()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{
+        Encoder,
+        TextEncoder,
+    };
+    use serde_json::json;
+
+    use crate::models::IngestLog;
+    use super::{
+        LogMetrics,
+        MetricsConfig,
+    };
+
+    fn render(metrics: &LogMetrics) -> String {
+        let mut buffer = vec![];
+
+        TextEncoder::new()
+            .encode(&metrics.gather(), &mut buffer)
+            .unwrap();
+
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_log_metrics() {
+        let config: MetricsConfig = serde_json::from_value(json!({
+            "metrics": [
+                {"name": "errors_total", "type": "counter", "filter": {"filter[level][gte]": "4"}, "labels": ["service", "http.status"], "tenant_label": true, "max_series": 2},
+                {"name": "latency_ms", "type": "histogram", "help": "Request latency", "field": "context.latency.ms", "buckets": [10, 100]},
+            ],
+        })).unwrap();
+        let metrics = LogMetrics::from_config(config).unwrap();
+        let logs: Vec<IngestLog> = serde_json::from_value(json!([
+            {"level": 5, "message": "a", "service": "api", "context": {"http": {"status": 500}, "latency": {"ms": 5}}},
+            {"level": 5, "message": "b", "service": "api", "context": {"http": {"status": 500}, "latency": {"ms": 50}}},
+            {"level": 4, "message": "c", "context": {"http": {"status": 503}, "latency": {"ms": "slow"}}},
+            {"level": 4, "message": "d", "service": "billing"},
+            {"level": 2, "message": "e", "service": "api", "context": {"latency": {"ms": 500}}},
+        ])).unwrap();
+
+        for log in &logs {
+            metrics.observe("default", log);
+        }
+
+        let text = render(&metrics);
+
+        assert!(text.contains("errors_total{http_status=\"500\",service=\"api\",tenant=\"default\"} 2"));
+        assert!(text.contains("errors_total{http_status=\"503\",service=\"\",tenant=\"default\"} 1"));
+        // a third combination is past the series limit
+        assert!(text.contains("errors_total{http_status=\"__overflow__\",service=\"__overflow__\",tenant=\"__overflow__\"} 1"));
+        assert!(!text.contains("service=\"billing\""));
+        assert!(text.contains("# HELP latency_ms Request latency"));
+        // only labelled by tenant when asked
+        assert!(text.contains("latency_ms_bucket{le=\"10\"} 1"));
+        assert!(text.contains("latency_ms_bucket{le=\"100\"} 2"));
+        // the non-numeric latency isn't observed
+        assert!(text.contains("latency_ms_count 3"));
+    }
+
+    #[test]
+    fn test_log_metrics_config_fail() {
+        for metric in [
+            json!({"name": "latency", "type": "histogram"}),
+            json!({"name": "bad name", "type": "counter"}),
+            json!({"name": "tenants", "type": "counter", "labels": ["tenant"]}),
+            json!({"name": "errors", "type": "counter", "labels": ["http..status"]}),
+            json!({"name": "latency", "type": "histogram", "field": "latency ms"}),
+            json!({"name": "errors", "type": "counter", "filter": {"filter[level][between]": "4"}}),
+        ] {
+            let config: MetricsConfig = serde_json::from_value(json!({"metrics": [metric]})).unwrap();
+
+            assert!(LogMetrics::from_config(config).is_err(), "{} should be rejected", metric);
+        }
+    }
+}
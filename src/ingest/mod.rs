@@ -0,0 +1,51 @@
+use std::{
+    error::Error,
+    fmt::{
+        Display,
+        Formatter,
+    },
+};
+
+use serde::de::DeserializeOwned;
+
+pub mod condition;
+pub mod grok;
+pub mod metrics;
+pub mod multiline;
+pub mod patterns;
+pub mod pipeline;
+pub mod redaction;
+pub mod traceparent;
+
+
+#[derive(Debug)]
+pub enum IngestError {
+    Config(String),
+    Io(std::io::Error),
+}
+
+impl Error for IngestError {}
+
+impl Display for IngestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestError::Config(e) => write!(f, "Invalid ingest configuration: {}", e),
+            IngestError::Io(e) => write!(f, "Failed to read ingest configuration: {}", e),
+        }
+    }
+}
+
+
+/// Reads a JSON configuration file, or the default configuration when no path is given
+pub fn read_config<T: DeserializeOwned + Default>(path: Option<&String>) -> Result<T, IngestError> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(T::default()),
+    };
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(IngestError::Io)?;
+
+    serde_json::from_str(&contents)
+        .map_err(|e| IngestError::Config(format!("{}: {}", path, e)))
+}
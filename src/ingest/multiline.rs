@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::models::IngestLog;
+
+use super::{
+    condition::field_value,
+    IngestError,
+};
+
+
+const DEFAULT_WINDOW_MS: i64 = 1000;
+const DEFAULT_MAX_LINES: usize = 500;
+/// Most events buffered at once, across every tenant and stream
+const MAX_PENDING_EVENTS: usize = 10_000;
+
+
+fn default_window_ms() -> i64 {
+    DEFAULT_WINDOW_MS
+}
+
+fn default_max_lines() -> usize {
+    DEFAULT_MAX_LINES
+}
+
+
+/// Multiline settings as loaded from the `multiline` section of `PIPELINE_CONFIG`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MultilineConfig {
+    /// Columns or context keys identifying a stream of lines, e.g. `["host", "stream"]`
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// Messages matching this pattern continue the preceding record of their stream
+    pub pattern: String,
+    #[serde(default = "default_window_ms")]
+    pub window_ms: i64,
+    #[serde(default = "default_max_lines")]
+    pub max_lines: usize,
+}
+
+
+/// The latest event of one stream, held until it is followed by a new event or goes quiet
+struct Pending {
+    log: IngestLog,
+    last_timestamp: Option<i64>,
+    lines: usize,
+    last_received: Instant,
+    /// Order the event started in, so flushed events keep their order
+    sequence: u64,
+}
+
+
+#[derive(Default)]
+struct Buffer {
+    pending: HashMap<(String, Vec<String>), Pending>,
+    sequence: u64,
+}
+
+
+/// Joins continuation lines (stack trace frames and the like) into the message of the
+/// preceding record with the same key, provided their timestamps are within the time window.
+///
+/// The latest event of each tenant's stream is buffered across ingest requests, so an event
+/// split over several requests is still stored as one record. An event is released once a new
+/// event starts on its stream, or once no line has been received for it for the window.
+pub struct Multiline {
+    keys: Vec<String>,
+    pattern: Regex,
+    window_ms: i64,
+    max_lines: usize,
+    buffer: Mutex<Buffer>,
+}
+
+impl Multiline {
+    pub fn from_config(config: MultilineConfig) -> Result<Self, IngestError> {
+        let pattern = Regex::new(&config.pattern)
+            .map_err(|e| IngestError::Config(format!("invalid multiline pattern: {}", e)))?;
+
+        Ok(Self {
+            keys: config.keys,
+            pattern,
+            window_ms: config.window_ms,
+            max_lines: config.max_lines,
+            buffer: Mutex::new(Buffer::default()),
+        })
+    }
+
+    /// How long an event is held after its latest line
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms.max(0) as u64)
+    }
+
+    /// Combines `tenant_id`'s `logs` with the events buffered for their streams, returning the
+    /// events which are complete and the number of lines appended to an event. The latest event
+    /// of each stream stays buffered; continuation lines without an event are kept as records
+    /// of their own.
+    pub fn combine(&self, tenant_id: &str, logs: Vec<IngestLog>, now: Instant) -> (Vec<IngestLog>, usize) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let mut complete = vec![];
+        let mut combined = 0;
+
+        for log in logs {
+            let key = (tenant_id.to_string(), self.key(&log));
+            let timestamp = log.timestamp.map(|timestamp| timestamp.timestamp_millis());
+
+            if self.pattern.is_match(&log.message) {
+                if let Some(pending) = buffer.pending.get_mut(&key) {
+                    let within_window = match (pending.last_timestamp, timestamp) {
+                        (Some(last), Some(timestamp)) => (timestamp - last).abs() <= self.window_ms,
+                        _ => true,
+                    };
+
+                    if within_window && pending.lines < self.max_lines {
+                        pending.log.message.push('\n');
+                        pending.log.message.push_str(&log.message);
+                        pending.last_timestamp = timestamp.or(pending.last_timestamp);
+                        pending.last_received = now;
+                        pending.lines += 1;
+                        combined += 1;
+
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(pending) = buffer.pending.remove(&key) {
+                complete.push(pending.log);
+            }
+
+            // past the limit new events aren't held, so the buffer can't grow without bound
+            if buffer.pending.len() >= MAX_PENDING_EVENTS {
+                complete.push(log);
+                continue;
+            }
+
+            buffer.sequence += 1;
+
+            let sequence = buffer.sequence;
+
+            buffer.pending.insert(key, Pending {
+                log,
+                last_timestamp: timestamp,
+                lines: 1,
+                last_received: now,
+                sequence,
+            });
+        }
+
+        (complete, combined)
+    }
+
+    /// Releases the events which haven't received a line for the window by `now`, or every
+    /// event when `now` is None, with the tenant each belongs to
+    pub fn flush(&self, now: Option<Instant>) -> Vec<(String, IngestLog)> {
+        let window = self.window();
+        let mut buffer = self.buffer.lock().unwrap();
+        let expired: Vec<(String, Vec<String>)> = buffer.pending
+            .iter()
+            .filter(|(_, pending)| now.map(|now| now.duration_since(pending.last_received) >= window).unwrap_or(true))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut flushed: Vec<(String, Pending)> = expired
+            .into_iter()
+            .filter_map(|key| buffer.pending.remove(&key).map(|pending| (key.0, pending)))
+            .collect();
+
+        flushed.sort_by_key(|(_, pending)| pending.sequence);
+        flushed
+            .into_iter()
+            .map(|(tenant_id, pending)| (tenant_id, pending.log))
+            .collect()
+    }
+
+    /// The number of `tenant_id`'s events currently buffered
+    pub fn buffered(&self, tenant_id: &str) -> usize {
+        self.buffer
+            .lock()
+            .unwrap()
+            .pending
+            .keys()
+            .filter(|(tenant, _)| tenant == tenant_id)
+            .count()
+    }
+
+    /// The stream `log` belongs to, as the JSON of each key's value. Keys naming a promoted
+    /// column are read from the column when set, as fields sent at the top level haven't
+    /// been promoted yet.
+    fn key(&self, log: &IngestLog) -> Vec<String> {
+        self.keys
+            .iter()
+            .map(|key| {
+                field_value(log, key)
+                    .map(|value| value.to_string())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{
+        Duration,
+        Instant,
+    };
+
+    use chrono::DateTime;
+    use serde_json::json;
+
+    use crate::models::IngestLog;
+    use super::{
+        Multiline,
+        MultilineConfig,
+    };
+
+    fn multiline(config: serde_json::Value) -> Multiline {
+        Multiline::from_config(serde_json::from_value::<MultilineConfig>(config).unwrap())
+            .unwrap()
+    }
+
+    fn log(offset_ms: i64, message: &str, host: &str) -> IngestLog {
+        let start = DateTime::parse_from_rfc3339("2022-12-25T13:45:00Z").unwrap();
+
+        IngestLog {
+            timestamp: Some(start + chrono::Duration::milliseconds(offset_ms)),
+            message: message.to_string(),
+            level: 4,
+            context: Some(json!({"host": host, "stream": "stderr"})),
+            ..Default::default()
+        }
+    }
+
+    fn messages(logs: &[IngestLog]) -> Vec<&str> {
+        logs
+            .iter()
+            .map(|log| log.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_combine_by_key() {
+        let multiline = multiline(json!({"keys": ["host", "stream"], "pattern": r"^(\s+at |Caused by:)"}));
+        let now = Instant::now();
+        let (complete, combined) = multiline.combine("default", vec![
+            log(0, "java.lang.IllegalStateException: boom", "a"),
+            log(1, "java.lang.RuntimeException: other host", "b"),
+            log(2, "\tat com.example.Service.run(Service.java:42)", "a"),
+            log(3, "\tat com.example.Other.call(Other.java:7)", "b"),
+            log(4, "Caused by: java.io.IOException", "a"),
+            log(5, "next event", "a"),
+        ], now);
+
+        // only the event followed by a new one is complete
+        assert_eq!(combined, 3);
+        assert_eq!(
+            messages(&complete),
+            vec!["java.lang.IllegalStateException: boom\n\tat com.example.Service.run(Service.java:42)\nCaused by: java.io.IOException"],
+        );
+        assert_eq!(multiline.buffered("default"), 2);
+
+        let flushed: Vec<IngestLog> = multiline.flush(Some(now + Duration::from_secs(1)))
+            .into_iter()
+            .map(|(tenant_id, log)| {
+                assert_eq!(tenant_id, "default");
+                log
+            })
+            .collect();
+
+        assert_eq!(
+            messages(&flushed),
+            vec!["java.lang.RuntimeException: other host\n\tat com.example.Other.call(Other.java:7)", "next event"],
+        );
+        assert_eq!(multiline.buffered("default"), 0);
+    }
+
+    #[test]
+    fn test_combine_across_batches() {
+        let multiline = multiline(json!({"keys": ["host"], "pattern": r"^\s+at "}));
+        let now = Instant::now();
+
+        let (complete, _) = multiline.combine("default", vec![log(0, "boom", "a"), log(1, "  at first", "a")], now);
+
+        assert!(complete.is_empty());
+        // still within the window, so the event is held
+        assert!(multiline.flush(Some(now + Duration::from_millis(500))).is_empty());
+
+        // another tenant's stream with the same key is separate
+        let (complete, combined) = multiline.combine("other", vec![log(2, "  at elsewhere", "a")], now);
+
+        assert_eq!((messages(&complete), combined), (vec![], 0));
+
+        let (complete, combined) = multiline.combine("default", vec![log(2, "  at second", "a"), log(3, "next", "a")], now);
+
+        assert_eq!(combined, 1);
+        assert_eq!(messages(&complete), vec!["boom\n  at first\n  at second"]);
+
+        let flushed = multiline.flush(None);
+
+        assert_eq!(flushed.len(), 2);
+        assert_eq!(flushed[0].0, "other");
+        assert_eq!(flushed[1].1.message, "next");
+    }
+
+    #[test]
+    fn test_combine_by_column() {
+        let multiline = multiline(json!({"keys": ["service"], "pattern": r"^\s+at "}));
+        let line = |message: &str, service: &str| IngestLog {
+            message: message.to_string(),
+            service: Some(service.to_string()),
+            ..Default::default()
+        };
+        let (_, combined) = multiline.combine("default", vec![
+            line("api failed", "api"),
+            line("billing failed", "billing"),
+            line("  at Api.handle", "api"),
+            line("  at Billing.charge", "billing"),
+        ], Instant::now());
+        let flushed: Vec<IngestLog> = multiline.flush(None)
+            .into_iter()
+            .map(|(_, log)| log)
+            .collect();
+
+        assert_eq!(combined, 2);
+        assert_eq!(messages(&flushed), vec!["api failed\n  at Api.handle", "billing failed\n  at Billing.charge"]);
+    }
+
+    #[test]
+    fn test_combine_window_and_limit() {
+        let multiline = multiline(json!({"keys": ["host"], "pattern": r"^\s", "window_ms": 100, "max_lines": 2}));
+        let (complete, _) = multiline.combine("default", vec![
+            log(0, "head", "a"),
+            log(50, "  first", "a"),
+            log(80, "  over the line limit", "a"),
+            log(500, "  outside the window", "a"),
+        ], Instant::now());
+
+        assert_eq!(messages(&complete), vec!["head\n  first", "  over the line limit"]);
+        assert_eq!(multiline.flush(None).len(), 1);
+
+        // continuation lines without a preceding record are kept as they are
+        multiline.combine("default", vec![log(0, "  orphan", "a")], Instant::now());
+
+        assert_eq!(multiline.flush(None).len(), 1);
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::{
+        BTreeSet,
+        HashMap,
+    },
+    sync::Arc,
+};
+
+use sea_orm::{
+    ActiveValue,
+    ColumnTrait,
+    DatabaseConnection,
+    DbErr,
+    EntityTrait,
+    QueryFilter,
+    sea_query::OnConflict,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use tokio::sync::Mutex;
+
+use crate::models::{
+    Pattern,
+    PatternActiveModel,
+    PatternColumn,
+};
+
+
+/// Stands in for a variable token in a template
+const WILDCARD: &str = "<*>";
+/// Share of tokens which must match for a message to join an existing pattern
+const SIMILARITY_THRESHOLD: f64 = 0.5;
+/// Hex characters of the template hash kept as a pattern id
+const PATTERN_ID_LENGTH: usize = 16;
+
+
+struct Cluster {
+    id: String,
+    tokens: Vec<String>,
+}
+
+
+/// Drain-style clustering of messages into templates. Messages are split into whitespace
+/// separated tokens, with tokens containing digits (ids, counts, addresses, times) masked.
+/// Candidate patterns share the message's token count and first token; the message joins the
+/// most similar candidate if enough of its tokens match, turning the tokens which differ into
+/// wildcards, and starts a new pattern otherwise.
+///
+/// Only the first line of a message is considered, so stack traces cluster on their headline.
+/// Once `max_clusters` patterns exist, messages which would start a new one get no pattern.
+pub struct Drain {
+    tenant_id: String,
+    max_clusters: usize,
+    clusters: Vec<Cluster>,
+    index: HashMap<(usize, String), Vec<usize>>,
+}
+
+impl Drain {
+    pub fn new<S: Into<String>>(tenant_id: S, max_clusters: usize) -> Self {
+        Self {
+            tenant_id: tenant_id.into(),
+            max_clusters,
+            clusters: vec![],
+            index: HashMap::new(),
+        }
+    }
+
+    /// Restores previously mined patterns
+    pub fn with_templates<S: Into<String>>(tenant_id: S, max_clusters: usize, templates: Vec<(String, String)>) -> Self {
+        let mut drain = Self::new(tenant_id, max_clusters);
+
+        for (id, template) in templates {
+            let tokens = template
+                .split_whitespace()
+                .map(|token| token.to_string())
+                .collect();
+
+            drain.insert(Cluster { id, tokens });
+        }
+
+        drain
+    }
+
+    /// Assigns `message` to a pattern, returning its id and whether the pattern is new or its
+    /// template changed; `None` when it would start a pattern beyond `max_clusters`
+    pub fn add(&mut self, message: &str) -> Option<(String, bool)> {
+        let tokens = Self::tokenize(message);
+        let candidates = self.index
+            .get(&Self::key(&tokens))
+            .cloned()
+            .unwrap_or_default();
+        let best = candidates
+            .into_iter()
+            .map(|position| (position, Self::similarity(&self.clusters[position].tokens, &tokens)))
+            .filter(|(_, (similarity, _))| *similarity >= SIMILARITY_THRESHOLD)
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        if let Some((position, _)) = best {
+            let cluster = &mut self.clusters[position];
+            let mut changed = false;
+
+            for (template, token) in cluster.tokens.iter_mut().zip(&tokens) {
+                if template != token && template != WILDCARD {
+                    *template = WILDCARD.to_string();
+                    changed = true;
+                }
+            }
+
+            return Some((cluster.id.clone(), changed));
+        }
+
+        if self.clusters.len() >= self.max_clusters {
+            return None;
+        }
+
+        let id = self.pattern_id(&tokens.join(" "));
+
+        self.insert(Cluster { id: id.clone(), tokens });
+
+        Some((id, true))
+    }
+
+    pub fn template(&self, id: &str) -> Option<String> {
+        self.clusters
+            .iter()
+            .find(|cluster| cluster.id == id)
+            .map(|cluster| cluster.tokens.join(" "))
+    }
+
+    fn insert(&mut self, cluster: Cluster) {
+        self.index
+            .entry(Self::key(&cluster.tokens))
+            .or_default()
+            .push(self.clusters.len());
+        self.clusters.push(cluster);
+    }
+
+    fn tokenize(message: &str) -> Vec<String> {
+        message
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|token| {
+                if token.chars().any(|c| c.is_ascii_digit()) {
+                    WILDCARD.to_string()
+                } else {
+                    token.to_string()
+                }
+            })
+            .collect()
+    }
+
+    fn key(tokens: &[String]) -> (usize, String) {
+        (tokens.len(), tokens.first().cloned().unwrap_or_default())
+    }
+
+    /// The share of tokens equal to the template's, then the number of wildcards in the
+    /// template, which breaks ties in favour of the more general pattern
+    fn similarity(template: &[String], tokens: &[String]) -> (f64, usize) {
+        if tokens.is_empty() {
+            return (1.0, 0);
+        }
+
+        let equal = template
+            .iter()
+            .zip(tokens)
+            .filter(|(template, token)| template == token)
+            .count();
+        let wildcards = template
+            .iter()
+            .filter(|template| *template == WILDCARD)
+            .count();
+
+        (equal as f64 / tokens.len() as f64, wildcards)
+    }
+
+    /// Ids are derived from the tenant and the first template of a pattern, so they're stable
+    /// across restarts
+    fn pattern_id(&self, template: &str) -> String {
+        let mut hasher = Sha256::new();
+
+        hasher.update(self.tenant_id.as_bytes());
+        hasher.update(b"\n");
+        hasher.update(template.as_bytes());
+
+        let digest = hasher.finalize();
+        let hex: String = digest
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        hex[..PATTERN_ID_LENGTH].to_string()
+    }
+}
+
+
+/// Mines patterns per tenant, keeping each tenant's [`Drain`] in memory and its templates in
+/// `log_patterns`. A tenant's patterns are loaded the first time its logs are mined.
+pub struct PatternMiner {
+    max_clusters: usize,
+    tenants: std::sync::Mutex<HashMap<String, Arc<Mutex<Option<Drain>>>>>,
+}
+
+impl PatternMiner {
+    pub fn new(max_clusters: usize) -> Self {
+        Self {
+            max_clusters,
+            tenants: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Assigns each message a pattern id, storing new and changed templates. Messages get no
+    /// pattern once the tenant has `max_clusters` patterns and they don't match any of them.
+    pub async fn mine(&self, db: &DatabaseConnection, tenant_id: &str, messages: &[&str]) -> Result<Vec<Option<String>>, DbErr> {
+        let tenant = self.tenants
+            .lock()
+            .unwrap()
+            .entry(tenant_id.to_string())
+            .or_default()
+            .clone();
+        // held until templates are stored, so an older template can't overwrite a newer one;
+        // only the tenant's own mining waits on it
+        let mut drain = tenant.lock().await;
+
+        if drain.is_none() {
+            let templates = Pattern::find()
+                .filter(PatternColumn::TenantId.eq(tenant_id))
+                .all(db)
+                .await?
+                .into_iter()
+                .map(|pattern| (pattern.id, pattern.template))
+                .collect();
+
+            *drain = Some(Drain::with_templates(tenant_id, self.max_clusters, templates));
+        }
+
+        let drain = drain
+            .as_mut()
+            .expect("Patterns were loaded above");
+        let mut changed = BTreeSet::new();
+        let ids: Vec<Option<String>> = messages
+            .iter()
+            .map(|message| {
+                let (id, is_changed) = drain.add(message)?;
+
+                if is_changed {
+                    changed.insert(id.clone());
+                }
+
+                Some(id)
+            })
+            .collect();
+
+        if changed.is_empty() {
+            return Ok(ids);
+        }
+
+        let now = chrono::Utc::now();
+        let patterns: Vec<PatternActiveModel> = changed
+            .into_iter()
+            .filter_map(|id| drain.template(&id).map(|template| (id, template)))
+            .map(|(id, template)| PatternActiveModel {
+                tenant_id: ActiveValue::Set(tenant_id.to_string()),
+                id: ActiveValue::Set(id),
+                template: ActiveValue::Set(template),
+                created_at: ActiveValue::Set(now.into()),
+                updated_at: ActiveValue::Set(now.into()),
+            })
+            .collect();
+
+        Pattern::insert_many(patterns)
+            .on_conflict(
+                OnConflict::columns([PatternColumn::TenantId, PatternColumn::Id])
+                    .update_columns([PatternColumn::Template, PatternColumn::UpdatedAt])
+                    .to_owned()
+            )
+            .exec(db)
+            .await?;
+
+        Ok(ids)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Drain;
+
+    #[test]
+    fn test_drain_clusters_messages() {
+        let mut drain = Drain::new("default", 100);
+
+        let (first, created) = drain.add("user 1234 logged in from 10.0.0.1")
+            .unwrap();
+        let (second, changed) = drain.add("user 99 logged in from 10.0.0.7")
+            .unwrap();
+
+        assert!(created);
+        assert!(!changed);
+        assert_eq!(first, second);
+        assert_eq!(drain.template(&first).unwrap(), "user <*> logged in from <*>");
+
+        // differing words are generalised once enough of the message matches
+        let (third, changed) = drain.add("user alice logged out from 10.0.0.9")
+            .unwrap();
+
+        assert_eq!(third, first);
+        assert!(changed);
+        assert_eq!(drain.template(&first).unwrap(), "user <*> logged <*> from <*>");
+
+        let (session, _) = drain.add("user 5 logged out")
+            .unwrap();
+        let (timeout, _) = drain.add("connection timed out after 30s\n  at Client.connect")
+            .unwrap();
+
+        assert_ne!(session, first);
+        assert_eq!(drain.template(&timeout).unwrap(), "connection timed out after <*>");
+    }
+
+    #[test]
+    fn test_drain_restores_templates() {
+        let mut drain = Drain::new("default", 100);
+        let (id, _) = drain.add("cache miss for key abc")
+            .unwrap();
+
+        drain.add("cache miss for key def")
+            .unwrap();
+
+        let mut restored = Drain::with_templates("default", 100, vec![(id.clone(), drain.template(&id).unwrap())]);
+
+        assert_eq!(restored.add("cache miss for key xyz"), Some((id.clone(), false)));
+
+        // ids depend on the tenant
+        assert_ne!(Drain::new("other", 100).add("cache miss for key abc").unwrap().0, id);
+    }
+
+    #[test]
+    fn test_drain_max_clusters() {
+        let mut drain = Drain::new("default", 1);
+        let (id, _) = drain.add("cache miss for key abc")
+            .unwrap();
+
+        // existing patterns still match and generalise, but no new pattern is started
+        assert_eq!(drain.add("cache miss for key def"), Some((id, true)));
+        assert_eq!(drain.add("connection refused"), None);
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    time::Instant,
+};
+
+use serde::Deserialize;
+
+use crate::models::IngestLog;
+
+use super::{
+    condition::Condition,
+    grok::{
+        self,
+        Grok,
+    },
+    multiline::{
+        Multiline,
+        MultilineConfig,
+    },
+    IngestError,
+};
+
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CoerceType {
+    Boolean,
+    Float,
+    Integer,
+    String,
+}
+
+
+/// A single processor as loaded from `PIPELINE_CONFIG`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ProcessorConfig {
+    /// Sets a context key to a fixed value
+    Set {
+        key: String,
+        value: serde_json::Value,
+    },
+    /// Moves a context key to a new name, replacing any existing value
+    Rename {
+        from: String,
+        to: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Replaces `message` with the value of a context key
+    CopyToMessage {
+        key: String,
+    },
+    /// Converts a context value to the given type; values which cannot be converted are left as is
+    Coerce {
+        key: String,
+        to: CoerceType,
+    },
+    /// Parses `source` (`message`, or a context key) with the first matching grok pattern,
+    /// merging its captures into the context
+    Grok {
+        #[serde(default = "default_grok_source")]
+        source: String,
+        patterns: Vec<String>,
+        #[serde(default)]
+        definitions: HashMap<String, String>,
+    },
+    /// Drops records matching every filter
+    Drop {
+        filter: HashMap<String, String>,
+    },
+    /// Runs `processors` for records matching every filter, `otherwise` for the rest
+    Route {
+        filter: HashMap<String, String>,
+        #[serde(default)]
+        processors: Vec<ProcessorConfig>,
+        #[serde(default)]
+        otherwise: Vec<ProcessorConfig>,
+    },
+}
+
+
+fn default_grok_source() -> String {
+    "message".to_string()
+}
+
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub multiline: Option<MultilineConfig>,
+    pub processors: Vec<ProcessorConfig>,
+}
+
+
+enum Processor {
+    Set(String, serde_json::Value),
+    Rename(String, String),
+    Remove(String),
+    CopyToMessage(String),
+    Coerce(String, CoerceType),
+    Grok(String, Vec<Grok>),
+    Drop(Condition),
+    Route(Condition, Vec<Processor>, Vec<Processor>),
+}
+
+impl Processor {
+    fn from_config(config: ProcessorConfig) -> Result<Self, IngestError> {
+        let processor = match config {
+            ProcessorConfig::Set { key, value } => Self::Set(key, value),
+            ProcessorConfig::Rename { from, to } => Self::Rename(from, to),
+            ProcessorConfig::Remove { key } => Self::Remove(key),
+            ProcessorConfig::CopyToMessage { key } => Self::CopyToMessage(key),
+            ProcessorConfig::Coerce { key, to } => Self::Coerce(key, to),
+            ProcessorConfig::Grok { source, patterns, definitions } => Self::Grok(
+                source,
+                patterns
+                    .iter()
+                    .map(|pattern| Grok::compile(pattern, &definitions))
+                    .collect::<Result<Vec<Grok>, IngestError>>()?,
+            ),
+            ProcessorConfig::Drop { filter } => Self::Drop(Condition::parse(&filter)?),
+            ProcessorConfig::Route { filter, processors, otherwise } => Self::Route(
+                Condition::parse(&filter)?,
+                Self::from_configs(processors)?,
+                Self::from_configs(otherwise)?,
+            ),
+        };
+
+        Ok(processor)
+    }
+
+    fn from_configs(configs: Vec<ProcessorConfig>) -> Result<Vec<Self>, IngestError> {
+        configs
+            .into_iter()
+            .map(Self::from_config)
+            .collect()
+    }
+
+    /// Applies the processor to `log`, returning false when the record should be dropped
+    fn apply(&self, log: &mut IngestLog) -> bool {
+        match self {
+            Self::Set(key, value) => {
+                if let Some(context) = context_mut(log) {
+                    context.insert(key.clone(), value.clone());
+                }
+            },
+            Self::Rename(from, to) => {
+                if let Some(context) = context_mut(log) {
+                    if let Some(value) = context.remove(from) {
+                        context.insert(to.clone(), value);
+                    }
+                }
+            },
+            Self::Remove(key) => {
+                if let Some(context) = context_mut(log) {
+                    context.remove(key);
+                }
+            },
+            Self::CopyToMessage(key) => {
+                let value = log.context
+                    .as_ref()
+                    .and_then(|context| context.get(key));
+
+                match value {
+                    Some(serde_json::Value::String(s)) => log.message = s.clone(),
+                    Some(serde_json::Value::Null) | None => {},
+                    Some(value) => log.message = value.to_string(),
+                }
+            },
+            Self::Coerce(key, to) => {
+                if let Some(value) = context_mut(log).and_then(|context| context.get_mut(key)) {
+                    if let Some(coerced) = coerce(value, *to) {
+                        *value = coerced;
+                    }
+                }
+            },
+            Self::Grok(source, patterns) => {
+                let text = match source.as_str() {
+                    "message" => Some(log.message.as_str()),
+                    key => log.context
+                        .as_ref()
+                        .and_then(|context| context.get(key))
+                        .and_then(|value| value.as_str()),
+                };
+                let fields = text.and_then(|text| patterns.iter().find_map(|grok| grok.parse(text)));
+
+                if let Some(fields) = fields {
+                    if let Some(context) = context_mut(log) {
+                        grok::merge(context, fields);
+                    }
+                }
+            },
+            Self::Drop(condition) => return !condition.matches(log),
+            Self::Route(condition, processors, otherwise) => {
+                let branch = if condition.matches(log) { processors } else { otherwise };
+
+                return branch
+                    .iter()
+                    .all(|processor| processor.apply(log));
+            },
+        }
+
+        true
+    }
+}
+
+
+/// Declarative processors run over each record before it is redacted and stored
+#[derive(Default)]
+pub struct Pipeline {
+    multiline: Option<Multiline>,
+    processors: Vec<Processor>,
+}
+
+impl Pipeline {
+    pub fn from_config(config: PipelineConfig) -> Result<Self, IngestError> {
+        Ok(Self {
+            multiline: config.multiline
+                .map(Multiline::from_config)
+                .transpose()?,
+            processors: Processor::from_configs(config.processors)?,
+        })
+    }
+
+    /// Joins continuation lines into their preceding records, when configured, returning the
+    /// complete records and the number of lines joined. Runs before the processors so they
+    /// see whole events; see [`Multiline`] for how events are buffered.
+    pub fn combine(&self, tenant_id: &str, logs: Vec<IngestLog>) -> (Vec<IngestLog>, usize) {
+        match &self.multiline {
+            Some(multiline) => multiline.combine(tenant_id, logs, Instant::now()),
+            None => (logs, 0),
+        }
+    }
+
+    /// The multiline combiner, when configured
+    pub fn multiline(&self) -> Option<&Multiline> {
+        self.multiline.as_ref()
+    }
+
+    /// Runs every processor over `log`, returning None if the record was dropped
+    pub fn process(&self, mut log: IngestLog) -> Option<IngestLog> {
+        for processor in &self.processors {
+            if !processor.apply(&mut log) {
+                return None;
+            }
+        }
+
+        Some(log)
+    }
+}
+
+
+/// The context as a mutable map, creating an empty one for logs without context.
+/// Contexts which are not JSON objects are left alone.
+fn context_mut(log: &mut IngestLog) -> Option<&mut serde_json::Map<String, serde_json::Value>> {
+    log.context
+        .get_or_insert_with(|| serde_json::Value::Object(Default::default()))
+        .as_object_mut()
+}
+
+
+fn coerce(value: &serde_json::Value, to: CoerceType) -> Option<serde_json::Value> {
+    use serde_json::Value;
+
+    match (to, value) {
+        (CoerceType::String, Value::String(_)) => None,
+        (CoerceType::String, Value::Null) => None,
+        (CoerceType::String, value) => Some(Value::String(value.to_string())),
+        (CoerceType::Integer, Value::String(s)) => s.trim()
+            .parse::<i64>()
+            .ok()
+            .or_else(|| s.trim().parse::<f64>().ok().filter(|f| f.is_finite()).map(|f| f as i64))
+            .map(Value::from),
+        (CoerceType::Integer, Value::Number(n)) if !n.is_i64() && !n.is_u64() => n.as_f64()
+            .map(|f| Value::from(f as i64)),
+        (CoerceType::Integer, Value::Bool(b)) => Some(Value::from(*b as i64)),
+        (CoerceType::Float, Value::String(s)) => s.trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number),
+        (CoerceType::Float, Value::Number(n)) => n.as_f64()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number),
+        (CoerceType::Boolean, Value::String(s)) => match s.trim().to_lowercase().as_str() {
+            "true" | "yes" | "1" => Some(Value::Bool(true)),
+            "false" | "no" | "0" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        (CoerceType::Boolean, Value::Number(n)) => n.as_f64().map(|f| Value::Bool(f != 0.0)),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::models::IngestLog;
+    use super::{
+        Pipeline,
+        PipelineConfig,
+    };
+
+    fn pipeline(config: serde_json::Value) -> Pipeline {
+        Pipeline::from_config(serde_json::from_value::<PipelineConfig>(config).unwrap())
+            .unwrap()
+    }
+
+    fn log(level: i32, context: serde_json::Value) -> IngestLog {
+        IngestLog {
+            timestamp: None,
+            message: "original".to_string(),
+            level,
+            context: Some(context),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pipeline_processors() {
+        let pipeline = pipeline(json!({
+            "processors": [
+                {"type": "set", "key": "env", "value": "prod"},
+                {"type": "rename", "from": "svc", "to": "service"},
+                {"type": "remove", "key": "debug"},
+                {"type": "copy_to_message", "key": "msg"},
+                {"type": "coerce", "key": "latency_ms", "to": "integer"},
+                {"type": "coerce", "key": "cached", "to": "boolean"},
+                {"type": "coerce", "key": "status", "to": "string"},
+            ],
+        }));
+
+        let processed = pipeline
+            .process(log(3, json!({
+                "svc": "api",
+                "debug": {"verbose": true},
+                "msg": "GET /health",
+                "latency_ms": "12",
+                "cached": "yes",
+                "status": 200,
+            })))
+            .unwrap();
+
+        assert_eq!(processed.message, "GET /health");
+        assert_eq!(
+            processed.context.unwrap(),
+            json!({
+                "env": "prod",
+                "service": "api",
+                "msg": "GET /health",
+                "latency_ms": 12,
+                "cached": true,
+                "status": "200",
+            }),
+        );
+    }
+
+    #[test]
+    fn test_pipeline_drop_and_route() {
+        let pipeline = pipeline(json!({
+            "processors": [
+                {"type": "drop", "filter": {"filter[path][eq]": "/health"}},
+                {
+                    "type": "route",
+                    "filter": {"filter[level][gte]": "4"},
+                    "processors": [{"type": "set", "key": "page", "value": true}],
+                    "otherwise": [{"type": "drop", "filter": {"filter[level][lt]": "2"}}],
+                },
+            ],
+        }));
+
+        assert!(pipeline.process(log(3, json!({"path": "/health"}))).is_none());
+        assert!(pipeline.process(log(1, json!({"path": "/users"}))).is_none());
+        assert_eq!(pipeline.process(log(5, json!({}))).unwrap().context.unwrap()["page"], true);
+        assert_eq!(pipeline.process(log(3, json!({}))).unwrap().context.unwrap(), json!({}));
+    }
+
+    #[test]
+    fn test_pipeline_grok() {
+        let pipeline = pipeline(json!({
+            "processors": [
+                {"type": "grok", "patterns": ["^%{POSTGRES}", "^%{NGINX_ACCESS}"]},
+                {"type": "grok", "source": "request", "patterns": ["%{WORD:request_info.verb} %{NOTSPACE:target}"]},
+            ],
+        }));
+
+        let mut access = log(3, json!({"request": "GET /", "request_info": {"id": 7}}));
+
+        access.message = r#"10.0.0.1 - - [25/Dec/2022:13:45:00 +0000] "GET /health HTTP/1.1" 200 2 "-" "curl/7.64.1""#.to_string();
+
+        let context = pipeline.process(access)
+            .unwrap()
+            .context
+            .unwrap();
+
+        assert_eq!(context["status"], 200);
+        assert_eq!(context["path"], "/health");
+        // nested captures are merged into existing objects
+        assert_eq!(context["request_info"], json!({"id": 7, "verb": "GET"}));
+
+        let mut unmatched = log(3, json!({}));
+
+        unmatched.context = None;
+
+        assert!(pipeline.process(unmatched).unwrap().context.is_none());
+    }
+
+    #[test]
+    fn test_invalid_pipeline_config() {
+        let unknown = serde_json::from_value::<PipelineConfig>(json!({"processors": [{"type": "explode"}]}));
+        let invalid = serde_json::from_value::<PipelineConfig>(json!({
+            "processors": [{"type": "drop", "filter": {"filter[level][between]": "1"}}],
+        })).unwrap();
+
+        assert!(unknown.is_err());
+        assert!(Pipeline::from_config(invalid).is_err());
+    }
+}
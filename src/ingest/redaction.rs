@@ -0,0 +1,472 @@
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    sync::Mutex,
+};
+
+use regex::{
+    Captures,
+    Regex,
+};
+use hmac::{
+    Hmac,
+    Mac,
+};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::models::IngestLog;
+
+use super::IngestError;
+
+
+const DEFAULT_REPLACEMENT: &str = "[REDACTED]";
+
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactionMode {
+    /// Replace sensitive values with the configured replacement
+    #[default]
+    Replace,
+    /// Replace sensitive values with a truncated HMAC-SHA256 keyed with the configured secret, so
+    /// equal values remain correlatable without being guessable from their digest
+    Hash,
+}
+
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatternConfig {
+    pub name: String,
+    pub pattern: String,
+}
+
+
+/// Redaction rules as loaded from `REDACTION_CONFIG`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactionConfig {
+    pub mode: RedactionMode,
+    pub replacement: Option<String>,
+    /// Key for `hash` mode, required by it
+    pub secret: Option<String>,
+    pub detectors: Vec<String>,
+    pub patterns: Vec<PatternConfig>,
+    pub keys: Vec<String>,
+}
+
+
+type Validator = fn(&str) -> bool;
+
+
+/// A regex redacting each match, or only its `value` group when the pattern has one
+struct Rule {
+    name: String,
+    regex: Regex,
+    validate: Option<Validator>,
+}
+
+
+/// Removes secrets and PII from logs before they are stored. Context values, and the
+/// `service`, `host`, `trace_id` and `span_id` columns, under a key matching one of the key
+/// globs are redacted whole; every other string in the message, columns and context is
+/// scanned by the detectors and patterns.
+#[derive(Default)]
+pub struct Redactor {
+    mode: RedactionMode,
+    replacement: String,
+    secret: Vec<u8>,
+    rules: Vec<Rule>,
+    keys: Vec<(String, Regex)>,
+    /// Redactions made by each rule, by tenant
+    counts: Mutex<HashMap<String, BTreeMap<String, u64>>>,
+}
+
+
+/// Redactions made by each rule while redacting one log
+type Tally<'a> = BTreeMap<&'a str, u64>;
+
+impl Redactor {
+    pub fn from_config(config: RedactionConfig) -> Result<Self, IngestError> {
+        let secret = config.secret
+            .filter(|secret| !secret.is_empty());
+
+        if config.mode == RedactionMode::Hash && secret.is_none() {
+            return Err(IngestError::Config("hash redaction mode requires a secret".to_string()));
+        }
+
+        let mut rules = vec![];
+
+        for detector in &config.detectors {
+            rules.push(Self::detector(detector)?);
+        }
+
+        for pattern in config.patterns {
+            let regex = Regex::new(&pattern.pattern)
+                .map_err(|e| IngestError::Config(format!("invalid redaction pattern {}: {}", pattern.name, e)))?;
+
+            rules.push(Rule {
+                name: pattern.name,
+                regex,
+                validate: None,
+            });
+        }
+
+        let keys = config.keys
+            .iter()
+            .map(|key| Ok((format!("key:{}", key), glob(key)?)))
+            .collect::<Result<Vec<(String, Regex)>, IngestError>>()?;
+
+        Ok(Self {
+            mode: config.mode,
+            replacement: config.replacement.unwrap_or(DEFAULT_REPLACEMENT.to_string()),
+            secret: secret.unwrap_or_default().into_bytes(),
+            rules,
+            keys,
+            counts: Mutex::default(),
+        })
+    }
+
+    fn detector(name: &str) -> Result<Rule, IngestError> {
+        let (pattern, validate): (&str, Option<Validator>) = match name {
+            "email" => (r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}", None),
+            "credit_card" => (r"\b(?:\d[ -]?){12,18}\d\b", Some(luhn)),
+            "bearer_token" => (r"(?i)\bbearer\s+(?P<value>[A-Za-z0-9\-._~+/]+=*)", None),
+            "jwt" => (r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+", None),
+            "aws_access_key" => (r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b", None),
+            "password_assignment" => (
+                r#"(?i)\b(?:password|passwd|pwd|secret|token|api[_-]?key)\b["']?\s*[=:]\s*["']?(?P<value>[^\s"'&,;]+)"#,
+                None,
+            ),
+            _ => return Err(IngestError::Config(format!("unknown redaction detector {}", name))),
+        };
+
+        Ok(Rule {
+            name: name.to_string(),
+            regex: Regex::new(pattern).unwrap(),
+            validate,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.keys.is_empty()
+    }
+
+    /// Redacts `tenant_id`'s `log` in place, returning the number of redactions made. Runs
+    /// after context fields are promoted, so the promoted columns are covered as well as the
+    /// context.
+    pub fn redact(&self, tenant_id: &str, log: &mut IngestLog) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+
+        let mut tally = Tally::new();
+
+        self.redact_string(&mut log.message, &mut tally);
+
+        let columns = [
+            ("service", &mut log.service),
+            ("host", &mut log.host),
+            ("trace_id", &mut log.trace_id),
+            ("span_id", &mut log.span_id),
+        ];
+
+        for (key, value) in columns {
+            if let Some(value) = value.as_mut() {
+                match self.key_rule(key) {
+                    Some(name) => {
+                        *value = self.replacement_for(value);
+                        *tally.entry(name).or_default() += 1;
+                    },
+                    None => self.redact_string(value, &mut tally),
+                }
+            }
+        }
+
+        if let Some(context) = log.context.as_mut() {
+            self.redact_value(context, &mut tally);
+        }
+
+        if tally.is_empty() {
+            return 0;
+        }
+
+        let mut counts = self.counts.lock().unwrap();
+        let tenant_counts = counts
+            .entry(tenant_id.to_string())
+            .or_default();
+
+        for (name, count) in &tally {
+            *tenant_counts.entry(name.to_string()).or_default() += count;
+        }
+
+        tally.values().sum()
+    }
+
+    /// Total redactions made in `tenant_id`'s logs by each detector, pattern and key rule
+    pub fn counts(&self, tenant_id: &str) -> BTreeMap<String, u64> {
+        let counts = self.counts.lock().unwrap();
+        let tenant_counts = counts.get(tenant_id);
+
+        self.rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .chain(self.keys.iter().map(|(name, _)| name.as_str()))
+            .map(|name| {
+                let count = tenant_counts
+                    .and_then(|tenant_counts| tenant_counts.get(name))
+                    .copied()
+                    .unwrap_or(0);
+
+                (name.to_string(), count)
+            })
+            .collect()
+    }
+
+    fn redact_value<'a>(&'a self, value: &mut serde_json::Value, tally: &mut Tally<'a>) {
+        match value {
+            serde_json::Value::String(s) => self.redact_string(s, tally),
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    self.redact_value(value, tally);
+                }
+            },
+            serde_json::Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    match self.key_rule(key) {
+                        Some(name) if !value.is_null() => {
+                            let original = match &*value {
+                                serde_json::Value::String(s) => s.clone(),
+                                other => other.to_string(),
+                            };
+
+                            *value = serde_json::Value::String(self.replacement_for(&original));
+                            *tally.entry(name).or_default() += 1;
+                        },
+                        Some(_) => {},
+                        None => self.redact_value(value, tally),
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// The name of the first key rule matching `key`
+    fn key_rule(&self, key: &str) -> Option<&str> {
+        self.keys
+            .iter()
+            .find(|(_, glob)| glob.is_match(key))
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn redact_string<'a>(&'a self, s: &mut String, tally: &mut Tally<'a>) {
+        for rule in &self.rules {
+            let mut matched = 0;
+            let redacted = rule.regex.replace_all(s, |captures: &Captures| {
+                let whole = captures.get(0).unwrap();
+                let target = captures.name("value").unwrap_or(whole);
+
+                if rule.validate.map(|validate| !validate(target.as_str())).unwrap_or(false) {
+                    return whole.as_str().to_string();
+                }
+
+                matched += 1;
+
+                format!(
+                    "{}{}{}",
+                    &whole.as_str()[..target.start() - whole.start()],
+                    self.replacement_for(target.as_str()),
+                    &whole.as_str()[target.end() - whole.start()..],
+                )
+            });
+
+            if matched > 0 {
+                *s = redacted.into_owned();
+                *tally.entry(rule.name.as_str()).or_default() += matched;
+            }
+        }
+    }
+
+    fn replacement_for(&self, value: &str) -> String {
+        match self.mode {
+            RedactionMode::Replace => self.replacement.clone(),
+            RedactionMode::Hash => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+                    .expect("HMAC accepts keys of any length");
+
+                mac.update(value.as_bytes());
+
+                let digest = format!("{:x}", mac.finalize().into_bytes());
+
+                format!("hmac:{}", &digest[..16])
+            },
+        }
+    }
+}
+
+
+/// Case insensitive glob (`*` matches any run of characters) as an anchored regex
+fn glob(pattern: &str) -> Result<Regex, IngestError> {
+    let expression = pattern
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<String>>()
+        .join(".*");
+
+    Regex::new(&format!("(?i)^{}$", expression))
+        .map_err(|e| IngestError::Config(format!("invalid redaction key {}: {}", pattern, e)))
+}
+
+
+/// Luhn checksum, used to discard digit runs which cannot be card numbers
+fn luhn(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .collect();
+
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(index, digit)| {
+            if index % 2 == 1 {
+                let doubled = digit * 2;
+
+                if doubled > 9 { doubled - 9 } else { doubled }
+            } else {
+                *digit
+            }
+        })
+        .sum();
+
+    digits.len() >= 13 && sum.is_multiple_of(10)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::models::IngestLog;
+    use super::{
+        RedactionConfig,
+        Redactor,
+    };
+
+    fn redactor(config: serde_json::Value) -> Redactor {
+        Redactor::from_config(serde_json::from_value::<RedactionConfig>(config).unwrap())
+            .unwrap()
+    }
+
+    fn log(message: &str, context: serde_json::Value) -> IngestLog {
+        IngestLog {
+            timestamp: None,
+            message: message.to_string(),
+            level: 3,
+            context: Some(context),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_detectors() {
+        let redactor = redactor(json!({
+            "detectors": ["email", "credit_card", "bearer_token", "password_assignment"],
+        }));
+        let mut log = log(
+            "user bob@example.com paid with 4111 1111 1111 1111, order 1234567890123 password=hunter2",
+            json!({"headers": {"authorization": "Bearer abc.def"}}),
+        );
+
+        let redactions = redactor.redact("default", &mut log);
+
+        assert_eq!(redactions, 4);
+        assert_eq!(
+            log.message,
+            "user [REDACTED] paid with [REDACTED], order 1234567890123 password=[REDACTED]",
+        );
+        assert_eq!(log.context.unwrap()["headers"]["authorization"], "Bearer [REDACTED]");
+        assert_eq!(redactor.counts("default")["credit_card"], 1);
+        // counts are kept per tenant, listing every rule
+        assert_eq!(redactor.counts("other")["credit_card"], 0);
+        assert_eq!(redactor.counts("other").len(), 4);
+    }
+
+    #[test]
+    fn test_keys_and_patterns_hashed() {
+        let redactor = redactor(json!({
+            "mode": "hash",
+            "secret": "correct horse battery staple",
+            "keys": ["*password*"],
+            "patterns": [{"name": "internal_id", "pattern": "secret-[0-9]+"}],
+        }));
+        let mut first = log("got secret-42", json!({"db_password": "hunter2", "user": {"Password": 1234}}));
+        let mut second = log("got secret-42", json!({}));
+
+        assert_eq!(redactor.redact("default", &mut first), 3);
+        redactor.redact("default", &mut second);
+
+        let context = first.context.unwrap();
+
+        assert!(context["db_password"].as_str().unwrap().starts_with("hmac:"));
+        assert!(context["user"]["Password"].as_str().unwrap().starts_with("hmac:"));
+        assert_eq!(first.message, second.message);
+        assert_ne!(first.message, "got secret-42");
+        assert_eq!(redactor.counts("default")["key:*password*"], 2);
+
+        // the digest depends on the secret, so can't be precomputed for likely values
+        let other = self::redactor(json!({
+            "mode": "hash",
+            "secret": "another secret",
+            "patterns": [{"name": "internal_id", "pattern": "secret-[0-9]+"}],
+        }));
+        let mut third = log("got secret-42", json!({}));
+
+        other.redact("default", &mut third);
+
+        assert_ne!(first.message, third.message);
+    }
+
+    #[test]
+    fn test_hash_requires_secret() {
+        let config = serde_json::from_value::<RedactionConfig>(json!({"mode": "hash", "keys": ["*password*"]})).unwrap();
+
+        assert!(Redactor::from_config(config).is_err());
+    }
+
+    #[test]
+    fn test_promoted_columns() {
+        let redactor = redactor(json!({
+            "detectors": ["email"],
+            "keys": ["host"],
+        }));
+        let mut log = log("login", json!({"service": "bob@example.com", "host": "db-1.internal"}));
+
+        log.promote_context_fields();
+
+        assert_eq!(redactor.redact("default", &mut log), 4);
+        assert_eq!(log.service.as_deref(), Some("[REDACTED]"));
+        assert_eq!(log.host.as_deref(), Some("[REDACTED]"));
+
+        let context = log.context.unwrap();
+
+        assert_eq!(context["service"], "[REDACTED]");
+        assert_eq!(context["host"], "[REDACTED]");
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let unknown = serde_json::from_value::<RedactionConfig>(json!({"detectors": ["ssn-ish"]}))
+            .unwrap();
+        let invalid = serde_json::from_value::<RedactionConfig>(json!({"patterns": [{"name": "bad", "pattern": "("}]}))
+            .unwrap();
+
+        assert!(Redactor::from_config(unknown).is_err());
+        assert!(Redactor::from_config(invalid).is_err());
+    }
+}
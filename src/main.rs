@@ -1,13 +1,29 @@
 use envconfig::Envconfig;
 
 use config::Config;
+use error::Loggable;
+use metrics::ServiceMetrics;
+use ingest::{
+    metrics::LogMetrics,
+    pipeline::Pipeline,
+    redaction::Redactor,
+};
 
 mod api;
+mod archive;
 mod config;
 mod database;
 mod error;
+mod export;
+mod ingest;
+mod metrics;
 mod models;
 mod parameters;
+mod query_language;
+mod tasks;
+#[cfg(test)]
+mod testing;
+mod time_expression;
 
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -30,9 +46,10 @@ async fn main() {
 
     tracing::info!("log-ingest-api version: {}", VERSION);
 
-    let db_connection = database::get_db_connection(&config)
+    let db_pool = database::get_db_pool(&config)
         .await
         .expect("Failed to setup database connection!");
+    let db_connection = database::into_db_connection(db_pool.clone());
     
     // run database migrations as part of application startup
     database::migrate(&config)
@@ -40,8 +57,35 @@ async fn main() {
         .expect("Failed to run database migrations!");
 
     let bind_to = format!("{}:{}", config.http_host, config.http_port);
-    let api = api::Api::new(db_connection, config);
-    
+    let redactor = ingest::read_config(config.redaction_config.as_ref())
+        .and_then(Redactor::from_config)
+        .expect("Failed to load redaction configuration!");
+    let pipeline = ingest::read_config(config.pipeline_config.as_ref())
+        .and_then(Pipeline::from_config)
+        .expect("Failed to load pipeline configuration!");
+    let log_metrics = ingest::read_config(config.metrics_config.as_ref())
+        .and_then(LogMetrics::from_config)
+        .expect("Failed to load metrics configuration!");
+
+    let api = api::Api::new(db_connection, config.clone())
+        .with_redactor(redactor)
+        .with_pipeline(pipeline)
+        .with_log_metrics(log_metrics)
+        .with_metrics(ServiceMetrics::default().with_pool(db_pool));
+
+    let state = api.state();
+
+    if let Ok(version) = database::migration_version(&state.db)
+        .await
+        .log_error("An exception occurred while reading the migration version") {
+        state.metrics.set_migration_version(version);
+    }
+
+    tasks::alerts::spawn(state.db.clone(), &config);
+    tasks::multiline::spawn(state.clone());
+    tasks::partitions::spawn(state.db.clone(), &config);
+    tasks::retention::spawn(state.db.clone(), state.retention.clone(), &config);
+
     tracing::info!("Starting server on {}", bind_to);
 
     axum::Server::bind(
@@ -51,6 +95,38 @@ async fn main() {
     ).serve(
         api.into_router()
             .into_make_service()
-    ).await
+    ).with_graceful_shutdown(shutdown_signal())
+    .await
     .unwrap();
+
+    // multiline events still waiting for lines would otherwise be lost
+    let flushed = tasks::multiline::flush(&state, None).await;
+
+    tracing::info!("Stored {} buffered multiline events on shutdown", flushed);
+}
+
+
+/// Resolves on Ctrl+C or, on unix, SIGTERM
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for shutdown signal!");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for shutdown signal!")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
 }
@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use prometheus::{
+    exponential_buckets,
+    Histogram,
+    HistogramOpts,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    IntGaugeVec,
+    Opts,
+    proto::MetricFamily,
+    Registry,
+};
+use sqlx::postgres::PgPool;
+
+use crate::database::MAX_CONNECTIONS;
+
+
+const NAMESPACE: &str = "log_ingest";
+
+
+/// Metrics of the service itself, as opposed to those derived from logs. Database pool
+/// metrics are sampled when metrics are gathered.
+pub struct ServiceMetrics {
+    registry: Registry,
+    ingest_records: IntCounter,
+    ingest_bytes: IntCounter,
+    ingest_batches: IntCounter,
+    insert_duration: Histogram,
+    query_duration: HistogramVec,
+    query_rows: HistogramVec,
+    http_errors: IntCounterVec,
+    pool_connections: IntGaugeVec,
+    pool_max_connections: IntGauge,
+    migration_version: IntGauge,
+    pool: Option<PgPool>,
+}
+
+impl Default for ServiceMetrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(NAMESPACE);
+        let histogram_opts = |name: &str, help: &str| HistogramOpts::new(name, help).namespace(NAMESPACE);
+
+        let metrics = Self {
+            ingest_records: IntCounter::with_opts(opts("ingest_records_total", "Logs stored by POST /logs"))
+                .expect("Valid metric"),
+            ingest_bytes: IntCounter::with_opts(opts("ingest_bytes_total", "Bytes received by POST /logs, as given by Content-Length"))
+                .expect("Valid metric"),
+            ingest_batches: IntCounter::with_opts(opts("ingest_batches_total", "Batches stored by POST /logs"))
+                .expect("Valid metric"),
+            insert_duration: Histogram::with_opts(histogram_opts("insert_duration_seconds", "Time taken to insert a batch of logs"))
+                .expect("Valid metric"),
+            query_duration: HistogramVec::new(histogram_opts("query_duration_seconds", "Time taken to run GET /logs queries"), &["mode"])
+                .expect("Valid metric"),
+            query_rows: HistogramVec::new(
+                histogram_opts("query_rows", "Rows returned by GET /logs queries")
+                    .buckets(exponential_buckets(1.0, 10.0, 7).expect("Valid buckets")),
+                &["mode"],
+            ).expect("Valid metric"),
+            http_errors: IntCounterVec::new(opts("http_errors_total", "Error responses, by error"), &["error"])
+                .expect("Valid metric"),
+            pool_connections: IntGaugeVec::new(opts("db_pool_connections", "Open database connections, by state"), &["state"])
+                .expect("Valid metric"),
+            pool_max_connections: IntGauge::with_opts(opts("db_pool_max_connections", "Maximum open database connections"))
+                .expect("Valid metric"),
+            migration_version: IntGauge::with_opts(opts("migration_version", "Version of the latest database migration"))
+                .expect("Valid metric"),
+            registry,
+            pool: None,
+        };
+
+        for collector in [
+            Box::new(metrics.ingest_records.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.ingest_bytes.clone()),
+            Box::new(metrics.ingest_batches.clone()),
+            Box::new(metrics.insert_duration.clone()),
+            Box::new(metrics.query_duration.clone()),
+            Box::new(metrics.query_rows.clone()),
+            Box::new(metrics.http_errors.clone()),
+            Box::new(metrics.pool_connections.clone()),
+            Box::new(metrics.pool_max_connections.clone()),
+            Box::new(metrics.migration_version.clone()),
+        ] {
+            metrics.registry
+                .register(collector)
+                .expect("Metrics are only registered once");
+        }
+
+        metrics
+    }
+}
+
+impl ServiceMetrics {
+    /// Samples the pool behind the application's database connection
+    pub fn with_pool(mut self, pool: PgPool) -> Self {
+        self.pool_max_connections.set(MAX_CONNECTIONS.into());
+        self.pool = Some(pool);
+        self
+    }
+
+    pub fn set_migration_version(&self, version: i64) {
+        self.migration_version.set(version);
+    }
+
+    pub fn record_ingest(&self, records: usize, bytes: Option<u64>, insert_duration: Duration) {
+        self.ingest_batches.inc();
+        self.ingest_records.inc_by(records as u64);
+        self.ingest_bytes.inc_by(bytes.unwrap_or_default());
+        self.insert_duration.observe(insert_duration.as_secs_f64());
+    }
+
+    /// Records a query returning `rows` rows; `mode` is `json` or `stream`
+    pub fn record_query(&self, mode: &str, duration: Duration, rows: u64) {
+        self.query_duration.with_label_values(&[mode]).observe(duration.as_secs_f64());
+        self.query_rows.with_label_values(&[mode]).observe(rows as f64);
+    }
+
+    pub fn record_error(&self, error: &str) {
+        self.http_errors.with_label_values(&[error]).inc();
+    }
+
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        if let Some(pool) = &self.pool {
+            let idle = pool.num_idle() as i64;
+
+            self.pool_connections.with_label_values(&["idle"]).set(idle);
+            self.pool_connections.with_label_values(&["in_use"]).set(i64::from(pool.size()) - idle);
+        }
+
+        self.registry.gather()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{
+        Encoder,
+        TextEncoder,
+    };
+    use sea_orm::{
+        ConnectionTrait,
+        DatabaseBackend,
+        Statement,
+    };
+
+    use crate::{
+        database,
+        testing,
+    };
+    use super::ServiceMetrics;
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_pool_metrics_database() {
+        let config = testing::config(&[]);
+        let pool = database::get_db_pool(&config)
+            .await
+            .unwrap();
+        let db = database::into_db_connection(pool.clone());
+        let metrics = ServiceMetrics::default()
+            .with_pool(pool);
+
+        database::migrate(&config)
+            .await
+            .unwrap();
+        db.execute(Statement::from_string(DatabaseBackend::Postgres, "SELECT 1".to_owned()))
+            .await
+            .unwrap();
+
+        // read from what has been applied, rather than what the binary was built with
+        let version = database::migration_version(&db)
+            .await
+            .unwrap();
+
+        metrics.set_migration_version(version);
+
+        let mut buffer = vec![];
+
+        TextEncoder::new()
+            .encode(&metrics.gather(), &mut buffer)
+            .unwrap();
+
+        let text = String::from_utf8(buffer).unwrap();
+
+        assert!(text.contains("log_ingest_db_pool_connections{state=\"idle\"}"));
+        assert!(text.contains("log_ingest_db_pool_connections{state=\"in_use\"}"));
+        assert!(text.contains("log_ingest_db_pool_max_connections 100"));
+        assert!(text.contains(&format!("log_ingest_migration_version {}", version)));
+        assert_eq!(Some(version), database::MIGRATE.iter().map(|migration| migration.version).max());
+    }
+}
@@ -0,0 +1,25 @@
+use sea_orm::entity::prelude::*;
+
+
+/// A notification of an alert rule waiting to be delivered to one of its webhooks. `payload`
+/// is the JSON posted; `attempts` counts the failed deliveries so far.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "alert_notifications")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    pub tenant_id: String,
+    pub rule_id: i64,
+    pub webhook: String,
+    pub dedupe_key: String,
+    pub payload: Json,
+    pub attempts: i32,
+    pub next_attempt_at: DateTimeWithTimeZone,
+    pub created_at: DateTimeWithTimeZone,
+}
+
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use sea_orm::entity::prelude::*;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use super::{
+    saved_search::parameter_map,
+    SavedSearchModel,
+};
+
+
+pub const STATE_OK: &str = "ok";
+pub const STATE_FIRING: &str = "firing";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertCondition {
+    /// Fires once at least one log matches
+    Any,
+    /// Fires once at least `threshold` logs match
+    Threshold,
+}
+
+impl AlertCondition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertCondition::Any => "any",
+            AlertCondition::Threshold => "threshold",
+        }
+    }
+}
+
+
+/// Alerts when the logs matching a query over the trailing `window_seconds` reach a
+/// threshold, notifying `webhooks` when it starts firing and when it resolves. The query is
+/// the rule's `parameters` applied over those of its saved search, if any.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "alert_rules")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    #[serde(skip)]
+    pub tenant_id: String,
+    pub name: String,
+    pub owner: String,
+    pub saved_search_id: Option<i64>,
+    pub parameters: Json,
+    pub condition: String,
+    pub threshold: i64,
+    pub window_seconds: i64,
+    pub webhooks: Json,
+    pub state: String,
+    pub last_count: Option<i64>,
+    pub last_evaluated_at: Option<DateTimeWithTimeZone>,
+    pub fired_at: Option<DateTimeWithTimeZone>,
+    pub resolved_at: Option<DateTimeWithTimeZone>,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+impl Model {
+    /// The query parameters of the rule, applied over those of `saved_search`
+    pub fn query_parameters(&self, saved_search: Option<&SavedSearchModel>) -> HashMap<String, String> {
+        let parameters = parameter_map(&self.parameters);
+
+        match saved_search {
+            Some(saved_search) => saved_search.query_parameters(parameters),
+            None => parameters,
+        }
+    }
+
+    pub fn webhook_urls(&self) -> Vec<String> {
+        self.webhooks
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|url| url.as_str().map(|url| url.to_string()))
+            .collect()
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,22 @@
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+
+
+/// Record of a `DELETE /logs` request: who removed which logs, and how many
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "deletion_audits")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    pub tenant_id: String,
+    pub actor: String,
+    pub filters: Json,
+    pub rows_deleted: i64,
+    pub created_at: DateTimeWithTimeZone,
+}
+
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
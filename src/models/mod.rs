@@ -1,10 +1,51 @@
 pub use self::log::{
     ActiveModel as LogActiveModel,
+    Column as LogColumn,
     Entity as Log,
     IngestLog,
     Model as LogModel,
     QueryBuilder,
 };
 
+pub use self::alert_notification::{
+    ActiveModel as AlertNotificationActiveModel,
+    Column as AlertNotificationColumn,
+    Entity as AlertNotification,
+    Model as AlertNotificationModel,
+};
+
+pub use self::alert_rule::{
+    ActiveModel as AlertRuleActiveModel,
+    AlertCondition,
+    Column as AlertRuleColumn,
+    Entity as AlertRule,
+    Model as AlertRuleModel,
+    STATE_FIRING,
+    STATE_OK,
+};
+
+pub use self::deletion_audit::{
+    ActiveModel as DeletionAuditActiveModel,
+    Entity as DeletionAudit,
+};
+
+pub use self::pattern::{
+    ActiveModel as PatternActiveModel,
+    Column as PatternColumn,
+    Entity as Pattern,
+};
+
+pub use self::saved_search::{
+    ActiveModel as SavedSearchActiveModel,
+    Column as SavedSearchColumn,
+    Entity as SavedSearch,
+    Model as SavedSearchModel,
+};
+
 
+mod alert_notification;
+mod alert_rule;
+mod deletion_audit;
 mod log;
+mod pattern;
+mod saved_search;
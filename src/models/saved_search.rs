@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+
+
+/// A named `GET /logs` query, stored as the query parameters it was made with
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "saved_searches")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    #[serde(skip)]
+    pub tenant_id: String,
+    pub name: String,
+    pub owner: String,
+    pub parameters: Json,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+impl Model {
+    /// The stored query parameters, with `overrides` taking precedence
+    pub fn query_parameters(&self, overrides: HashMap<String, String>) -> HashMap<String, String> {
+        let mut parameters = parameter_map(&self.parameters);
+
+        parameters.extend(overrides);
+        parameters
+    }
+}
+
+
+/// Query parameters stored as a JSON object of strings
+pub(super) fn parameter_map(parameters: &Json) -> HashMap<String, String> {
+    parameters
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_string())))
+        .collect()
+}
+
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
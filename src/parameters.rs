@@ -10,10 +10,35 @@ use std::{
 use axum::http::StatusCode;
 use sea_orm::Value;
 
+use crate::{
+    export::ExportFormat,
+    models::LogModel,
+    query_language::{
+        Expression,
+        QueryError,
+    },
+    time_expression,
+};
+
+
+const CONTEXT_PREFIX: &str = "context.";
+const FIELDS_PARAMETER: &str = "fields";
+const FLATTEN_PARAMETER: &str = "flatten";
+const FORMAT_PARAMETER: &str = "format";
+const SORT_PARAMETER: &str = "sort";
+const QUERY_PARAMETER: &str = "q";
+const FIELD_PARAMETER: &str = "field";
+const STATS_PARAMETER: &str = "stats";
+const GROUP_BY_PARAMETER: &str = "group_by";
+const INTERVAL_PARAMETER: &str = "interval";
+const FROM_PARAMETER: &str = "from";
+const TO_PARAMETER: &str = "to";
+const TIMESTAMP_FIELD: &str = "timestamp";
+
 
 /// "Guesses" a value type from a string parameter
 /// by bruteforcing the possible types.
-struct Type {
+pub struct Type {
     value: String,
 }
 
@@ -43,9 +68,9 @@ impl Type {
     }
 }
 
-impl Into<Value> for Type {
-    fn into(self) -> Value {
-        self.into_value()
+impl From<Type> for Value {
+    fn from(value: Type) -> Self {
+        value.into_value()
     }
 }
 
@@ -71,7 +96,7 @@ impl OperatorParserError {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Operator {
     Contains,
     Eq,
@@ -110,8 +135,8 @@ impl Display for FilterParameterError {
     }
 }
 
-impl Into<StatusCode> for FilterParameterError {
-    fn into(self) -> StatusCode {
+impl From<FilterParameterError> for StatusCode {
+    fn from(_: FilterParameterError) -> Self {
         StatusCode::BAD_REQUEST
     }
 }
@@ -124,24 +149,46 @@ impl FilterParameterError {
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct FilterParameter {
-    pub field: String,
+    pub field: FieldSelector,
     pub op: Operator,
     pub value: Value,
 }
 
 impl FilterParameter {
+    /// Parses filters from query parameters. Values of `timestamp` filters are time expressions
+    /// (see `time_expression::resolve`), resolved against the current time, and values of text
+    /// columns are always strings.
     pub fn from_hashmap(hashmap: HashMap<String, String>) -> Result<Vec<Self>, FilterParameterError> {
+        let now = chrono::Utc::now();
+
         hashmap
             .into_iter()
             .map(|(key, value)| {
-                Self::parse(
-                    key,
-                    Type::from(value)
-                )
+                let mut parameter = Self::parse(
+                    key.clone(),
+                    Type::from(value.clone())
+                )?;
+
+                if parameter.field == FieldSelector::Column(TIMESTAMP_FIELD.to_string()) && parameter.op != Operator::Contains {
+                    parameter.value = Self::timestamp_value(&key, &value, now)?;
+                } else if parameter.field.is_text() {
+                    parameter.value = Value::String(Some(Box::new(value)));
+                }
+
+                Ok(parameter)
             }).collect()
     }
 
+    fn timestamp_value(key: &str, expression: &str, now: chrono::DateTime<chrono::Utc>) -> Result<Value, FilterParameterError> {
+        time_expression::resolve(expression, now)
+            .map(|timestamp| Value::ChronoDateTimeUtc(Some(Box::new(timestamp))))
+            .ok_or_else(|| FilterParameterError::from(format!("{}={}", key, expression)))
+    }
+
+    /// Parses a single `filter[field][op]` key. The field is resolved with
+    /// `FieldSelector::resolve`, so names that could not be safely quoted are rejected.
     pub fn parse<I: Into<Value>>(filter: String, value: I) -> Result<Self, FilterParameterError> {
         let split: Vec<&str> = filter.split("[")
             .map(|s| 
@@ -159,9 +206,12 @@ impl FilterParameter {
                 return Err(FilterParameterError::from(filter));
             }
 
+            let field = FieldSelector::resolve(field)
+                .ok_or_else(|| FilterParameterError::from(filter.clone()))?;
+
             Ok(Self {
-                field: field.to_string(),
-                op: op,
+                field,
+                op,
                 value: value.into(),
             })
         } else { // invalid filter
@@ -171,6 +221,360 @@ impl FilterParameter {
 }
 
 
+/// A value to select: a column, or a path into `context` such as `context.http.status`
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldSelector {
+    Column(String),
+    Context(Vec<String>),
+}
+
+impl FieldSelector {
+    /// The name the value is returned under
+    pub fn name(&self) -> String {
+        match self {
+            Self::Column(column) => column.clone(),
+            Self::Context(path) => format!("{}{}", CONTEXT_PREFIX, path.join(".")),
+        }
+    }
+
+    /// Whether this is a text column, which values must be compared to as strings
+    pub fn is_text(&self) -> bool {
+        matches!(self, Self::Column(column) if LogModel::text_columns().contains(&column.as_str()))
+    }
+
+    /// Whether values of this field can be summarised: numeric columns, or any context path,
+    /// whose non-numeric values are skipped
+    pub fn is_numeric(&self) -> bool {
+        match self {
+            Self::Column(column) => LogModel::numeric_columns().contains(&column.as_str()),
+            Self::Context(_) => true,
+        }
+    }
+
+    /// Resolves a field name where the `context.` prefix is optional: columns by name, and
+    /// anything else as a dotted context path
+    pub fn resolve(name: &str) -> Option<Self> {
+        let name = name.trim();
+        let name = name.strip_prefix(CONTEXT_PREFIX).unwrap_or(name);
+        let valid = name
+            .split('.')
+            .all(|segment| !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'));
+
+        if !valid || name == "context" {
+            None
+        } else if LogModel::columns().contains(&name) {
+            Some(Self::Column(name.to_string()))
+        } else {
+            Some(Self::Context(name.split('.').map(|segment| segment.to_string()).collect()))
+        }
+    }
+}
+
+impl FromStr for FieldSelector {
+    type Err = QueryParametersError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::resolve(s)
+            .ok_or_else(|| QueryParametersError::Field(s.trim().to_string()))
+    }
+}
+
+
+/// A sort key: `field` ascending, or descending with a `-` prefix
+#[derive(Clone, Debug, PartialEq)]
+pub struct SortField {
+    pub field: FieldSelector,
+    pub descending: bool,
+}
+
+impl SortField {
+    pub fn asc(field: FieldSelector) -> Self {
+        Self {
+            field,
+            descending: false,
+        }
+    }
+}
+
+impl FromStr for SortField {
+    type Err = QueryParametersError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (field, descending) = match s.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (s.strip_prefix('+').unwrap_or(s), false),
+        };
+
+        // a second sign would otherwise be read as part of a context key
+        if field.starts_with(['-', '+']) {
+            return Err(QueryParametersError::Field(s.to_string()));
+        }
+
+        Ok(Self {
+            field: field.parse()?,
+            descending,
+        })
+    }
+}
+
+
+#[derive(Debug)]
+pub enum QueryParametersError {
+    Filter(FilterParameterError),
+    Field(String),
+    Flag(String),
+    Format(String),
+    Interval(String),
+    Missing(String),
+    NotNumeric(String),
+    Query(QueryError),
+    Statistic(String),
+}
+
+impl Error for QueryParametersError {}
+
+impl Display for QueryParametersError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Filter(e) => write!(f, "{}", e),
+            Self::Field(field) => write!(f, "Invalid field: {}", field),
+            Self::Flag(flag) => write!(f, "Invalid boolean parameter: {}", flag),
+            Self::Format(format) => write!(f, "Invalid format: {}", format),
+            Self::Interval(interval) => write!(f, "Invalid interval: {}", interval),
+            Self::Missing(parameter) => write!(f, "Missing parameter: {}", parameter),
+            Self::NotNumeric(field) => write!(f, "Not a numeric field: {}", field),
+            Self::Query(e) => write!(f, "{}", e),
+            Self::Statistic(statistic) => write!(f, "Invalid statistic: {}", statistic),
+        }
+    }
+}
+
+impl From<FilterParameterError> for QueryParametersError {
+    fn from(e: FilterParameterError) -> Self {
+        Self::Filter(e)
+    }
+}
+
+impl From<QueryError> for QueryParametersError {
+    fn from(e: QueryError) -> Self {
+        Self::Query(e)
+    }
+}
+
+
+/// A summary of a numeric field
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statistic {
+    Count,
+    Avg,
+    Sum,
+    Min,
+    Max,
+    /// Continuous percentile, from 0 to 100 exclusive, e.g. `p95`
+    Percentile(f64),
+}
+
+impl Statistic {
+    /// The name the statistic is returned under
+    pub fn name(&self) -> String {
+        match self {
+            Self::Count => "count".to_string(),
+            Self::Avg => "avg".to_string(),
+            Self::Sum => "sum".to_string(),
+            Self::Min => "min".to_string(),
+            Self::Max => "max".to_string(),
+            Self::Percentile(percentile) => format!("p{}", percentile),
+        }
+    }
+
+    fn defaults() -> Vec<Self> {
+        vec![
+            Self::Count,
+            Self::Avg,
+            Self::Sum,
+            Self::Min,
+            Self::Max,
+            Self::Percentile(50.0),
+            Self::Percentile(95.0),
+            Self::Percentile(99.0),
+        ]
+    }
+}
+
+impl FromStr for Statistic {
+    type Err = QueryParametersError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "count" => Ok(Self::Count),
+            "avg" => Ok(Self::Avg),
+            "sum" => Ok(Self::Sum),
+            "min" => Ok(Self::Min),
+            "max" => Ok(Self::Max),
+            percentile => percentile
+                .strip_prefix('p')
+                .and_then(|percentile| percentile.parse::<f64>().ok())
+                .filter(|percentile| *percentile > 0.0 && *percentile < 100.0)
+                .map(Self::Percentile)
+                .ok_or_else(|| QueryParametersError::Statistic(s.to_string())),
+        }
+    }
+}
+
+
+/// Parameters of numeric statistics: the field summarised, the statistics and how rows are
+/// grouped, alongside the usual query parameters selecting logs
+#[derive(Clone, Debug)]
+pub struct StatsParameters {
+    pub field: FieldSelector,
+    pub statistics: Vec<Statistic>,
+    pub group_by: Vec<FieldSelector>,
+    /// Width of time buckets in seconds; no bucketing when unset
+    pub interval: Option<i64>,
+    pub query: QueryParameters,
+}
+
+impl StatsParameters {
+    pub fn from_hashmap(mut hashmap: HashMap<String, String>) -> Result<Self, QueryParametersError> {
+        let field = hashmap.remove(FIELD_PARAMETER)
+            .ok_or_else(|| QueryParametersError::Missing(FIELD_PARAMETER.to_string()))?;
+        let field = FieldSelector::resolve(&field)
+            .ok_or(QueryParametersError::Field(field))?;
+
+        if !field.is_numeric() {
+            return Err(QueryParametersError::NotNumeric(field.name()));
+        }
+
+        let statistics = match hashmap.remove(STATS_PARAMETER) {
+            Some(statistics) => statistics
+                .split(',')
+                .filter(|statistic| !statistic.trim().is_empty())
+                .map(|statistic| statistic.parse::<Statistic>())
+                .collect::<Result<Vec<Statistic>, QueryParametersError>>()?,
+            None => vec![],
+        };
+        let statistics = if statistics.is_empty() {
+            Statistic::defaults()
+        } else {
+            statistics
+        };
+        let group_by = match hashmap.remove(GROUP_BY_PARAMETER) {
+            Some(group_by) => group_by
+                .split(',')
+                .filter(|field| !field.trim().is_empty())
+                .map(|field| FieldSelector::resolve(field).ok_or_else(|| QueryParametersError::Field(field.to_string())))
+                .collect::<Result<Vec<FieldSelector>, QueryParametersError>>()?,
+            None => vec![],
+        };
+        let interval = match hashmap.remove(INTERVAL_PARAMETER) {
+            Some(interval) => Some(
+                time_expression::interval_seconds(&interval)
+                    .ok_or(QueryParametersError::Interval(interval))?
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            field,
+            statistics,
+            group_by,
+            interval,
+            query: QueryParameters::from_hashmap(hashmap)?,
+        })
+    }
+}
+
+
+/// Parameters accepted when querying logs: filters plus the parameters which shape the response
+#[derive(Clone, Debug, Default)]
+pub struct QueryParameters {
+    pub filters: Vec<FilterParameter>,
+    pub fields: Vec<FieldSelector>,
+    pub flatten: bool,
+    /// Defaults to oldest first
+    pub sort: Vec<SortField>,
+    /// Parsed `q=` query, applied alongside the filters
+    pub query: Option<Expression>,
+    /// Export format; JSON when unset
+    pub format: Option<ExportFormat>,
+}
+
+impl QueryParameters {
+    pub fn from_hashmap(mut hashmap: HashMap<String, String>) -> Result<Self, QueryParametersError> {
+        let fields = match hashmap.remove(FIELDS_PARAMETER) {
+            Some(fields) => fields
+                .split(',')
+                .filter(|field| !field.trim().is_empty())
+                .map(|field| field.parse::<FieldSelector>())
+                .collect::<Result<Vec<FieldSelector>, QueryParametersError>>()?,
+            None => vec![],
+        };
+        let flatten = match hashmap.remove(FLATTEN_PARAMETER) {
+            Some(flatten) => parse_flag(FLATTEN_PARAMETER, &flatten)?,
+            None => false,
+        };
+        let sort = match hashmap.remove(SORT_PARAMETER) {
+            Some(sort) => sort
+                .split(',')
+                .filter(|field| !field.trim().is_empty())
+                .map(|field| field.parse::<SortField>())
+                .collect::<Result<Vec<SortField>, QueryParametersError>>()?,
+            None => vec![],
+        };
+        let sort = if sort.is_empty() {
+            vec![SortField::asc(FieldSelector::Column("timestamp".to_string()))]
+        } else {
+            sort
+        };
+        let format = match hashmap.remove(FORMAT_PARAMETER) {
+            Some(format) => Some(format.parse::<ExportFormat>().map_err(QueryParametersError::Format)?),
+            None => None,
+        };
+        let query = match hashmap.remove(QUERY_PARAMETER) {
+            Some(query) if !query.trim().is_empty() => Some(Expression::parse(&query)?),
+            _ => None,
+        };
+
+        let now = chrono::Utc::now();
+        let mut range = vec![];
+
+        // `from` and `to` are shorthand for inclusive timestamp bounds
+        for (parameter, op) in [(FROM_PARAMETER, Operator::Gte), (TO_PARAMETER, Operator::Lte)] {
+            if let Some(expression) = hashmap.remove(parameter) {
+                range.push(FilterParameter {
+                    field: FieldSelector::Column(TIMESTAMP_FIELD.to_string()),
+                    op,
+                    value: FilterParameter::timestamp_value(parameter, &expression, now)?,
+                });
+            }
+        }
+
+        let mut filters = FilterParameter::from_hashmap(hashmap)?;
+
+        filters.extend(range);
+
+        Ok(Self {
+            filters,
+            fields,
+            flatten,
+            sort,
+            query,
+            format,
+        })
+    }
+}
+
+
+fn parse_flag(name: &str, value: &str) -> Result<bool, QueryParametersError> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(QueryParametersError::Flag(format!("{}={}", name, value))),
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -184,10 +588,41 @@ mod tests {
         let filter_param = FilterParameter::parse(filter, value)
             .unwrap();
 
-        assert_eq!(filter_param.field, "foo");
+        assert_eq!(filter_param.field, FieldSelector::Context(vec!["foo".to_string()]));
         assert_eq!(filter_param.op, Operator::Eq);
     }
 
+    #[test]
+    fn test_text_column_values() {
+        let hashmap = HashMap::from([
+            ("filter[span_id][eq]".to_string(), "1234567890123456".to_string()),
+            ("filter[level][eq]".to_string(), "3".to_string()),
+        ]);
+
+        let filters = FilterParameter::from_hashmap(hashmap)
+            .unwrap();
+
+        for filter in filters {
+            match filter.field.name().as_str() {
+                "span_id" => assert_eq!(filter.value, Value::String(Some(Box::new("1234567890123456".to_string())))),
+                _ => assert_eq!(filter.value, Value::BigInt(Some(3))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_filter_parameter_field_rejected() {
+        let value = Value::String(Some(Box::new("1".to_string())));
+
+        assert!(FilterParameter::parse("filter[x' OR true --][eq]".to_string(), value.clone()).is_err());
+        assert!(FilterParameter::parse("filter[context][eq]".to_string(), value.clone()).is_err());
+        assert!(FilterParameter::parse("filter[a..b][eq]".to_string(), value.clone()).is_err());
+
+        let hashmap = HashMap::from([("filter[x' OR true --][eq]".to_string(), "1".to_string())]);
+
+        assert!(matches!(QueryParameters::from_hashmap(hashmap), Err(QueryParametersError::Filter(_))));
+    }
+
     #[test]
     fn test_failing_filter_parameter_parse() {
         let filter1 = "falter[foo][eq]".to_string();
@@ -200,4 +635,137 @@ mod tests {
         assert!(filter_param1.is_err());
         assert!(filter_param2.is_err());
     }
+
+    #[test]
+    fn test_query_parameters_parse() {
+        let hashmap = HashMap::from([
+            ("filter[level][gte]".to_string(), "3".to_string()),
+            ("fields".to_string(), "id,message,context.http.status,http.method".to_string()),
+            ("flatten".to_string(), "true".to_string()),
+        ]);
+
+        let parameters = QueryParameters::from_hashmap(hashmap)
+            .unwrap();
+
+        assert_eq!(parameters.filters.len(), 1);
+        assert_eq!(
+            parameters.fields,
+            vec![
+                FieldSelector::Column("id".to_string()),
+                FieldSelector::Column("message".to_string()),
+                FieldSelector::Context(vec!["http".to_string(), "status".to_string()]),
+                FieldSelector::Context(vec!["http".to_string(), "method".to_string()]),
+            ],
+        );
+        assert_eq!(parameters.fields[2].name(), "context.http.status");
+        // the context prefix is optional
+        assert_eq!(parameters.fields[3].name(), "context.http.method");
+        assert!(parameters.flatten);
+        assert_eq!(parameters.sort, vec![SortField::asc(FieldSelector::Column("timestamp".to_string()))]);
+    }
+
+    #[test]
+    fn test_timestamp_expressions() {
+        let hashmap = HashMap::from([
+            ("filter[timestamp][gte]".to_string(), "2022-12-25".to_string()),
+            ("filter[timestamp][lt]".to_string(), "1671975900".to_string()),
+            ("filter[count][eq]".to_string(), "1671975900".to_string()),
+            ("from".to_string(), "now-15m".to_string()),
+        ]);
+
+        let mut filters = QueryParameters::from_hashmap(hashmap)
+            .unwrap()
+            .filters;
+
+        filters.sort_by_key(|filter| format!("{}{:?}", filter.field.name(), filter.op));
+
+        let date = chrono::DateTime::parse_from_rfc3339("2022-12-25T00:00:00Z").unwrap().with_timezone(&chrono::Utc);
+        let epoch = chrono::DateTime::parse_from_rfc3339("2022-12-25T13:45:00Z").unwrap().with_timezone(&chrono::Utc);
+
+        assert_eq!(filters[0].value, Value::BigInt(Some(1671975900)));
+        assert_eq!(filters.len(), 4);
+        assert!(filters.iter().any(|filter| filter.op == Operator::Gte && filter.value == Value::ChronoDateTimeUtc(Some(Box::new(date)))));
+        assert!(filters.iter().any(|filter| filter.op == Operator::Lt && filter.value == Value::ChronoDateTimeUtc(Some(Box::new(epoch)))));
+        assert_eq!(filters.iter().filter(|filter| filter.field == FieldSelector::Column("timestamp".to_string()) && filter.op == Operator::Gte).count(), 2);
+
+        let invalid = HashMap::from([("filter[timestamp][gte]".to_string(), "last tuesday".to_string())]);
+
+        assert!(QueryParameters::from_hashmap(invalid).is_err());
+        assert!(QueryParameters::from_hashmap(HashMap::from([("to".to_string(), "soon".to_string())])).is_err());
+    }
+
+    #[test]
+    fn test_sort_parse() {
+        let hashmap = HashMap::from([("sort".to_string(), "-timestamp,level,+context.latency_ms,-region".to_string())]);
+        let sort = QueryParameters::from_hashmap(hashmap)
+            .unwrap()
+            .sort;
+
+        assert_eq!(
+            sort,
+            vec![
+                SortField { field: FieldSelector::Column("timestamp".to_string()), descending: true },
+                SortField::asc(FieldSelector::Column("level".to_string())),
+                SortField::asc(FieldSelector::Context(vec!["latency_ms".to_string()])),
+                SortField { field: FieldSelector::Context(vec!["region".to_string()]), descending: true },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_query_parameters_parse_fail() {
+        let invalid = |key: &str, value: &str| {
+            QueryParameters::from_hashmap(HashMap::from([(key.to_string(), value.to_string())]))
+                .is_err()
+        };
+
+        assert!(invalid("fields", "context.a'b"));
+        assert!(invalid("fields", "context."));
+        assert!(invalid("fields", "context"));
+        assert!(invalid("fields", "http..status"));
+        assert!(invalid("flatten", "maybe"));
+        assert!(invalid("sort", "--timestamp"));
+        assert!(invalid("sort", "-+level"));
+        assert!(invalid("filter[level][between]", "1"));
+    }
+
+    #[test]
+    fn test_stats_parameters_parse() {
+        let hashmap = HashMap::from([
+            ("field".to_string(), "latency_ms".to_string()),
+            ("stats".to_string(), "p99.9,avg".to_string()),
+            ("group_by".to_string(), "service,context.region".to_string()),
+            ("interval".to_string(), "5m".to_string()),
+            ("filter[level][gte]".to_string(), "3".to_string()),
+        ]);
+        let parameters = StatsParameters::from_hashmap(hashmap)
+            .unwrap();
+
+        assert_eq!(parameters.field, FieldSelector::Context(vec!["latency_ms".to_string()]));
+        assert_eq!(parameters.statistics, vec![Statistic::Percentile(99.9), Statistic::Avg]);
+        assert_eq!(parameters.statistics[0].name(), "p99.9");
+        assert_eq!(
+            parameters.group_by,
+            vec![FieldSelector::Column("service".to_string()), FieldSelector::Context(vec!["region".to_string()])],
+        );
+        assert_eq!(parameters.interval, Some(300));
+        assert_eq!(parameters.query.filters.len(), 1);
+
+        let invalid = |key: &str, value: &str| {
+            let mut hashmap = HashMap::from([("field".to_string(), "latency_ms".to_string())]);
+
+            hashmap.insert(key.to_string(), value.to_string());
+            StatsParameters::from_hashmap(hashmap).is_err()
+        };
+
+        assert!(invalid("stats", "p100"));
+        assert!(invalid("stats", "median"));
+        assert!(invalid("interval", "1M"));
+        assert!(invalid("group_by", "context"));
+        assert!(invalid("field", "a'b"));
+        assert!(invalid("field", "message"));
+        assert!(invalid("field", "timestamp"));
+        assert!(!invalid("field", "level"));
+        assert!(StatsParameters::from_hashmap(HashMap::new()).is_err());
+    }
 }
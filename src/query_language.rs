@@ -0,0 +1,631 @@
+use std::{
+    error::Error,
+    fmt::{
+        Display,
+        Formatter,
+    },
+};
+
+use sea_orm::Value;
+
+use crate::{
+    parameters::{
+        FieldSelector,
+        Operator,
+        Type,
+    },
+    time_expression,
+};
+
+
+const TIMESTAMP_FIELD: &str = "timestamp";
+const CONTEXT_PREFIX: &str = "context.";
+/// Field searched by terms without a field, e.g. `timeout`
+const DEFAULT_FIELD: &str = "message";
+/// Longest query accepted, in characters
+const MAX_QUERY_LENGTH: usize = 4096;
+/// Deepest nesting of parentheses and `NOT`s; parsing and compiling recurse once per level
+const MAX_DEPTH: usize = 64;
+/// Most `AND`s and `OR`s in a query, each of which nests the expression a level deeper
+const MAX_OPERATORS: usize = 256;
+
+
+#[derive(Debug, PartialEq)]
+pub struct QueryError {
+    /// 1-based character position of the offending token
+    pub position: usize,
+    pub message: String,
+}
+
+impl Error for QueryError {}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid query at position {}: {}", self.position, self.message)
+    }
+}
+
+impl QueryError {
+    fn new<S: Into<String>>(position: usize, message: S) -> Self {
+        Self {
+            position: position + 1,
+            message: message.into(),
+        }
+    }
+}
+
+
+/// A single comparison, e.g. `level:>=4`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub field: FieldSelector,
+    pub op: Operator,
+    pub value: Value,
+}
+
+
+/// Parsed `q=` query
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Comparison(Comparison),
+}
+
+impl Expression {
+    /// Parses a Lucene-like query:
+    ///
+    /// * `field:value`, `field:"quoted value"` and `field:*text*` (contains)
+    /// * `field:>=value` with `>`, `>=`, `<` or `<=`
+    /// * `field:[low TO high]`, inclusive, or `{low TO high}`, exclusive; `*` leaves a bound open
+    /// * bare terms such as `timeout` or `"health check"` search `message`
+    /// * `AND`, `OR`, `NOT` (or a `-` prefix) and parentheses; `AND` binds tighter than `OR`
+    ///   and is implied between adjacent terms
+    ///
+    /// Fields which aren't columns are context paths, with nested keys separated by dots.
+    ///
+    /// Queries are limited to 4096 characters, 64 levels of parentheses and `NOT`, and 256
+    /// `AND`s and `OR`s.
+    pub fn parse(query: &str) -> Result<Self, QueryError> {
+        if query.chars().count() > MAX_QUERY_LENGTH {
+            return Err(QueryError::new(MAX_QUERY_LENGTH, format!("query is longer than {} characters", MAX_QUERY_LENGTH)));
+        }
+
+        let tokens = Lexer::new(query).tokenize()?;
+        let mut parser = Parser {
+            tokens,
+            index: 0,
+            end: query.chars().count(),
+            now: chrono::Utc::now(),
+            depth: 0,
+            operators: 0,
+        };
+
+        let expression = parser.or()?;
+
+        match parser.peek() {
+            None => Ok(expression),
+            Some((token, position)) => Err(QueryError::new(*position, format!("unexpected {}", token))),
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    Colon,
+    Minus,
+    Compare(Operator),
+    Word(String),
+    Quoted(String),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::LBracket => write!(f, "'['"),
+            Token::RBracket => write!(f, "']'"),
+            Token::LBrace => write!(f, "'{{'"),
+            Token::RBrace => write!(f, "'}}'"),
+            Token::Colon => write!(f, "':'"),
+            Token::Minus => write!(f, "'-'"),
+            Token::Compare(op) => write!(f, "comparison {:?}", op),
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Quoted(quoted) => write!(f, "\"{}\"", quoted),
+        }
+    }
+}
+
+
+struct Lexer {
+    chars: Vec<char>,
+    index: usize,
+}
+
+impl Lexer {
+    fn new(query: &str) -> Self {
+        Self {
+            chars: query.chars().collect(),
+            index: 0,
+        }
+    }
+
+    fn tokenize(mut self) -> Result<Vec<(Token, usize)>, QueryError> {
+        let mut tokens: Vec<(Token, usize)> = vec![];
+
+        while self.index < self.chars.len() {
+            let start = self.index;
+            let c = self.chars[self.index];
+            // values may contain colons, e.g. timestamps
+            let value_position = matches!(
+                tokens.last(),
+                Some((Token::Colon | Token::Compare(_) | Token::LBracket | Token::LBrace, _))
+            ) || matches!(tokens.last(), Some((Token::Word(word), _)) if word == "TO");
+
+            let token = match c {
+                c if c.is_whitespace() => {
+                    self.index += 1;
+                    continue;
+                },
+                '(' => self.single(Token::LParen),
+                ')' => self.single(Token::RParen),
+                '[' => self.single(Token::LBracket),
+                ']' => self.single(Token::RBracket),
+                '{' => self.single(Token::LBrace),
+                '}' => self.single(Token::RBrace),
+                ':' => self.single(Token::Colon),
+                '-' if !value_position => self.single(Token::Minus),
+                '>' | '<' if matches!(tokens.last(), Some((Token::Colon, _))) => {
+                    self.index += 1;
+
+                    let inclusive = self.chars.get(self.index) == Some(&'=');
+
+                    if inclusive {
+                        self.index += 1;
+                    }
+
+                    Token::Compare(match (c, inclusive) {
+                        ('>', false) => Operator::Gt,
+                        ('>', true) => Operator::Gte,
+                        ('<', false) => Operator::Lt,
+                        _ => Operator::Lte,
+                    })
+                },
+                '"' => self.quoted()?,
+                _ => self.word(value_position),
+            };
+
+            tokens.push((token, start));
+        }
+
+        Ok(tokens)
+    }
+
+    fn single(&mut self, token: Token) -> Token {
+        self.index += 1;
+        token
+    }
+
+    fn quoted(&mut self) -> Result<Token, QueryError> {
+        let start = self.index;
+        let mut value = String::new();
+
+        self.index += 1;
+
+        while let Some(c) = self.chars.get(self.index) {
+            self.index += 1;
+
+            match c {
+                '"' => return Ok(Token::Quoted(value)),
+                '\\' => match self.chars.get(self.index) {
+                    Some(escaped) => {
+                        value.push(*escaped);
+                        self.index += 1;
+                    },
+                    None => break,
+                },
+                c => value.push(*c),
+            }
+        }
+
+        Err(QueryError::new(start, "unterminated quoted string"))
+    }
+
+    fn word(&mut self, allow_colon: bool) -> Token {
+        let mut word = String::new();
+
+        while let Some(c) = self.chars.get(self.index) {
+            let delimiter = c.is_whitespace() || "()[]{}\"".contains(*c) || (*c == ':' && !allow_colon);
+
+            if delimiter {
+                break;
+            }
+
+            word.push(*c);
+            self.index += 1;
+        }
+
+        Token::Word(word)
+    }
+}
+
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+    end: usize,
+    now: chrono::DateTime<chrono::Utc>,
+    /// Parentheses and `NOT`s currently open
+    depth: usize,
+    /// `AND`s and `OR`s so far
+    operators: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&(Token, usize)> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<(Token, usize)> {
+        let token = self.tokens.get(self.index).cloned();
+
+        self.index += 1;
+        token
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some((Token::Word(word), _)) if word == keyword)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), QueryError> {
+        match self.next() {
+            Some((token, _)) if token == expected => Ok(()),
+            Some((token, position)) => Err(QueryError::new(position, format!("expected {}, found {}", expected, token))),
+            None => Err(QueryError::new(self.end, format!("expected {}", expected))),
+        }
+    }
+
+    /// Opens a level of nesting at `position`
+    fn enter(&mut self, position: usize) -> Result<(), QueryError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(QueryError::new(position, format!("query is nested more than {} levels deep", MAX_DEPTH)));
+        }
+
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Counts an `AND` or `OR` at `position`
+    fn operator(&mut self, position: usize) -> Result<(), QueryError> {
+        if self.operators >= MAX_OPERATORS {
+            return Err(QueryError::new(position, format!("query has more than {} AND and OR operators", MAX_OPERATORS)));
+        }
+
+        self.operators += 1;
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.peek()
+            .map(|(_, position)| *position)
+            .unwrap_or(self.end)
+    }
+
+    fn or(&mut self) -> Result<Expression, QueryError> {
+        let mut expression = self.and()?;
+
+        while self.peek_keyword("OR") {
+            self.operator(self.position())?;
+            self.next();
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression, QueryError> {
+        let mut expression = self.not()?;
+
+        loop {
+            let position = self.position();
+
+            if self.peek_keyword("AND") {
+                self.next();
+            } else if matches!(self.peek(), None | Some((Token::RParen, _))) || self.peek_keyword("OR") {
+                break;
+            }
+
+            self.operator(position)?;
+
+            expression = Expression::And(Box::new(expression), Box::new(self.not()?));
+        }
+
+        Ok(expression)
+    }
+
+    fn not(&mut self) -> Result<Expression, QueryError> {
+        if self.peek_keyword("NOT") || matches!(self.peek(), Some((Token::Minus, _))) {
+            self.enter(self.position())?;
+            self.next();
+
+            let expression = Expression::Not(Box::new(self.not()?));
+
+            self.depth -= 1;
+
+            return Ok(expression);
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expression, QueryError> {
+        match self.next() {
+            Some((Token::LParen, position)) => {
+                self.enter(position)?;
+
+                let expression = self.or()?;
+
+                self.expect(Token::RParen)?;
+                self.depth -= 1;
+
+                Ok(expression)
+            },
+            Some((Token::Word(word), position)) if ["AND", "OR", "TO"].contains(&word.as_str()) => {
+                Err(QueryError::new(position, format!("unexpected {}", word)))
+            },
+            Some((Token::Word(word), position)) => {
+                if matches!(self.peek(), Some((Token::Colon, _))) {
+                    self.next();
+
+                    let field = Self::field(&word, position)?;
+
+                    self.value(field)
+                } else {
+                    self.bare_term(word, position, false)
+                }
+            },
+            Some((Token::Quoted(quoted), position)) => self.bare_term(quoted, position, true),
+            Some((token, position)) => Err(QueryError::new(position, format!("unexpected {}", token))),
+            None => Err(QueryError::new(self.end, "unexpected end of query")),
+        }
+    }
+
+    /// A term without a field searches the message
+    fn bare_term(&mut self, term: String, position: usize, quoted: bool) -> Result<Expression, QueryError> {
+        let field = FieldSelector::Column(DEFAULT_FIELD.to_string());
+        let term = if quoted { term } else { term.trim_matches('*').to_string() };
+
+        if term.is_empty() {
+            return Err(QueryError::new(position, "empty search term"));
+        }
+
+        Ok(Expression::Comparison(Comparison {
+            field,
+            op: Operator::Contains,
+            value: Value::String(Some(Box::new(term))),
+        }))
+    }
+
+    fn field(name: &str, position: usize) -> Result<FieldSelector, QueryError> {
+        FieldSelector::resolve(name)
+            .ok_or_else(|| QueryError::new(position, format!("invalid field '{}'", name.strip_prefix(CONTEXT_PREFIX).unwrap_or(name))))
+    }
+
+    fn value(&mut self, field: FieldSelector) -> Result<Expression, QueryError> {
+        match self.next() {
+            Some((Token::Compare(op), _)) => {
+                let (value, position, quoted) = self.literal()?;
+
+                Ok(self.comparison(field, op, &value, position, quoted)?)
+            },
+            Some((Token::LBracket, _)) => self.range(field, true),
+            Some((Token::LBrace, _)) => self.range(field, false),
+            Some((Token::Word(word), position)) if word.len() > 2 && word.starts_with('*') && word.ends_with('*') => {
+                self.comparison(field, Operator::Contains, &word[1..word.len() - 1], position, true)
+            },
+            Some((Token::Word(word), position)) => self.comparison(field, Operator::Eq, &word, position, false),
+            Some((Token::Quoted(quoted), position)) => self.comparison(field, Operator::Eq, &quoted, position, true),
+            Some((token, position)) => Err(QueryError::new(position, format!("expected a value, found {}", token))),
+            None => Err(QueryError::new(self.end, "expected a value")),
+        }
+    }
+
+    fn literal(&mut self) -> Result<(String, usize, bool), QueryError> {
+        match self.next() {
+            Some((Token::Word(word), position)) => Ok((word, position, false)),
+            Some((Token::Quoted(quoted), position)) => Ok((quoted, position, true)),
+            Some((token, position)) => Err(QueryError::new(position, format!("expected a value, found {}", token))),
+            None => Err(QueryError::new(self.end, "expected a value")),
+        }
+    }
+
+    fn range(&mut self, field: FieldSelector, lower_inclusive: bool) -> Result<Expression, QueryError> {
+        let (lower, lower_position, lower_quoted) = self.literal()?;
+
+        match self.next() {
+            Some((Token::Word(word), _)) if word == "TO" => {},
+            Some((token, position)) => return Err(QueryError::new(position, format!("expected TO, found {}", token))),
+            None => return Err(QueryError::new(self.end, "expected TO")),
+        }
+
+        let (upper, upper_position, upper_quoted) = self.literal()?;
+        let upper_inclusive = match self.next() {
+            Some((Token::RBracket, _)) => true,
+            Some((Token::RBrace, _)) => false,
+            Some((token, position)) => return Err(QueryError::new(position, format!("expected ']' or '}}', found {}", token))),
+            None => return Err(QueryError::new(self.end, "expected ']' or '}'")),
+        };
+
+        let mut bounds = vec![];
+
+        if lower_quoted || lower != "*" {
+            let op = if lower_inclusive { Operator::Gte } else { Operator::Gt };
+
+            bounds.push(self.comparison(field.clone(), op, &lower, lower_position, lower_quoted)?);
+        }
+
+        if upper_quoted || upper != "*" {
+            let op = if upper_inclusive { Operator::Lte } else { Operator::Lt };
+
+            bounds.push(self.comparison(field, op, &upper, upper_position, upper_quoted)?);
+        }
+
+        let mut bounds = bounds.into_iter();
+
+        match (bounds.next(), bounds.next()) {
+            (Some(lower), Some(upper)) => Ok(Expression::And(Box::new(lower), Box::new(upper))),
+            (Some(bound), None) => Ok(bound),
+            _ => Err(QueryError::new(lower_position, "a range needs at least one bound")),
+        }
+    }
+
+    /// Types `value` as filters do: quoted values and values for text columns are strings,
+    /// timestamps are time expressions and anything else is guessed
+    fn comparison(&self, field: FieldSelector, op: Operator, value: &str, position: usize, quoted: bool) -> Result<Expression, QueryError> {
+        let is_timestamp = field == FieldSelector::Column(TIMESTAMP_FIELD.to_string());
+        let value = if is_timestamp && op != Operator::Contains {
+            time_expression::resolve(value, self.now)
+                .map(|timestamp| Value::ChronoDateTimeUtc(Some(Box::new(timestamp))))
+                .ok_or_else(|| QueryError::new(position, format!("invalid time expression '{}'", value)))?
+        } else if quoted || op == Operator::Contains || field.is_text() {
+            Value::String(Some(Box::new(value.to_string())))
+        } else {
+            Type::from(value.to_string()).into_value()
+        };
+
+        Ok(Expression::Comparison(Comparison {
+            field,
+            op,
+            value,
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::Value;
+
+    use crate::parameters::{
+        FieldSelector,
+        Operator,
+    };
+    use super::{
+        Comparison,
+        Expression,
+    };
+
+    fn comparison(field: FieldSelector, op: Operator, value: Value) -> Box<Expression> {
+        Box::new(Expression::Comparison(Comparison { field, op, value }))
+    }
+
+    fn column(name: &str) -> FieldSelector {
+        FieldSelector::Column(name.to_string())
+    }
+
+    fn string(value: &str) -> Value {
+        Value::String(Some(Box::new(value.to_string())))
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let expression = Expression::parse(r#"level:>=4 AND service:api AND NOT message:"health check" OR http.status:[500 TO 599]"#)
+            .unwrap();
+
+        let left = Expression::And(
+            Box::new(Expression::And(
+                comparison(column("level"), Operator::Gte, Value::BigInt(Some(4))),
+                comparison(column("service"), Operator::Eq, string("api")),
+            )),
+            Box::new(Expression::Not(comparison(column("message"), Operator::Eq, string("health check")))),
+        );
+        let status = FieldSelector::Context(vec!["http".to_string(), "status".to_string()]);
+        let right = Expression::And(
+            comparison(status.clone(), Operator::Gte, Value::BigInt(Some(500))),
+            comparison(status, Operator::Lte, Value::BigInt(Some(599))),
+        );
+
+        assert_eq!(expression, Expression::Or(Box::new(left), Box::new(right)));
+    }
+
+    #[test]
+    fn test_parse_terms() {
+        assert_eq!(
+            Expression::parse("-(timeout OR refused) host:web-1").unwrap(),
+            Expression::And(
+                Box::new(Expression::Not(Box::new(Expression::Or(
+                    comparison(column("message"), Operator::Contains, string("timeout")),
+                    comparison(column("message"), Operator::Contains, string("refused")),
+                )))),
+                comparison(column("host"), Operator::Eq, string("web-1")),
+            ),
+        );
+
+        assert_eq!(
+            Expression::parse("context.latency_ms:{* TO 10.5} message:*disk*").unwrap(),
+            Expression::And(
+                comparison(FieldSelector::Context(vec!["latency_ms".to_string()]), Operator::Lt, Value::Double(Some(10.5))),
+                comparison(column("message"), Operator::Contains, string("disk")),
+            ),
+        );
+
+        let timestamp = Expression::parse("timestamp:>2022-12-25T13:45:00Z").unwrap();
+        let expected = chrono::DateTime::parse_from_rfc3339("2022-12-25T13:45:00Z").unwrap().with_timezone(&chrono::Utc);
+
+        assert_eq!(*comparison(column("timestamp"), Operator::Gt, Value::ChronoDateTimeUtc(Some(Box::new(expected)))), timestamp);
+
+        // text columns compare as strings however the value looks
+        assert_eq!(
+            Expression::parse("service:123 level:3").unwrap(),
+            Expression::And(
+                comparison(column("service"), Operator::Eq, string("123")),
+                comparison(column("level"), Operator::Eq, Value::BigInt(Some(3))),
+            ),
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |query: &str| {
+            let error = Expression::parse(query).unwrap_err();
+
+            (error.position, error.message)
+        };
+
+        assert_eq!(error("level:"), (7, "expected a value".to_string()));
+        assert_eq!(error("level:[1 TO 5"), (14, "expected ']' or '}'".to_string()));
+        assert_eq!(error("(a OR b"), (8, "expected ')'".to_string()));
+        assert_eq!(error("a OR OR b"), (6, "unexpected OR".to_string()));
+        assert_eq!(error("a)"), (2, "unexpected ')'".to_string()));
+        assert_eq!(error("message:\"open"), (9, "unterminated quoted string".to_string()));
+        assert_eq!(error("timestamp:>=yesterday"), (13, "invalid time expression 'yesterday'".to_string()));
+        assert_eq!(error("bad'field:1"), (1, "invalid field 'bad'field'".to_string()));
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let error = |query: &str| {
+            let error = Expression::parse(query).unwrap_err();
+
+            (error.position, error.message)
+        };
+
+        assert!(Expression::parse(&format!("{}a", "-".repeat(64))).is_ok());
+        assert!(Expression::parse(&format!("{}a{}", "(".repeat(64), ")".repeat(64))).is_ok());
+        assert!(Expression::parse(&vec!["a"; 257].join(" ")).is_ok());
+
+        assert_eq!(error(&format!("{}a", "-".repeat(4000))), (65, "query is nested more than 64 levels deep".to_string()));
+        assert_eq!(error(&format!("NOT {}a", "(".repeat(64))), (68, "query is nested more than 64 levels deep".to_string()));
+        assert_eq!(error(&vec!["a"; 258].join(" OR ")), (1283, "query has more than 256 AND and OR operators".to_string()));
+        assert_eq!(error(&"-".repeat(30000)), (4097, "query is longer than 4096 characters".to_string()));
+    }
+}
@@ -0,0 +1,883 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{
+        Display,
+        Formatter,
+    },
+    net::{
+        IpAddr,
+        SocketAddr,
+    },
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{
+    DateTime,
+    Duration as ChronoDuration,
+    Utc,
+};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{
+        Addrs,
+        Resolve,
+        Resolving,
+    },
+    redirect::Policy,
+    StatusCode,
+    Url,
+};
+use sea_orm::{
+    ActiveValue,
+    ColumnTrait,
+    ConnectionTrait,
+    DatabaseConnection,
+    DbErr,
+    EntityTrait,
+    QueryFilter,
+    QueryOrder,
+    sea_query::Expr,
+    TransactionTrait,
+};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::{
+    config::Config,
+    error::Loggable,
+    models::{
+        AlertNotification,
+        AlertNotificationActiveModel,
+        AlertNotificationColumn,
+        AlertNotificationModel,
+        AlertRule,
+        AlertRuleColumn,
+        AlertRuleModel,
+        QueryBuilder,
+        SavedSearch,
+        SavedSearchColumn,
+        STATE_FIRING,
+        STATE_OK,
+    },
+    parameters::{
+        FieldSelector,
+        QueryParameters,
+    },
+};
+
+
+/// Wait before the first retry of a failed webhook delivery, doubled for each retry after
+const WEBHOOK_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between retries, however many there have been
+const MAX_WEBHOOK_BACKOFF: Duration = Duration::from_secs(300);
+
+
+/// Sent to a rule's webhooks when it starts firing and when it resolves. Both share a
+/// `dedupe_key`, which receivers can use to discard repeated deliveries.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    pub dedupe_key: String,
+    pub status: &'static str,
+    pub tenant_id: String,
+    pub rule_id: i64,
+    pub rule_name: String,
+    pub condition: String,
+    pub threshold: i64,
+    pub window_seconds: i64,
+    pub count: i64,
+    pub fired_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub evaluated_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub webhooks: Vec<String>,
+}
+
+
+/// Evaluates every alert rule at `now`, returning a notification for each rule which started
+/// firing or resolved. Notifications are queued for delivery along with the change of state.
+/// Rules only change state if they're still in the state they were read in, so when several
+/// instances evaluate the same rule only one of them queues a notification.
+pub async fn evaluate(db: &DatabaseConnection, now: DateTime<Utc>) -> Result<Vec<Notification>, DbErr> {
+    let rules = AlertRule::find()
+        .all(db)
+        .await?;
+    let mut notifications = vec![];
+
+    // a rule which can't be evaluated mustn't stop the others
+    for rule in rules {
+        let count = match count(db, &rule, now)
+            .await
+            .log_error(format!("An exception occurred while evaluating alert rule {}", rule.id)) {
+            Ok(Some(count)) => count,
+            _ => continue,
+        };
+
+        if let Ok(Some(notification)) = transition(db, &rule, count, now)
+            .await
+            .log_error(format!("An exception occurred while updating alert rule {}", rule.id)) {
+            notifications.push(notification);
+        }
+    }
+
+    Ok(notifications)
+}
+
+/// Logs matching a rule over its window, or None if its query can no longer be run
+async fn count(db: &DatabaseConnection, rule: &AlertRuleModel, now: DateTime<Utc>) -> Result<Option<i64>, DbErr> {
+    let saved_search = match rule.saved_search_id {
+        Some(id) => {
+            let saved_search = SavedSearch::find_by_id(id)
+                .filter(SavedSearchColumn::TenantId.eq(rule.tenant_id.as_str()))
+                .one(db)
+                .await?;
+
+            if saved_search.is_none() {
+                tracing::warn!("Alert rule {} refers to missing saved search {}", rule.id, id);
+
+                return Ok(None);
+            }
+
+            saved_search
+        },
+        None => None,
+    };
+    let parameters = match QueryParameters::from_hashmap(rule.query_parameters(saved_search.as_ref())) {
+        Ok(parameters) => parameters,
+        Err(e) => {
+            tracing::warn!("Alert rule {} has an invalid query: {}", rule.id, e);
+
+            return Ok(None);
+        },
+    };
+    let statement = QueryBuilder::new(rule.tenant_id.as_str())
+        .conditions(&parameters)
+        .gte(FieldSelector::Column("timestamp".to_string()), now - ChronoDuration::seconds(rule.window_seconds))
+        .into_count_statement(db.get_database_backend());
+
+    let count = db.query_one(statement)
+        .await?
+        .map(|row| row.try_get::<i64>("", "count"))
+        .transpose()?
+        .unwrap_or(0);
+
+    Ok(Some(count))
+}
+
+/// Records the outcome of an evaluation, returning a notification if the rule changed state.
+/// The notification is queued for each webhook in the same transaction as the change, so it
+/// can't be lost if the process stops before it's delivered.
+async fn transition(db: &DatabaseConnection, rule: &AlertRuleModel, count: i64, now: DateTime<Utc>) -> Result<Option<Notification>, DbErr> {
+    let firing = count >= rule.threshold;
+    let changed = firing != (rule.state == STATE_FIRING);
+    let mut update = AlertRule::update_many()
+        .col_expr(AlertRuleColumn::LastCount, Expr::value(count))
+        .col_expr(AlertRuleColumn::LastEvaluatedAt, Expr::value(now))
+        .filter(AlertRuleColumn::Id.eq(rule.id));
+
+    if changed {
+        let (state, at) = if firing {
+            (STATE_FIRING, AlertRuleColumn::FiredAt)
+        } else {
+            (STATE_OK, AlertRuleColumn::ResolvedAt)
+        };
+
+        update = update
+            .col_expr(AlertRuleColumn::State, Expr::value(state))
+            .col_expr(at, Expr::value(now))
+            .filter(AlertRuleColumn::State.eq(rule.state.as_str()));
+    }
+
+    let transaction = db.begin().await?;
+    let updated = update
+        .exec(&transaction)
+        .await?
+        .rows_affected;
+
+    if !changed || updated == 0 {
+        transaction.commit().await?;
+
+        return Ok(None);
+    }
+
+    let fired_at = if firing {
+        now
+    } else {
+        rule.fired_at
+            .map(|fired_at| fired_at.with_timezone(&Utc))
+            .unwrap_or(now)
+    };
+
+    let notification = Notification {
+        dedupe_key: format!("{}/{}/{}", rule.tenant_id, rule.id, fired_at.timestamp()),
+        status: if firing { "firing" } else { "resolved" },
+        tenant_id: rule.tenant_id.clone(),
+        rule_id: rule.id,
+        rule_name: rule.name.clone(),
+        condition: rule.condition.clone(),
+        threshold: rule.threshold,
+        window_seconds: rule.window_seconds,
+        count,
+        fired_at,
+        resolved_at: if firing { None } else { Some(now) },
+        evaluated_at: now,
+        webhooks: rule.webhook_urls(),
+    };
+    let payload = serde_json::to_value(&notification)
+        .map_err(|e| DbErr::Custom(e.to_string()))?;
+    let queued: Vec<AlertNotificationActiveModel> = notification.webhooks
+        .iter()
+        .map(|webhook| AlertNotificationActiveModel {
+            tenant_id: ActiveValue::Set(rule.tenant_id.clone()),
+            rule_id: ActiveValue::Set(rule.id),
+            webhook: ActiveValue::Set(webhook.clone()),
+            dedupe_key: ActiveValue::Set(notification.dedupe_key.clone()),
+            payload: ActiveValue::Set(payload.clone()),
+            attempts: ActiveValue::Set(0),
+            next_attempt_at: ActiveValue::Set(now.into()),
+            created_at: ActiveValue::Set(now.into()),
+            ..Default::default()
+        })
+        .collect();
+
+    if !queued.is_empty() {
+        AlertNotification::insert_many(queued)
+            .exec(&transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(Some(notification))
+}
+
+
+/// `duration` after `at`, or `at` itself if that can't be represented
+fn after(at: DateTime<Utc>, duration: Duration) -> DateTime<Utc> {
+    ChronoDuration::from_std(duration)
+        .ok()
+        .and_then(|duration| at.checked_add_signed(duration))
+        .unwrap_or(at)
+}
+
+
+/// Whether `ip` may be sent webhooks: anything but loopback, private, link-local (which
+/// includes cloud metadata services) and other special purpose ranges
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() ||
+                ip.is_broadcast() || ip.is_documentation() || ip.is_multicast() ||
+                a == 0 || a >= 240 || (a == 100 && (64..128).contains(&b)) || (a == 198 && (18..20).contains(&b)))
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() ||
+                    (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80)
+            },
+        },
+    }
+}
+
+/// Whether `url` is a webhook notifications may be sent to. Hosts given by name are checked
+/// again once resolved, when delivering.
+pub fn is_allowed_webhook(url: &Url, allow_private: bool) -> bool {
+    if !["http", "https"].contains(&url.scheme()) {
+        return false;
+    }
+
+    if allow_private {
+        return true;
+    }
+
+    let host = match url.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return false,
+    };
+
+    match host.parse::<IpAddr>() {
+        Ok(ip) => is_public(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_lowercase();
+
+            domain != "localhost" && !domain.ends_with(".localhost")
+        },
+    }
+}
+
+
+/// Resolves webhook hosts to their public addresses only, so a name can't be pointed at an
+/// internal service
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+
+            let addrs: Addrs = Box::new(addrs.into_iter());
+
+            Ok(addrs)
+        })
+    }
+}
+
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    Forbidden(String),
+    Request(reqwest::Error),
+    Status(StatusCode),
+}
+
+impl Error for DeliveryError {}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Forbidden(url) => write!(f, "Webhook {} is not allowed", url),
+            Self::Request(e) => write!(f, "{}", e),
+            Self::Status(status) => write!(f, "Webhook responded with {}", status),
+        }
+    }
+}
+
+
+/// Delivers queued notifications to webhooks as JSON `POST`s, retrying failed deliveries with
+/// exponential backoff. Redirects aren't followed, and unless private addresses are allowed
+/// webhooks are only delivered to public addresses.
+#[derive(Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    retries: u32,
+    timeout: Duration,
+    backoff: Duration,
+    allow_private: bool,
+}
+
+impl Notifier {
+    pub fn new(retries: u32, timeout: Duration, backoff: Duration, allow_private: bool) -> Self {
+        let mut builder = reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(Policy::none());
+
+        if !allow_private {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+
+        let client = builder
+            .build()
+            .expect("Failed to build webhook client!");
+
+        Self {
+            client,
+            retries,
+            timeout,
+            backoff,
+            allow_private,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.alert_webhook_retries,
+            Duration::from_secs(config.alert_webhook_timeout_seconds),
+            WEBHOOK_BACKOFF,
+            config.alert_webhook_allow_private,
+        )
+    }
+
+    /// Delivers the queued notifications due by `now`. A rule's notifications to a webhook are
+    /// delivered in the order they were queued: one which fails is retried once its backoff,
+    /// counted from its stored attempts, has passed, and holds up those queued after it until
+    /// it's delivered or given up on.
+    pub async fn deliver_pending(&self, db: Arc<DatabaseConnection>, now: DateTime<Utc>) -> Result<(), DbErr> {
+        let pending = AlertNotification::find()
+            .order_by_asc(AlertNotificationColumn::Id)
+            .all(db.as_ref())
+            .await?;
+        let mut queues: BTreeMap<(i64, String), Vec<AlertNotificationModel>> = BTreeMap::new();
+
+        for queued in pending {
+            queues
+                .entry((queued.rule_id, queued.webhook.clone()))
+                .or_default()
+                .push(queued);
+        }
+
+        // a slow webhook mustn't hold up the others
+        let handles: Vec<JoinHandle<()>> = queues
+            .into_values()
+            .map(|queue| {
+                let notifier = self.clone();
+                let db = db.clone();
+
+                tokio::spawn(async move {
+                    for queued in queue {
+                        let next = notifier.deliver_queued(&db, &queued, now)
+                            .await
+                            .log_error(format!("An exception occurred while delivering alert {} to {}", queued.dedupe_key, queued.webhook));
+
+                        if !matches!(next, Ok(true)) {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            let _ = handle.await;
+        }
+
+        Ok(())
+    }
+
+    /// Delivers `queued` if it's due by `now`, returning whether the notifications queued after
+    /// it may follow: once it's delivered, or given up on after the retries. It's claimed for
+    /// twice the timeout first, so another instance doesn't deliver it at the same time.
+    async fn deliver_queued(&self, db: &DatabaseConnection, queued: &AlertNotificationModel, now: DateTime<Utc>) -> Result<bool, DbErr> {
+        let claimed = AlertNotification::update_many()
+            .col_expr(AlertNotificationColumn::NextAttemptAt, Expr::value(after(now, self.timeout * 2)))
+            .filter(AlertNotificationColumn::Id.eq(queued.id))
+            .filter(AlertNotificationColumn::NextAttemptAt.lte(now))
+            .exec(db)
+            .await?
+            .rows_affected;
+
+        if claimed == 0 {
+            return Ok(false);
+        }
+
+        let attempt = queued.attempts.max(0) as u32;
+
+        match self.deliver(&queued.webhook, &queued.payload).await {
+            Ok(_) => {},
+            Err(e) if attempt >= self.retries => {
+                tracing::error!("Gave up delivering alert {} to {}: {}", queued.dedupe_key, queued.webhook, e);
+            },
+            Err(e) => {
+                tracing::warn!("Retrying alert {} to {}: {}", queued.dedupe_key, queued.webhook, e);
+
+                AlertNotification::update_many()
+                    .col_expr(AlertNotificationColumn::Attempts, Expr::value(queued.attempts + 1))
+                    .col_expr(AlertNotificationColumn::NextAttemptAt, Expr::value(after(now, self.backoff_for(attempt))))
+                    .filter(AlertNotificationColumn::Id.eq(queued.id))
+                    .exec(db)
+                    .await?;
+
+                return Ok(false);
+            },
+        }
+
+        AlertNotification::delete_by_id(queued.id)
+            .exec(db)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Posts `payload` to `url` once, succeeding on any 2xx response
+    pub async fn deliver<T: Serialize + ?Sized>(&self, url: &str, payload: &T) -> Result<(), DeliveryError> {
+        let allowed = Url::parse(url)
+            .map(|url| is_allowed_webhook(&url, self.allow_private))
+            .unwrap_or(false);
+
+        if !allowed {
+            return Err(DeliveryError::Forbidden(url.to_string()));
+        }
+
+        match self.client.post(url).json(payload).send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(DeliveryError::Status(response.status())),
+            Err(e) => Err(DeliveryError::Request(e)),
+        }
+    }
+
+    /// The wait before retry `attempt + 1`: the backoff doubled `attempt` times, up to a maximum
+    fn backoff_for(&self, attempt: u32) -> Duration {
+        2u32.checked_pow(attempt)
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .unwrap_or(MAX_WEBHOOK_BACKOFF)
+            .min(MAX_WEBHOOK_BACKOFF)
+    }
+}
+
+
+pub fn spawn(db: Arc<DatabaseConnection>, config: &Config) -> JoinHandle<()> {
+    let notifier = Notifier::from_config(config);
+    let period = Duration::from_secs(config.alert_interval_seconds.get());
+    let delivering = Arc::new(tokio::sync::Mutex::new(()));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+
+            if let Ok(notifications) = evaluate(&db, Utc::now())
+                .await
+                .log_error("An exception occurred while evaluating alert rules") {
+                for notification in notifications {
+                    tracing::info!("Alert rule {} is {}", notification.rule_id, notification.status);
+                }
+            }
+
+            // deliveries mustn't hold up the next evaluation, nor overlap each other
+            if let Ok(guard) = delivering.clone().try_lock_owned() {
+                let notifier = notifier.clone();
+                let db = db.clone();
+
+                tokio::spawn(async move {
+                    let _ = notifier.deliver_pending(db, Utc::now())
+                        .await
+                        .log_error("An exception occurred while delivering alert notifications");
+
+                    drop(guard);
+                });
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use axum::{
+        extract::State,
+        http::StatusCode,
+        Json,
+        routing::post,
+        Router,
+    };
+    use chrono::{
+        TimeZone,
+        Utc,
+    };
+    use sea_orm::{
+        ActiveModelTrait,
+        ActiveValue,
+        ColumnTrait,
+        DatabaseConnection,
+        EntityTrait,
+        QueryFilter,
+    };
+    use serde_json::json;
+
+    use crate::{
+        models::{
+            AlertNotification,
+            AlertNotificationColumn,
+            AlertRuleActiveModel,
+            IngestLog,
+            Log,
+        },
+        testing,
+    };
+    use super::{
+        evaluate,
+        is_allowed_webhook,
+        DeliveryError,
+        Notification,
+        Notifier,
+    };
+
+    /// Webhook stand-in which fails the first `failures` requests and records the rest
+    #[derive(Clone, Default)]
+    struct Receiver {
+        failures: Arc<Mutex<u32>>,
+        received: Arc<Mutex<Vec<serde_json::Value>>>,
+    }
+
+    async fn receive(State(receiver): State<Receiver>, Json(body): Json<serde_json::Value>) -> StatusCode {
+        let mut failures = receiver.failures.lock().unwrap();
+
+        if *failures > 0 {
+            *failures -= 1;
+
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+
+        receiver.received.lock().unwrap().push(body);
+
+        StatusCode::NO_CONTENT
+    }
+
+    fn serve(receiver: Receiver) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let router = Router::new()
+            .route("/hook", post(receive))
+            .with_state(receiver);
+
+        tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        url
+    }
+
+    /// Evaluates every rule at `now`, keeping the notification of rule `rule_id` only, as other
+    /// tests' rules are evaluated too
+    async fn evaluate_rule(db: &DatabaseConnection, rule_id: i64, now: chrono::DateTime<Utc>) -> Vec<Notification> {
+        evaluate(db, now)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|notification| notification.rule_id == rule_id)
+            .collect()
+    }
+
+    fn notification(webhooks: Vec<String>) -> Notification {
+        let now = Utc.with_ymd_and_hms(2022, 12, 25, 13, 45, 0).unwrap();
+
+        Notification {
+            dedupe_key: "default/1/1671975900".to_string(),
+            status: "firing",
+            tenant_id: "default".to_string(),
+            rule_id: 1,
+            rule_name: "errors".to_string(),
+            condition: "any".to_string(),
+            threshold: 1,
+            window_seconds: 300,
+            count: 2,
+            fired_at: now,
+            resolved_at: None,
+            evaluated_at: now,
+            webhooks,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notifier_deliver() {
+        let receiver = Receiver::default();
+        let url = serve(receiver.clone());
+        let notifier = Notifier::new(2, Duration::from_secs(5), Duration::from_millis(10), true);
+
+        // a single attempt, retries being left to the queue
+        *receiver.failures.lock().unwrap() = 1;
+
+        let error = notifier.deliver(&url, &notification(vec![])).await.unwrap_err();
+
+        assert!(matches!(error, DeliveryError::Status(reqwest::StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(receiver.received.lock().unwrap().is_empty());
+
+        notifier.deliver(&url, &notification(vec![])).await.unwrap();
+
+        let received = receiver.received.lock().unwrap().clone();
+
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["dedupe_key"], "default/1/1671975900");
+        assert_eq!(received[0]["status"], "firing");
+        assert_eq!(received[0]["count"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_notifier_private_addresses() {
+        let receiver = Receiver::default();
+        let url = serve(receiver.clone());
+        let notifier = Notifier::new(0, Duration::from_secs(5), Duration::from_millis(10), false);
+        let port = url.split(':').nth(2).unwrap();
+
+        for url in [url.clone(), format!("http://localhost:{}", port), "http://169.254.169.254/latest".to_string()] {
+            let error = notifier.deliver(&url, &notification(vec![])).await.unwrap_err();
+
+            assert!(matches!(error, DeliveryError::Forbidden(_)), "{} was allowed", url);
+        }
+
+        assert!(receiver.received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_notifier_redirects_not_followed() {
+        let receiver = Receiver::default();
+        let url = serve(receiver.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap();
+        let redirect = format!("http://{}/", listener.local_addr().unwrap());
+        let router = Router::new()
+            .route("/", post(move || async move { axum::response::Redirect::temporary(&url) }));
+
+        tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let notifier = Notifier::new(0, Duration::from_secs(5), Duration::from_millis(10), true);
+        let error = notifier.deliver(&redirect, &notification(vec![])).await.unwrap_err();
+
+        assert!(matches!(error, DeliveryError::Status(reqwest::StatusCode::TEMPORARY_REDIRECT)));
+        assert!(receiver.received.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_webhook_addresses() {
+        let allowed = |url: &str| is_allowed_webhook(&reqwest::Url::parse(url).unwrap(), false);
+
+        assert!(allowed("https://hooks.example.com/alerts"));
+        assert!(allowed("http://8.8.8.8/"));
+        assert!(!allowed("http://127.0.0.1:8080/"));
+        assert!(!allowed("http://localhost/"));
+        assert!(!allowed("http://10.1.2.3/"));
+        assert!(!allowed("http://192.168.0.1/"));
+        assert!(!allowed("http://169.254.169.254/"));
+        assert!(!allowed("http://[::1]/"));
+        assert!(!allowed("http://[::ffff:127.0.0.1]/"));
+        assert!(!allowed("http://[fd00::1]/"));
+        assert!(!allowed("ftp://hooks.example.com/"));
+        assert!(is_allowed_webhook(&reqwest::Url::parse("http://127.0.0.1:8080/").unwrap(), true));
+    }
+
+    #[test]
+    fn test_backoff_capped() {
+        let notifier = Notifier::new(40, Duration::from_secs(5), Duration::from_secs(1), true);
+
+        assert_eq!(notifier.backoff_for(0), Duration::from_secs(1));
+        assert_eq!(notifier.backoff_for(3), Duration::from_secs(8));
+        assert_eq!(notifier.backoff_for(20), Duration::from_secs(300));
+        assert_eq!(notifier.backoff_for(40), Duration::from_secs(300));
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_evaluate_database() {
+        let db = testing::database(&testing::config(&[]))
+            .await;
+        let tenant = testing::tenant("alerts-evaluate");
+
+        let logs: Vec<IngestLog> = serde_json::from_value(json!([
+            {"timestamp": "2022-12-25T13:41:00Z", "level": 5, "message": "payment failed", "service": "billing"},
+            {"timestamp": "2022-12-25T13:44:00Z", "level": 5, "message": "payment failed", "service": "billing"},
+            {"timestamp": "2022-12-25T13:44:30Z", "level": 2, "message": "payment ok", "service": "billing"},
+        ])).unwrap();
+
+        Log::insert_many(logs.into_iter().map(|mut log| {
+            log.promote_context_fields();
+            log.into_tenant_active_model(tenant.as_str())
+        }))
+        .exec(&db)
+        .await
+        .unwrap();
+
+        let receiver = Receiver::default();
+        let url = serve(receiver.clone());
+        let now = Utc::now();
+        let rule = AlertRuleActiveModel {
+            tenant_id: ActiveValue::Set(tenant.clone()),
+            name: ActiveValue::Set("billing errors".to_string()),
+            owner: ActiveValue::Set("alice".to_string()),
+            saved_search_id: ActiveValue::Set(None),
+            parameters: ActiveValue::Set(json!({"filter[level][gte]": "4", "filter[service][eq]": "billing"})),
+            condition: ActiveValue::Set("threshold".to_string()),
+            threshold: ActiveValue::Set(2),
+            window_seconds: ActiveValue::Set(300),
+            webhooks: ActiveValue::Set(json!([url])),
+            state: ActiveValue::Set("ok".to_string()),
+            created_at: ActiveValue::Set(now.into()),
+            updated_at: ActiveValue::Set(now.into()),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await
+        .unwrap();
+
+        // both errors fall within the window
+        let fired_at = Utc.with_ymd_and_hms(2022, 12, 25, 13, 45, 0).unwrap();
+        let notifications = evaluate_rule(&db, rule.id, fired_at).await;
+
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].status, "firing");
+        assert_eq!(notifications[0].count, 2);
+        assert_eq!(notifications[0].dedupe_key, format!("{}/{}/{}", tenant, rule.id, fired_at.timestamp()));
+
+        let db = Arc::new(db);
+        let notifier = Notifier::new(1, Duration::from_secs(5), Duration::from_secs(1), true);
+
+        // the failed delivery stays queued, and is retried once its backoff has passed
+        *receiver.failures.lock().unwrap() = 1;
+        notifier.deliver_pending(db.clone(), fired_at).await.unwrap();
+        notifier.deliver_pending(db.clone(), fired_at).await.unwrap();
+
+        assert!(receiver.received.lock().unwrap().is_empty());
+
+        let queued = AlertNotification::find()
+            .filter(AlertNotificationColumn::TenantId.eq(tenant.as_str()))
+            .all(db.as_ref())
+            .await
+            .unwrap();
+
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].attempts, 1);
+        assert_eq!(queued[0].next_attempt_at, fired_at + chrono::Duration::seconds(1));
+
+        // still firing, so nothing new to send
+        assert!(evaluate_rule(&db, rule.id, Utc.with_ymd_and_hms(2022, 12, 25, 13, 46, 0).unwrap()).await.is_empty());
+
+        // the first error has left the window
+        let resolved_at = Utc.with_ymd_and_hms(2022, 12, 25, 13, 47, 0).unwrap();
+        let notifications = evaluate_rule(&db, rule.id, resolved_at).await;
+
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].status, "resolved");
+        assert_eq!(notifications[0].count, 1);
+        assert_eq!(notifications[0].fired_at, fired_at);
+        assert_eq!(notifications[0].resolved_at, Some(resolved_at));
+        assert_eq!(notifications[0].dedupe_key, format!("{}/{}/{}", tenant, rule.id, fired_at.timestamp()));
+
+        // queued behind the firing notification, so delivered after it
+        notifier.deliver_pending(db.clone(), resolved_at).await.unwrap();
+
+        let received = receiver.received.lock().unwrap().clone();
+
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0]["status"], "firing");
+        assert_eq!(received[1]["status"], "resolved");
+        assert_eq!(received[1]["rule_name"], "billing errors");
+
+        let queued = AlertNotification::find()
+            .filter(AlertNotificationColumn::TenantId.eq(tenant.as_str()))
+            .all(db.as_ref())
+            .await
+            .unwrap();
+
+        assert!(queued.is_empty());
+
+        let rule = crate::models::AlertRule::find_by_id(rule.id)
+            .one(db.as_ref())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(rule.state, "ok");
+        assert_eq!(rule.last_count, Some(1));
+    }
+}
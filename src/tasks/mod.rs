@@ -0,0 +1,4 @@
+pub mod alerts;
+pub mod multiline;
+pub mod partitions;
+pub mod retention;
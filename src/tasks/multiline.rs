@@ -0,0 +1,65 @@
+use std::{
+    collections::BTreeMap,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    api::AppState,
+    error::Loggable,
+    models::IngestLog,
+};
+
+
+/// Shortest pause between flushes, however short the multiline window
+const MIN_FLUSH_PERIOD: Duration = Duration::from_millis(100);
+
+
+/// Stores the buffered multiline events released by `now`, or every buffered event when
+/// `now` is None, returning the number stored
+pub async fn flush(state: &AppState, now: Option<Instant>) -> usize {
+    let multiline = match state.pipeline.multiline() {
+        Some(multiline) => multiline,
+        None => return 0,
+    };
+    let mut tenants: BTreeMap<String, Vec<IngestLog>> = BTreeMap::new();
+
+    for (tenant_id, log) in multiline.flush(now) {
+        tenants.entry(tenant_id).or_default().push(log);
+    }
+
+    let mut stored = 0;
+
+    for (tenant_id, logs) in tenants {
+        if let Ok(logs) = state.store_logs(&tenant_id, logs, None)
+            .await
+            .log_error("An exception occurred while storing buffered multiline events") {
+            stored += logs.count;
+        }
+    }
+
+    stored
+}
+
+
+pub fn spawn(state: AppState) -> Option<JoinHandle<()>> {
+    let period = state.pipeline
+        .multiline()?
+        .window()
+        .max(MIN_FLUSH_PERIOD);
+
+    let handle = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+            flush(&state, Some(Instant::now())).await;
+        }
+    });
+
+    Some(handle)
+}
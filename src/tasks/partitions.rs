@@ -0,0 +1,529 @@
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{
+    DateTime,
+    Duration as ChronoDuration,
+    NaiveDateTime,
+    TimeZone,
+    Timelike,
+    Utc,
+};
+use sea_orm::{
+    ConnectionTrait,
+    DatabaseConnection,
+    DbErr,
+    Statement,
+    TransactionTrait,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    config::{
+        Config,
+        PartitionInterval,
+    },
+    error::Loggable,
+    models::LogModel,
+};
+
+
+const PARTITION_PREFIX: &str = "logs_p";
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S+00";
+
+
+/// A single range partition of the logs table covering `[from, to)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partition {
+    pub name: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl Partition {
+    /// Recovers a partition's range from a name created by the `PartitionManager`
+    pub fn from_name(name: &str) -> Option<Self> {
+        let suffix = name.strip_prefix(PARTITION_PREFIX)?;
+        let date = chrono::NaiveDate::parse_from_str(suffix.get(..8)?, "%Y%m%d").ok()?;
+        let (hour, step) = match suffix.len() {
+            8 => (0, ChronoDuration::days(1)),
+            10 => (suffix[8..].parse::<u32>().ok()?, ChronoDuration::hours(1)),
+            _ => return None,
+        };
+        let from = Utc.from_utc_datetime(&date.and_hms_opt(hour, 0, 0)?);
+
+        Some(Self {
+            name: name.to_string(),
+            from,
+            to: from + step,
+        })
+    }
+}
+
+
+/// A partition attached to the logs table, with the range Postgres holds for it rather than
+/// the one implied by its name. Unbounded ends (`MINVALUE`/`MAXVALUE`) are None.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttachedPartition {
+    pub name: String,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl AttachedPartition {
+    /// Whether any timestamp in `partition` would be routed to this partition
+    pub fn overlaps(&self, partition: &Partition) -> bool {
+        self.from.map(|from| from < partition.to).unwrap_or(true)
+            && self.to.map(|to| partition.from < to).unwrap_or(true)
+    }
+}
+
+
+pub struct PartitionManager {
+    interval: PartitionInterval,
+    precreate: u32,
+}
+
+impl PartitionManager {
+    pub fn new(interval: PartitionInterval, precreate: u32) -> Self {
+        Self {
+            interval,
+            precreate,
+        }
+    }
+
+    /// Returns the partition which would contain `at`
+    pub fn partition_for(&self, at: DateTime<Utc>) -> Partition {
+        let (from, step, name_format) = match self.interval {
+            PartitionInterval::Daily => (
+                at.date_naive().and_hms_opt(0, 0, 0).unwrap(),
+                ChronoDuration::days(1),
+                "%Y%m%d",
+            ),
+            PartitionInterval::Hourly => (
+                at.date_naive().and_hms_opt(at.hour(), 0, 0).unwrap(),
+                ChronoDuration::hours(1),
+                "%Y%m%d%H",
+            ),
+        };
+        let from = Utc.from_utc_datetime(&from);
+
+        Partition {
+            name: format!("{}{}", PARTITION_PREFIX, from.format(name_format)),
+            from,
+            to: from + step,
+        }
+    }
+
+    /// Returns the partition containing `now` followed by `precreate` future partitions
+    pub fn upcoming(&self, now: DateTime<Utc>) -> Vec<Partition> {
+        let mut partitions = vec![self.partition_for(now)];
+
+        for _ in 0..self.precreate {
+            let next = self.partition_for(partitions.last().unwrap().to);
+
+            partitions.push(next);
+        }
+
+        partitions
+    }
+
+    /// Returns the partitions covering rows held by the default partition from before `before`,
+    /// such as rows logged before the table was partitioned
+    pub async fn stranded(&self, db: &DatabaseConnection, before: DateTime<Utc>) -> Result<Vec<Partition>, DbErr> {
+        let unit = match self.interval {
+            PartitionInterval::Daily => "day",
+            PartitionInterval::Hourly => "hour",
+        };
+        let statement = Statement::from_sql_and_values(
+            db.get_database_backend(),
+            &format!(
+                "SELECT DISTINCT date_trunc('{}', timestamp AT TIME ZONE 'UTC') AS start FROM \"{}_default\" WHERE timestamp < $1 ORDER BY 1",
+                unit,
+                LogModel::table_name(),
+            ),
+            vec![before.into()],
+        );
+
+        db.query_all(statement)
+            .await?
+            .into_iter()
+            .map(|row| {
+                row.try_get::<NaiveDateTime>("", "start")
+                    .map(|start| self.partition_for(Utc.from_utc_datetime(&start)))
+            })
+            .collect()
+    }
+
+    /// Creates any missing partitions for stranded and upcoming rows, returning the names of
+    /// those created. Ranges overlapping an attached partition, including one created by hand
+    /// with other bounds, are skipped; a partition which can't be created is logged and the
+    /// rest are still created.
+    pub async fn ensure_partitions(&self, db: &DatabaseConnection, now: DateTime<Utc>) -> Result<Vec<String>, DbErr> {
+        let existing = partitions(db).await?;
+        let stranded = self.stranded(db, self.partition_for(now).from).await?;
+        let mut created = vec![];
+
+        for partition in stranded.into_iter().chain(self.upcoming(now)) {
+            if existing.iter().any(|attached| attached.name == partition.name || attached.overlaps(&partition)) {
+                continue;
+            }
+
+            if Self::create_partition(db, &partition)
+                .await
+                .log_error(format!("An exception occurred while creating log partition {}", partition.name))
+                .is_ok() {
+                created.push(partition.name);
+            }
+        }
+
+        Ok(created)
+    }
+
+    /// Creates a partition and attaches it to the logs table. Rows already in the default
+    /// partition which fall within its range are moved into it first, as Postgres refuses
+    /// to attach a partition whose range overlaps rows held by the default partition.
+    async fn create_partition(db: &DatabaseConnection, partition: &Partition) -> Result<(), DbErr> {
+        let table_name = LogModel::table_name();
+        let from = partition.from.format(TIMESTAMP_FORMAT);
+        let to = partition.to.format(TIMESTAMP_FORMAT);
+        let statements = [
+            format!(
+                "CREATE TABLE IF NOT EXISTS \"{}\" (LIKE \"{}\" INCLUDING DEFAULTS INCLUDING CONSTRAINTS)",
+                partition.name,
+                table_name,
+            ),
+            format!(
+                "WITH moved AS (DELETE FROM \"{}_default\" WHERE timestamp >= '{}' AND timestamp < '{}' RETURNING *) INSERT INTO \"{}\" SELECT * FROM moved",
+                table_name,
+                from,
+                to,
+                partition.name,
+            ),
+            format!(
+                "ALTER TABLE \"{}\" ATTACH PARTITION \"{}\" FOR VALUES FROM ('{}') TO ('{}')",
+                table_name,
+                partition.name,
+                from,
+                to,
+            ),
+        ];
+
+        let transaction = db.begin().await?;
+
+        for statement in statements {
+            transaction.execute(
+                Statement::from_string(
+                    db.get_database_backend(),
+                    statement,
+                )
+            ).await?;
+        }
+
+        transaction.commit().await
+    }
+}
+
+
+/// Returns every range partition currently attached to the logs table, with the bounds read
+/// from `relpartbound`. The default partition isn't included.
+pub async fn partitions(db: &DatabaseConnection) -> Result<Vec<AttachedPartition>, DbErr> {
+    let statement = Statement::from_sql_and_values(
+        db.get_database_backend(),
+        r#"
+            SELECT
+                name,
+                (regexp_match(bound, 'FROM \(''([^'']*)''\)'))[1]::timestamptz AS "from",
+                (regexp_match(bound, 'TO \(''([^'']*)''\)'))[1]::timestamptz AS "to"
+            FROM (
+                SELECT c.relname AS name, pg_get_expr(c.relpartbound, c.oid) AS bound
+                FROM pg_inherits i
+                JOIN pg_class c ON c.oid = i.inhrelid
+                JOIN pg_class p ON p.oid = i.inhparent
+                WHERE p.relname = $1
+            ) attached
+            WHERE bound <> 'DEFAULT'
+        "#,
+        vec![LogModel::table_name().into()],
+    );
+
+    db.query_all(statement)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok(AttachedPartition {
+                name: row.try_get("", "name")?,
+                from: row.try_get("", "from")?,
+                to: row.try_get("", "to")?,
+            })
+        })
+        .collect()
+}
+
+
+pub fn spawn(db: Arc<DatabaseConnection>, config: &Config) -> JoinHandle<()> {
+    let manager = PartitionManager::new(config.partition_interval, config.partition_precreate);
+    let period = Duration::from_secs(config.partition_maintenance_seconds.get());
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+
+            if let Ok(created) = manager
+                .ensure_partitions(&db, Utc::now())
+                .await
+                .log_error("An exception occurred while creating log partitions") {
+                for name in created {
+                    tracing::info!("Created log partition {}", name);
+                }
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::{
+        TimeZone,
+        Utc,
+    };
+    use sea_orm::{
+        ConnectionTrait,
+        DatabaseBackend,
+        Statement,
+    };
+
+    use crate::{
+        config::PartitionInterval,
+        models::QueryBuilder,
+        parameters::FieldSelector,
+        testing,
+    };
+    use super::{
+        partitions,
+        AttachedPartition,
+        Partition,
+        PartitionManager,
+    };
+
+    #[test]
+    fn test_partition_for() {
+        let at = Utc.with_ymd_and_hms(2022, 12, 25, 13, 45, 0).unwrap();
+
+        let daily = PartitionManager::new(PartitionInterval::Daily, 0)
+            .partition_for(at);
+        let hourly = PartitionManager::new(PartitionInterval::Hourly, 0)
+            .partition_for(at);
+
+        assert_eq!(daily.name, "logs_p20221225");
+        assert_eq!(daily.from, Utc.with_ymd_and_hms(2022, 12, 25, 0, 0, 0).unwrap());
+        assert_eq!(daily.to, Utc.with_ymd_and_hms(2022, 12, 26, 0, 0, 0).unwrap());
+
+        assert_eq!(hourly.name, "logs_p2022122513");
+        assert_eq!(hourly.from, Utc.with_ymd_and_hms(2022, 12, 25, 13, 0, 0).unwrap());
+        assert_eq!(hourly.to, Utc.with_ymd_and_hms(2022, 12, 25, 14, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_partition_from_name() {
+        let at = Utc.with_ymd_and_hms(2022, 12, 25, 13, 45, 0).unwrap();
+
+        for interval in [PartitionInterval::Daily, PartitionInterval::Hourly] {
+            let partition = PartitionManager::new(interval, 0)
+                .partition_for(at);
+
+            assert_eq!(Partition::from_name(&partition.name), Some(partition));
+        }
+
+        assert_eq!(Partition::from_name("logs_default"), None);
+        assert_eq!(Partition::from_name("logs_p2022122"), None);
+    }
+
+    #[test]
+    fn test_upcoming() {
+        let at = Utc.with_ymd_and_hms(2022, 12, 31, 13, 45, 0).unwrap();
+        let names: Vec<String> = PartitionManager::new(PartitionInterval::Daily, 2)
+            .upcoming(at)
+            .into_iter()
+            .map(|partition| partition.name)
+            .collect();
+
+        assert_eq!(names, vec!["logs_p20221231", "logs_p20230101", "logs_p20230102"]);
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let partition = PartitionManager::new(PartitionInterval::Daily, 0)
+            .partition_for(Utc.with_ymd_and_hms(2022, 12, 25, 13, 45, 0).unwrap());
+        let attached = |from: Option<u32>, to: Option<u32>| AttachedPartition {
+            name: "logs_manual".to_string(),
+            from: from.map(|day| Utc.with_ymd_and_hms(2022, 12, day, 0, 0, 0).unwrap()),
+            to: to.map(|day| Utc.with_ymd_and_hms(2022, 12, day, 0, 0, 0).unwrap()),
+        };
+
+        assert!(attached(Some(20), Some(27)).overlaps(&partition));
+        assert!(attached(Some(25), Some(26)).overlaps(&partition));
+        assert!(attached(None, Some(26)).overlaps(&partition));
+        assert!(attached(Some(25), None).overlaps(&partition));
+        assert!(!attached(Some(20), Some(25)).overlaps(&partition));
+        assert!(!attached(Some(26), None).overlaps(&partition));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_ensure_partitions_overlapping() {
+        let db = testing::database(&testing::config(&[]))
+            .await;
+        let at = Utc.with_ymd_and_hms(2030, 6, 25, 13, 45, 0).unwrap();
+        let manager = PartitionManager::new(PartitionInterval::Daily, 2);
+
+        // a partition attached by hand covering more than a day under another name
+        db.execute(
+            Statement::from_string(
+                DatabaseBackend::Postgres,
+                "CREATE TABLE logs_manual PARTITION OF logs FOR VALUES FROM ('2030-06-26 00:00:00+00') TO ('2030-06-27 12:00:00+00')".to_owned(),
+            )
+        ).await
+        .unwrap();
+
+        let attached = partitions(&db)
+            .await
+            .unwrap();
+
+        assert!(attached.contains(&AttachedPartition {
+            name: "logs_manual".to_string(),
+            from: Some(Utc.with_ymd_and_hms(2030, 6, 26, 0, 0, 0).unwrap()),
+            to: Some(Utc.with_ymd_and_hms(2030, 6, 27, 12, 0, 0).unwrap()),
+        }));
+
+        // both days it overlaps are skipped, and the others still created
+        let created = manager.ensure_partitions(&db, at)
+            .await
+            .unwrap();
+
+        assert_eq!(created, vec!["logs_p20300625"]);
+
+        for name in ["logs_manual", "logs_p20300625"] {
+            db.execute(
+                Statement::from_string(
+                    DatabaseBackend::Postgres,
+                    format!("DROP TABLE {}", name),
+                )
+            ).await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_ensure_partitions_and_pruning() {
+        let db = testing::database(&testing::config(&[]))
+            .await;
+        let tenant = testing::tenant("partitions");
+        let at = Utc.with_ymd_and_hms(2030, 1, 25, 13, 45, 0).unwrap();
+        let manager = PartitionManager::new(PartitionInterval::Daily, 1);
+
+        // a row landing in the default partition before its partition exists is moved on creation
+        db.execute(
+            Statement::from_sql_and_values(
+                DatabaseBackend::Postgres,
+                "INSERT INTO logs (tenant_id, timestamp, message, level) VALUES ($1, '2030-01-25 12:00:00+00', 'early', 3)",
+                vec![tenant.as_str().into()],
+            )
+        ).await
+        .unwrap();
+
+        // as is a row from before the current partition, e.g. one logged before partitioning
+        db.execute(
+            Statement::from_sql_and_values(
+                DatabaseBackend::Postgres,
+                "INSERT INTO logs (tenant_id, timestamp, message, level) VALUES ($1, '2030-01-20 08:00:00+00', 'historical', 3)",
+                vec![tenant.as_str().into()],
+            )
+        ).await
+        .unwrap();
+
+        manager.ensure_partitions(&db, at)
+            .await
+            .unwrap();
+
+        let names: Vec<String> = partitions(&db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|partition| partition.name)
+            .collect();
+
+        assert!(names.contains(&"logs_p20300120".to_string()));
+        assert!(names.contains(&"logs_p20300125".to_string()));
+        assert!(names.contains(&"logs_p20300126".to_string()));
+
+        let stranded = db.query_one(
+            Statement::from_sql_and_values(
+                DatabaseBackend::Postgres,
+                "SELECT count(*) AS count FROM logs_default WHERE tenant_id = $1",
+                vec![tenant.as_str().into()],
+            )
+        ).await
+        .unwrap()
+        .unwrap()
+        .try_get::<i64>("", "count")
+        .unwrap();
+
+        assert_eq!(stranded, 0);
+
+        // a second pass has nothing left to create
+        assert!(manager.ensure_partitions(&db, at).await.unwrap().is_empty());
+
+        let moved = db.query_one(
+            Statement::from_sql_and_values(
+                DatabaseBackend::Postgres,
+                "SELECT count(*) AS count FROM logs_p20300125 WHERE tenant_id = $1",
+                vec![tenant.as_str().into()],
+            )
+        ).await
+        .unwrap()
+        .unwrap()
+        .try_get::<i64>("", "count")
+        .unwrap();
+
+        assert_eq!(moved, 1);
+
+        let statement = QueryBuilder::new(tenant.as_str())
+            .gte(FieldSelector::Column("timestamp".to_string()), Utc.with_ymd_and_hms(2030, 1, 25, 0, 0, 0).unwrap())
+            .lt(FieldSelector::Column("timestamp".to_string()), Utc.with_ymd_and_hms(2030, 1, 26, 0, 0, 0).unwrap())
+            .into_sql_statement(DatabaseBackend::Postgres);
+
+        let plan = db.query_all(
+            Statement::from_sql_and_values(
+                DatabaseBackend::Postgres,
+                &format!("EXPLAIN {}", statement.sql),
+                statement.values.unwrap().0,
+            )
+        ).await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.try_get::<String>("", "QUERY PLAN").unwrap())
+        .collect::<Vec<String>>()
+        .join("\n");
+
+        assert!(plan.contains("logs_p20300125"));
+        assert!(!plan.contains("logs_p20300126"));
+        assert!(!plan.contains("logs_default"));
+
+        for name in ["logs_p20300120", "logs_p20300125", "logs_p20300126"] {
+            db.execute(
+                Statement::from_string(
+                    DatabaseBackend::Postgres,
+                    format!("DROP TABLE {}", name),
+                )
+            ).await
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,711 @@
+use std::{
+    collections::BTreeSet,
+    sync::{
+        Arc,
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{
+    DateTime,
+    Duration as ChronoDuration,
+    NaiveDate,
+    TimeZone,
+    Utc,
+};
+use sea_orm::{
+    ConnectionTrait,
+    DatabaseConnection,
+    DbErr,
+    EntityTrait,
+    Statement,
+};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::{
+    archive::{
+        Archive,
+        ArchiveError,
+    },
+    config::Config,
+    error::Loggable,
+    models::{
+        Log,
+        LogModel,
+    },
+};
+use super::partitions::{
+    partitions,
+    Partition,
+};
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RetentionRun {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub rows_removed: u64,
+    pub partitions_dropped: Vec<String>,
+}
+
+
+/// Running totals of the retention task, shared with the API
+#[derive(Debug, Default)]
+pub struct RetentionStats {
+    rows_removed: AtomicU64,
+    last_run: Mutex<Option<RetentionRun>>,
+}
+
+impl RetentionStats {
+    pub fn record(&self, run: RetentionRun) {
+        self.rows_removed.fetch_add(run.rows_removed, Ordering::Relaxed);
+        *self.last_run.lock().unwrap() = Some(run);
+    }
+
+    pub fn rows_removed(&self) -> u64 {
+        self.rows_removed.load(Ordering::Relaxed)
+    }
+
+    pub fn last_run(&self) -> Option<RetentionRun> {
+        self.last_run
+            .lock()
+            .unwrap()
+            .clone()
+    }
+}
+
+
+/// A set of rows expiring after `days`, expressed as a SQL condition over the logs table
+struct RetentionRule {
+    condition: String,
+    values: Vec<sea_orm::Value>,
+    days: u32,
+}
+
+
+/// How long logs are kept. A tenant override takes precedence over a level override,
+/// which takes precedence over the default; without a default, logs not covered by
+/// an override are kept forever. Expired logs are written to the archive, when
+/// configured, before they are removed.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    default_days: Option<u32>,
+    levels: Vec<(i32, u32)>,
+    tenants: Vec<(String, u32)>,
+    batch_size: u64,
+    archive: Option<Archive>,
+}
+
+impl RetentionPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            default_days: config.retention_days,
+            levels: config.retention_level_overrides
+                .iter()
+                .flat_map(|overrides| overrides.iter().cloned())
+                .collect(),
+            tenants: config.retention_tenant_overrides
+                .iter()
+                .flat_map(|overrides| overrides.iter().cloned())
+                .collect(),
+            batch_size: config.retention_batch_size.get(),
+            archive: config.archive_directory
+                .as_ref()
+                .map(Archive::new),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.default_days.is_some() || !self.levels.is_empty() || !self.tenants.is_empty()
+    }
+
+    /// The earliest time before which some of `tenant`'s rows expire, if any of its rows do
+    pub fn cutoff_for_tenant(&self, tenant: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let days = match self.tenants.iter().find(|(overridden, _)| overridden == tenant) {
+            Some((_, days)) => *days,
+            None => self.levels
+                .iter()
+                .map(|(_, days)| *days)
+                .chain(self.default_days)
+                .min()?,
+        };
+
+        Some(now - ChronoDuration::days(days as i64))
+    }
+
+    /// The age after which every row has expired, regardless of tenant or level
+    fn max_days(&self) -> Option<u32> {
+        let default_days = self.default_days?;
+
+        let max_days = self.levels
+            .iter()
+            .map(|(_, days)| *days)
+            .chain(self.tenants.iter().map(|(_, days)| *days))
+            .fold(default_days, u32::max);
+
+        Some(max_days)
+    }
+
+    fn rules(&self) -> Vec<RetentionRule> {
+        let tenants: Vec<sea_orm::Value> = self.tenants
+            .iter()
+            .map(|(tenant, _)| tenant.clone().into())
+            .collect();
+        let levels: Vec<sea_orm::Value> = self.levels
+            .iter()
+            .map(|(level, _)| (*level).into())
+            .collect();
+        let mut rules = vec![];
+
+        for (tenant, days) in &self.tenants {
+            rules.push(RetentionRule {
+                condition: "\"tenant_id\" = $1".to_string(),
+                values: vec![tenant.clone().into()],
+                days: *days,
+            });
+        }
+
+        for (level, days) in &self.levels {
+            let mut values = vec![(*level).into()];
+            let condition = format!(
+                "\"level\" = $1 AND {}",
+                Self::exclusion("tenant_id", &tenants, &mut values),
+            );
+
+            rules.push(RetentionRule {
+                condition,
+                values,
+                days: *days,
+            });
+        }
+
+        if let Some(days) = self.default_days {
+            let mut values = vec![];
+            let condition = format!(
+                "{} AND {}",
+                Self::exclusion("tenant_id", &tenants, &mut values),
+                Self::exclusion("level", &levels, &mut values),
+            );
+
+            rules.push(RetentionRule {
+                condition,
+                values,
+                days,
+            });
+        }
+
+        rules
+    }
+
+    /// `column NOT IN (...)` with positionals following `values`, or an always true
+    /// condition when there is nothing to exclude
+    fn exclusion(column: &str, excluded: &[sea_orm::Value], values: &mut Vec<sea_orm::Value>) -> String {
+        if excluded.is_empty() {
+            return "TRUE".to_string();
+        }
+
+        let positionals = excluded
+            .iter()
+            .map(|value| {
+                values.push(value.clone());
+                format!("${}", values.len())
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        format!("\"{}\" NOT IN ({})", column, positionals)
+    }
+
+    pub async fn run(&self, db: &DatabaseConnection, now: DateTime<Utc>) -> Result<RetentionRun, DbErr> {
+        let mut rows_removed = 0;
+        let mut partitions_dropped = vec![];
+
+        if let Some(max_days) = self.max_days() {
+            let cutoff = now - ChronoDuration::days(max_days as i64);
+
+            for partition in partitions(db).await?.iter().filter_map(|attached| Partition::from_name(&attached.name)) {
+                if partition.to > cutoff {
+                    continue;
+                }
+
+                rows_removed += self.drop_partition(db, &partition).await?;
+                partitions_dropped.push(partition.name);
+            }
+        }
+
+        for (index, rule) in self.rules().iter().enumerate() {
+            let cutoff = now - ChronoDuration::days(rule.days as i64);
+            let archive_name = format!("expired-{}-{}", now.timestamp_millis(), index);
+
+            rows_removed += self.delete_expired(db, rule, cutoff, &archive_name).await?;
+        }
+
+        Ok(RetentionRun {
+            started_at: now,
+            finished_at: Utc::now(),
+            rows_removed,
+            partitions_dropped,
+        })
+    }
+
+    async fn drop_partition(&self, db: &DatabaseConnection, partition: &Partition) -> Result<u64, DbErr> {
+        let count = match &self.archive {
+            Some(archive) => self.archive_partition(db, archive, partition).await?,
+            None => db.query_one(
+                Statement::from_string(
+                    db.get_database_backend(),
+                    format!("SELECT count(*) AS count FROM \"{}\"", partition.name),
+                )
+            ).await?
+            .map(|row| row.try_get::<i64>("", "count"))
+            .transpose()?
+            .unwrap_or(0) as u64,
+        };
+
+        db.execute(
+            Statement::from_string(
+                db.get_database_backend(),
+                format!("DROP TABLE \"{}\"", partition.name),
+            )
+        ).await?;
+
+        Ok(count)
+    }
+
+    /// Writes every row of `partition` to a single archive file, a page at a time
+    async fn archive_partition(&self, db: &DatabaseConnection, archive: &Archive, partition: &Partition) -> Result<u64, DbErr> {
+        let context_keys = db.query_all(
+            Statement::from_string(
+                db.get_database_backend(),
+                format!(
+                    "SELECT DISTINCT jsonb_object_keys(context) AS key FROM \"{}\" WHERE jsonb_typeof(context) = 'object'",
+                    partition.name,
+                ),
+            )
+        ).await?
+        .into_iter()
+        .map(|row| row.try_get::<String>("", "key"))
+        .collect::<Result<BTreeSet<String>, DbErr>>()?;
+
+        let day = partition.from.date_naive();
+        let name = partition.name.clone();
+        let archive = archive.clone();
+        let mut writer = blocking(move || archive.writer(day, &name, &context_keys))
+            .await?;
+        let sql = format!("SELECT * FROM \"{}\" WHERE id > $1 ORDER BY id LIMIT $2", partition.name);
+        let mut last_id = i64::MIN;
+        let mut archived = 0;
+
+        loop {
+            let models = Log::find()
+                .from_raw_sql(
+                    Statement::from_sql_and_values(
+                        db.get_database_backend(),
+                        &sql,
+                        vec![last_id.into(), (self.batch_size as i64).into()],
+                    )
+                )
+                .all(db)
+                .await?;
+            let full = models.len() as u64 == self.batch_size;
+
+            archived += models.len() as u64;
+
+            if let Some(model) = models.last() {
+                last_id = model.id;
+            }
+
+            writer = blocking(move || writer.write(&models).map(|_| writer))
+                .await?;
+
+            if !full {
+                break;
+            }
+        }
+
+        blocking(move || writer.finish())
+            .await?;
+
+        Ok(archived)
+    }
+
+    /// Deletes rows matching `rule` older than `cutoff`. When archiving, the rule's expired rows
+    /// of each day are first written to one archive file named `archive_name` under the day, so
+    /// each run writes a file per day for every rule with expired rows. Only the rows written are
+    /// then deleted, so no transaction is held open while the archive is written.
+    async fn delete_expired(&self, db: &DatabaseConnection, rule: &RetentionRule, cutoff: DateTime<Utc>, archive_name: &str) -> Result<u64, DbErr> {
+        let condition = format!("{} AND \"timestamp\" < ${}", rule.condition, rule.values.len() + 1);
+        let mut values = rule.values.clone();
+
+        values.push(cutoff.into());
+
+        let archive = match &self.archive {
+            Some(archive) => archive,
+            None => return self.delete_batches(db, &condition, values).await,
+        };
+
+        let days = db.query_all(
+            Statement::from_sql_and_values(
+                db.get_database_backend(),
+                &format!(
+                    "SELECT DISTINCT (\"timestamp\" AT TIME ZONE 'UTC')::date AS day FROM {} WHERE {} ORDER BY 1",
+                    LogModel::table_name(),
+                    condition,
+                ),
+                values.clone(),
+            )
+        ).await?
+        .into_iter()
+        .map(|row| row.try_get::<NaiveDate>("", "day"))
+        .collect::<Result<Vec<NaiveDate>, DbErr>>()?;
+        let mut removed = 0;
+
+        for day in days {
+            let from = Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).unwrap());
+            let condition = format!(
+                "{} AND \"timestamp\" >= ${} AND \"timestamp\" < ${}",
+                condition,
+                values.len() + 1,
+                values.len() + 2,
+            );
+            let mut values = values.clone();
+
+            values.push(from.into());
+            values.push((from + ChronoDuration::days(1)).into());
+
+            removed += self.archive_day(db, archive, day, archive_name, &condition, values).await?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Archives the rows matching `condition`, all logged on `day`, to one file a page at a
+    /// time, then deletes them. Rows arriving meanwhile have later ids and are left for the
+    /// next run.
+    async fn archive_day(&self, db: &DatabaseConnection, archive: &Archive, day: NaiveDate, name: &str, condition: &str, values: Vec<sea_orm::Value>) -> Result<u64, DbErr> {
+        let table_name = LogModel::table_name();
+        let context_keys = db.query_all(
+            Statement::from_sql_and_values(
+                db.get_database_backend(),
+                &format!(
+                    "SELECT DISTINCT jsonb_object_keys(context) AS key FROM {} WHERE {} AND jsonb_typeof(context) = 'object'",
+                    table_name,
+                    condition,
+                ),
+                values.clone(),
+            )
+        ).await?
+        .into_iter()
+        .map(|row| row.try_get::<String>("", "key"))
+        .collect::<Result<BTreeSet<String>, DbErr>>()?;
+
+        let sql = format!(
+            "SELECT * FROM {} WHERE {} AND id > ${} ORDER BY id LIMIT ${}",
+            table_name,
+            condition,
+            values.len() + 1,
+            values.len() + 2,
+        );
+        let mut writer = None;
+        let mut last_id = i64::MIN;
+
+        loop {
+            let mut page = values.clone();
+
+            page.push(last_id.into());
+            page.push((self.batch_size as i64).into());
+
+            let models = Log::find()
+                .from_raw_sql(Statement::from_sql_and_values(db.get_database_backend(), &sql, page))
+                .all(db)
+                .await?;
+            let full = models.len() as u64 == self.batch_size;
+
+            match models.last() {
+                Some(model) => last_id = model.id,
+                None => break,
+            }
+
+            let mut current = match writer.take() {
+                Some(writer) => writer,
+                None => {
+                    let archive = archive.clone();
+                    let name = name.to_string();
+                    let context_keys = context_keys.clone();
+
+                    blocking(move || archive.writer(day, &name, &context_keys))
+                        .await?
+                },
+            };
+
+            current = blocking(move || current.write(&models).map(|_| current))
+                .await?;
+            writer = Some(current);
+
+            if !full {
+                break;
+            }
+        }
+
+        let writer = match writer {
+            Some(writer) => writer,
+            None => return Ok(0),
+        };
+
+        blocking(move || writer.finish())
+            .await?;
+
+        let condition = format!("{} AND id <= ${}", condition, values.len() + 1);
+        let mut values = values;
+
+        values.push(last_id.into());
+
+        self.delete_batches(db, &condition, values).await
+    }
+
+    /// Deletes rows matching `condition` in batches of `batch_size`
+    async fn delete_batches(&self, db: &DatabaseConnection, condition: &str, values: Vec<sea_orm::Value>) -> Result<u64, DbErr> {
+        let sql = format!(
+            "DELETE FROM {table} WHERE (id, timestamp) IN (SELECT id, timestamp FROM {table} WHERE {condition} LIMIT ${limit})",
+            table = LogModel::table_name(),
+            condition = condition,
+            limit = values.len() + 1,
+        );
+        let mut values = values;
+
+        values.push((self.batch_size as i64).into());
+
+        let mut removed = 0;
+
+        loop {
+            let rows_affected = db.execute(Statement::from_sql_and_values(db.get_database_backend(), &sql, values.clone()))
+                .await?
+                .rows_affected();
+
+            removed += rows_affected;
+
+            if rows_affected < self.batch_size {
+                break;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+
+/// Runs archive IO on the blocking pool rather than the async runtime
+async fn blocking<T, F>(f: F) -> Result<T, DbErr>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ArchiveError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| DbErr::Custom(format!("Failed to archive logs: {}", e)))?
+        .map_err(archive_error)
+}
+
+fn archive_error(e: ArchiveError) -> DbErr {
+    DbErr::Custom(format!("Failed to archive logs: {}", e))
+}
+
+pub fn spawn(db: Arc<DatabaseConnection>, stats: Arc<RetentionStats>, config: &Config) -> Option<JoinHandle<()>> {
+    let policy = RetentionPolicy::from_config(config);
+    let period = Duration::from_secs(config.retention_interval_seconds.get());
+
+    if !policy.is_enabled() {
+        return None;
+    }
+
+    let handle = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+
+            if let Ok(run) = policy
+                .run(&db, Utc::now())
+                .await
+                .log_error("An exception occurred while purging expired logs") {
+                tracing::info!(
+                    "Retention removed {} logs, dropped partitions: {:?}",
+                    run.rows_removed,
+                    run.partitions_dropped,
+                );
+
+                stats.record(run);
+            }
+        }
+    });
+
+    Some(handle)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use chrono::{
+        Duration,
+        NaiveDate,
+        Utc,
+    };
+    use sea_orm::{
+        ConnectionTrait,
+        DatabaseBackend,
+        Statement,
+    };
+
+    use crate::{
+        archive::Archive,
+        testing::{
+            self,
+            config,
+        },
+    };
+    use super::RetentionPolicy;
+
+    #[test]
+    fn test_policy_rules() {
+        let policy = RetentionPolicy::from_config(&config(&[
+            ("RETENTION_DAYS", "30"),
+            ("RETENTION_LEVEL_OVERRIDES", "1=7"),
+            ("RETENTION_TENANT_OVERRIDES", "team-a=90,team-b=60"),
+        ]));
+
+        let conditions: Vec<(String, usize, u32)> = policy.rules()
+            .into_iter()
+            .map(|rule| (rule.condition, rule.values.len(), rule.days))
+            .collect();
+
+        assert_eq!(policy.max_days(), Some(90));
+        assert_eq!(
+            conditions,
+            vec![
+                ("\"tenant_id\" = $1".to_string(), 1, 90),
+                ("\"tenant_id\" = $1".to_string(), 1, 60),
+                ("\"level\" = $1 AND \"tenant_id\" NOT IN ($2, $3)".to_string(), 3, 7),
+                ("\"tenant_id\" NOT IN ($1, $2) AND \"level\" NOT IN ($3)".to_string(), 3, 30),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_policy_without_default() {
+        let policy = RetentionPolicy::from_config(&config(&[
+            ("RETENTION_LEVEL_OVERRIDES", "1=7"),
+        ]));
+
+        assert!(policy.is_enabled());
+        assert_eq!(policy.max_days(), None);
+        assert_eq!(policy.rules().len(), 1);
+        assert!(!RetentionPolicy::from_config(&config(&[])).is_enabled());
+    }
+
+    #[test]
+    fn test_cutoff_for_tenant() {
+        let now = Utc::now();
+        let policy = RetentionPolicy::from_config(&config(&[
+            ("RETENTION_DAYS", "30"),
+            ("RETENTION_LEVEL_OVERRIDES", "1=7"),
+            ("RETENTION_TENANT_OVERRIDES", "team-a=90"),
+        ]));
+
+        assert_eq!(policy.cutoff_for_tenant("team-a", now), Some(now - Duration::days(90)));
+        assert_eq!(policy.cutoff_for_tenant("default", now), Some(now - Duration::days(7)));
+        assert_eq!(RetentionPolicy::from_config(&config(&[])).cutoff_for_tenant("default", now), None);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_retention_run() {
+        let directory = std::env::temp_dir().join(format!("log-ingest-retention-{}", std::process::id()));
+        let team_a = testing::tenant("retention-a");
+        let team_b = testing::tenant("retention-b");
+        // level and default rules would expire other tests' rows, so only this test's tenants
+        // have rules; how rules combine is covered by test_policy_rules
+        let overrides = format!("{}=30,{}=90", team_a, team_b);
+        let config = config(&[
+            ("RETENTION_TENANT_OVERRIDES", overrides.as_str()),
+            ("RETENTION_BATCH_SIZE", "1"),
+            ("ARCHIVE_DIRECTORY", directory.to_str().unwrap()),
+        ]);
+        let db = testing::database(&config)
+            .await;
+        let now = Utc::now();
+
+        let rows = [
+            (&team_a, 3, 10, true),
+            (&team_a, 3, 40, false),
+            (&team_a, 1, 40, false),
+            (&team_a, 1, 3, true),
+            (&team_b, 1, 40, true),
+            (&team_b, 3, 100, false),
+        ];
+
+        for (tenant, level, age, _) in rows {
+            db.execute(
+                Statement::from_sql_and_values(
+                    DatabaseBackend::Postgres,
+                    "INSERT INTO logs (timestamp, message, level, tenant_id) VALUES ($1, 'retention', $2, $3)",
+                    vec![(now - Duration::days(age)).into(), level.into(), tenant.as_str().into()],
+                )
+            ).await
+            .unwrap();
+        }
+
+        let run = RetentionPolicy::from_config(&config)
+            .run(&db, now)
+            .await
+            .unwrap();
+
+        let remaining = db.query_one(
+            Statement::from_sql_and_values(
+                DatabaseBackend::Postgres,
+                "SELECT count(*) AS count FROM logs WHERE tenant_id IN ($1, $2)",
+                vec![team_a.as_str().into(), team_b.as_str().into()],
+            )
+        ).await
+        .unwrap()
+        .unwrap()
+        .try_get::<i64>("", "count")
+        .unwrap();
+
+        assert_eq!(run.rows_removed, 3);
+        assert_eq!(remaining as usize, rows.iter().filter(|(_, _, _, kept)| *kept).count());
+
+        // every removed row was archived under the day it was logged
+        let archive = Archive::new(&directory);
+        let days: BTreeSet<NaiveDate> = rows
+            .iter()
+            .filter(|(_, _, _, kept)| !*kept)
+            .map(|(_, _, age, _)| (now - Duration::days(*age)).date_naive())
+            .collect();
+        let archived: usize = days
+            .iter()
+            .flat_map(|day| [&team_a, &team_b].map(|tenant| archive.read(*day, tenant).unwrap().len()))
+            .sum();
+
+        assert_eq!(archived, 3);
+
+        // each rule writes one file per day in a run, however many batches it took
+        for day in days {
+            let files = std::fs::read_dir(directory.join(day.format("%Y-%m-%d").to_string()))
+                .unwrap()
+                .count();
+
+            assert_eq!(files, 1);
+        }
+
+        std::fs::remove_dir_all(directory)
+            .unwrap();
+    }
+}
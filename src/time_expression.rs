@@ -0,0 +1,237 @@
+use chrono::{
+    DateTime,
+    Datelike,
+    Duration,
+    Months,
+    NaiveDate,
+    NaiveDateTime,
+    TimeZone,
+    Utc,
+};
+
+
+/// Epoch values above this are taken to be milliseconds rather than seconds
+/// (`100000000000` seconds is in the year 5138)
+const EPOCH_MILLIS_THRESHOLD: i64 = 100_000_000_000;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Unit {
+    fn parse(c: char) -> Option<Self> {
+        match c {
+            's' => Some(Self::Second),
+            'm' => Some(Self::Minute),
+            'h' | 'H' => Some(Self::Hour),
+            'd' => Some(Self::Day),
+            'w' => Some(Self::Week),
+            'M' => Some(Self::Month),
+            'y' => Some(Self::Year),
+            _ => None,
+        }
+    }
+
+    fn add(self, time: DateTime<Utc>, amount: i64) -> Option<DateTime<Utc>> {
+        let months = |months: i64| -> Option<DateTime<Utc>> {
+            let magnitude = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+
+            if months >= 0 {
+                time.checked_add_months(magnitude)
+            } else {
+                time.checked_sub_months(magnitude)
+            }
+        };
+
+        match self {
+            Self::Second => time.checked_add_signed(Duration::try_seconds(amount)?),
+            Self::Minute => time.checked_add_signed(Duration::try_minutes(amount)?),
+            Self::Hour => time.checked_add_signed(Duration::try_hours(amount)?),
+            Self::Day => time.checked_add_signed(Duration::try_days(amount)?),
+            Self::Week => time.checked_add_signed(Duration::try_weeks(amount)?),
+            Self::Month => months(amount),
+            Self::Year => months(amount.checked_mul(12)?),
+        }
+    }
+
+    /// Rounds `time` down to the start of the unit; weeks start on Monday
+    fn floor(self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let date = time.date_naive();
+        let start_of_day = |date: NaiveDate| Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?));
+
+        match self {
+            Self::Second => Utc.timestamp_opt(time.timestamp(), 0).single(),
+            Self::Minute => Utc.timestamp_opt(time.timestamp() - time.timestamp().rem_euclid(60), 0).single(),
+            Self::Hour => Utc.timestamp_opt(time.timestamp() - time.timestamp().rem_euclid(3600), 0).single(),
+            Self::Day => start_of_day(date),
+            Self::Week => start_of_day(date - Duration::days(date.weekday().num_days_from_monday() as i64)),
+            Self::Month => start_of_day(date.with_day(1)?),
+            Self::Year => start_of_day(NaiveDate::from_ymd_opt(date.year(), 1, 1)?),
+        }
+    }
+}
+
+
+/// Resolves a time expression against `now`. Accepts RFC 3339 timestamps, dates
+/// (`2022-12-25`, midnight UTC), date times without an offset (taken as UTC), epoch seconds or
+/// milliseconds, and date math relative to now: `now`, `now-15m`, `now+1h`, `now-1d/d`.
+///
+/// Date math applies any number of `+<n><unit>` / `-<n><unit>` steps, then optionally rounds
+/// down with `/<unit>`. Units are `s`, `m`, `h`, `d`, `w`, `M` (months) and `y`.
+pub fn resolve(expression: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let expression = expression.trim();
+
+    if let Some(math) = expression.strip_prefix("now") {
+        return date_math(math, now);
+    }
+
+    if !expression.is_empty() && expression.chars().all(|c| c.is_ascii_digit()) {
+        let epoch = expression.parse::<i64>().ok()?;
+
+        return if epoch > EPOCH_MILLIS_THRESHOLD {
+            Utc.timestamp_millis_opt(epoch).single()
+        } else {
+            Utc.timestamp_opt(epoch, 0).single()
+        };
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(expression) {
+        return Some(time.with_timezone(&Utc));
+    }
+
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(expression, format) {
+            return Some(Utc.from_utc_datetime(&time));
+        }
+    }
+
+    NaiveDate::parse_from_str(expression, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| Utc.from_utc_datetime(&time))
+}
+
+
+/// Parses a fixed length interval such as `30s`, `5m`, `1h`, `1d` or `1w` into seconds.
+/// Months and years vary in length, so aren't accepted.
+pub fn interval_seconds(expression: &str) -> Option<i64> {
+    let expression = expression.trim();
+    let unit = Unit::parse(expression.chars().last()?)?;
+    let amount = expression[..expression.len() - 1].parse::<i64>().ok().filter(|amount| *amount > 0)?;
+    let seconds = match unit {
+        Unit::Second => 1,
+        Unit::Minute => 60,
+        Unit::Hour => 3600,
+        Unit::Day => 86_400,
+        Unit::Week => 604_800,
+        Unit::Month | Unit::Year => return None,
+    };
+
+    amount.checked_mul(seconds)
+}
+
+
+fn date_math(math: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let mut time = now;
+    let mut chars = math.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '+' | '-' => {
+                let mut digits = String::new();
+
+                while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                    digits.push(digit);
+                }
+
+                let amount = digits.parse::<i64>().ok()?;
+                let unit = Unit::parse(chars.next()?)?;
+
+                time = unit.add(time, if c == '-' { -amount } else { amount })?;
+            },
+            '/' => {
+                let unit = Unit::parse(chars.next()?)?;
+
+                // rounding is always the final step
+                return match chars.next() {
+                    None => unit.floor(time),
+                    Some(_) => None,
+                };
+            },
+            _ => return None,
+        }
+    }
+
+    Some(time)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::{
+        DateTime,
+        TimeZone,
+        Utc,
+    };
+
+    use super::{
+        interval_seconds,
+        resolve,
+    };
+
+    fn now() -> DateTime<Utc> {
+        // a Wednesday
+        Utc.with_ymd_and_hms(2022, 12, 28, 13, 45, 30).unwrap()
+    }
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> Option<DateTime<Utc>> {
+        Some(Utc.with_ymd_and_hms(year, month, day, hour, minute, second).unwrap())
+    }
+
+    #[test]
+    fn test_date_math() {
+        assert_eq!(resolve("now", now()), Some(now()));
+        assert_eq!(resolve("now-15m", now()), at(2022, 12, 28, 13, 30, 30));
+        assert_eq!(resolve("now+1h", now()), at(2022, 12, 28, 14, 45, 30));
+        assert_eq!(resolve("now-1d/d", now()), at(2022, 12, 27, 0, 0, 0));
+        assert_eq!(resolve("now/w", now()), at(2022, 12, 26, 0, 0, 0));
+        assert_eq!(resolve("now-1M/M", now()), at(2022, 11, 1, 0, 0, 0));
+        assert_eq!(resolve("now-1y-2h/h", now()), at(2021, 12, 28, 11, 0, 0));
+    }
+
+    #[test]
+    fn test_absolute_times() {
+        assert_eq!(resolve("2022-12-25T13:45:00+01:00", now()), at(2022, 12, 25, 12, 45, 0));
+        assert_eq!(resolve("2022-12-25", now()), at(2022, 12, 25, 0, 0, 0));
+        assert_eq!(resolve("2022-12-25 08:30:00", now()), at(2022, 12, 25, 8, 30, 0));
+        assert_eq!(resolve("1671975900", now()), at(2022, 12, 25, 13, 45, 0));
+        assert_eq!(resolve("1671975900000", now()), at(2022, 12, 25, 13, 45, 0));
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        for expression in ["", "yesterday", "now-", "now-15", "now-15q", "now/d-1h", "now*2d", "2022-13-01", "-5"] {
+            assert_eq!(resolve(expression, now()), None, "{}", expression);
+        }
+    }
+
+    #[test]
+    fn test_interval_seconds() {
+        assert_eq!(interval_seconds("30s"), Some(30));
+        assert_eq!(interval_seconds("5m"), Some(300));
+        assert_eq!(interval_seconds("1h"), Some(3600));
+        assert_eq!(interval_seconds("2w"), Some(1_209_600));
+
+        for expression in ["", "m", "0m", "-5m", "5", "1M", "1y", "5q"] {
+            assert_eq!(interval_seconds(expression), None, "{}", expression);
+        }
+    }
+}